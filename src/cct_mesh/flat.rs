@@ -1,6 +1,8 @@
 //
 //
 
+use std::collections::HashMap;
+
 type ExecLimit = u32;
 //type NodeIdx = usize;
 
@@ -19,36 +21,118 @@ pub struct ElementInst
 	pub inst: Box<::elements::Element+'static>,
 	pub inputs: Vec<NodeRef>,
 	pub outputs: Vec<NodeRef>,
+	/// Sub-unit path of the element (e.g. "CPU#0/ALU#2/", empty for local elements)
+	pub scope: String,
+	/// Display block path (e.g. "cpu.decode", empty if not in a block)
+	pub block: String,
 }
 
 #[derive(Clone,Default)]
 pub struct Node
 {
+	/// Names from the mesh's own unit (sub-unit names are found through `Mesh::instances`)
 	pub names: Vec<String>
 }
 
+/// A sub-unit instance merged into a mesh
+///
+/// Only direct sub-units are recorded, names and nested instances are resolved through `mesh` when needed
+/// (copying them into every parent uses too much memory).
+#[derive(Clone)]
+pub struct Instance
+{
+	pub unit: String,
+	/// Path prefix of the instance (e.g. "ALU#0/")
+	pub scope: String,
+	/// The unit's own flattened mesh
+	pub mesh: ::std::rc::Rc<Mesh>,
+	/// Maps node indexes in the unit's own flattened mesh to nodes in this mesh
+	pub nodes: Vec<NodeRef>,
+}
+
 // Represents a flattened (executable) mesh
 #[derive(Clone)]
 pub struct Mesh
 {
+	pub name: String,
 	pub n_nodes: usize,
-	//pub nodes: Vec<Node>,	// Aka LinkValues
+	pub nodes: Vec<Node>,	// Aka LinkValues
 	pub elements: Vec<ElementInst>,
 	pub inputs: Vec<NodeRef>,
 	pub outputs: Vec<NodeRef>,
 	
 	pub breakpoints: Vec<Breakpoint>,
 	pub dispitems: Vec<Display>,
+	
+	pub instances: Vec<Instance>,
+	/// Properties (#property) that must hold on every tick
+	pub properties: Vec<TestAssert>,
+	/// Global reset line (#reset)
+	pub reset: Option<NodeRef>,
 }
 
 pub struct Test
 {
 	unit: ::std::rc::Rc<Mesh>,
 	exec_limit:	u32,
+	reset_hold: u32,
 	completion: Vec<NodeRef>,
 	assertions: Vec<TestAssert>,
+	vectors: Option<TestVectors>,
+	/// Sorted by tick
+	stimulus: Vec<Stimulus>,
+	timed_asserts: Vec<TimedAssert>,
+	/// Expected to fail (#xfail)
+	xfail: bool,
+}
+
+/// Values forced onto nodes from the start of a tick (#at)
+///
+/// Nodes stay forced for the rest of the test, until a later stimulus sets them again.
+#[derive(Clone)]
+pub struct Stimulus
+{
+	pub tick: u32,
+	pub nodes: Vec<NodeRef>,
+	pub values: Vec<bool>,
+}
+
+#[derive(Copy,Clone,Debug)]
+pub enum AssertTiming
+{
+	/// Checked once, at the end of the given tick (#expect_at)
+	At(u32),
+	/// Must hold at the end of at least one of the first N ticks (#expect_within)
+	Within(u32),
+}
+#[derive(Clone)]
+pub struct TimedAssert
+{
+	pub timing: AssertTiming,
+	pub check: TestAssert,
+}
+
+/// Test vectors (#testvectors), one row applied every `period` ticks
+#[derive(Clone)]
+pub struct TestVectors
+{
+	pub period: u32,
+	/// Nodes forced to each row's input values
+	pub inputs: Vec<NodeRef>,
+	/// Names and widths of the input and output signals
+	pub input_signals: Vec<(String,usize)>,
+	pub output_signals: Vec<(String,usize)>,
+	pub rows: Vec<VectorRow>,
+}
+#[derive(Clone)]
+pub struct VectorRow
+{
+	pub inputs: Vec<bool>,
+	/// Checked on the last tick of the row (against constant expected values)
+	pub check: TestAssert,
 }
 
+#[derive(Clone)]
 pub struct TestAssert
 {
 	pub line: u32,
@@ -62,14 +146,20 @@ pub struct Display
 {
 	pub condition: Vec<NodeRef>,
 	pub text: String,
+	/// `text`, parsed when the item was defined
+	pub format: ::display::Format,
 	pub values: Vec<NodeRef>,
+	/// Display block path (empty if not in a block)
+	pub block: String,
 }
 
 #[derive(Clone)]
 pub struct Breakpoint
 {
-	name: String,
+	pub name: String,
 	pub conds: Vec<NodeRef>,
+	/// Marked as an error condition (`#breakpoint ... "name" error`)
+	pub error: bool,
 }
 
 impl Mesh
@@ -80,13 +170,18 @@ impl Mesh
 		) -> Mesh
 	{
 		Mesh {
+			name: unit.name.clone(),
 			n_nodes: n_nodes,
+			nodes: ::from_elem(n_nodes, Node::default()),
 			elements: Vec::with_capacity(n_eles),
 			inputs:  linklist_to_noderefs(unit, inputs),
 			outputs: linklist_to_noderefs(unit, outputs),
 			
 			breakpoints: Vec::with_capacity(n_bps),
 			dispitems: Vec::with_capacity(n_disp),
+			instances: Vec::new(),
+			properties: Vec::new(),
+			reset: None,
 		}
 	}
 	
@@ -100,8 +195,119 @@ impl Mesh
 	pub fn push_breakpoint(&mut self, bp: Breakpoint) {
 		self.breakpoints.push( bp );
 	}
+	pub fn push_property(&mut self, prop: TestAssert) {
+		self.properties.push( prop );
+	}
+	pub fn push_node_name(&mut self, id: u32, name: String) {
+		self.nodes[id as usize].names.push( name );
+	}
+	
+	/// Get a printable name for a node (the first name, or "#<id>" for anonymous nodes)
+	pub fn node_name(&self, id: u32) -> String {
+		self.find_node_name(id).unwrap_or_else(|| format!("#{}", id))
+	}
+	/// First name of a node, preferring this unit's names over those from sub-units
+	fn find_node_name(&self, id: u32) -> Option<String> {
+		if let Some(n) = self.nodes[id as usize].names.first() {
+			return Some(n.clone());
+		}
+		self.instances.iter().find_map(|inst| {
+			inst.nodes.iter().enumerate()
+				.filter(|&(_,n)| matches!(*n, NodeRef::NodeId(v) if v == id))
+				.find_map(|(j,_)| inst.mesh.find_node_name(j as u32))
+				.map(|n| format!("{}{}", inst.scope, n))
+			})
+	}
+	/// All names of every node, including those from sub-units (prefixed with the instance path)
+	///
+	/// This walks every sub-unit instance, so get it once rather than per node.
+	pub fn node_names(&self) -> Vec<Vec<String>>
+	{
+		let mut rv: Vec<Vec<String>> = self.nodes.iter().map(|n| n.names.clone()).collect();
+		for inst in self.instances.iter()
+		{
+			for (j,names) in inst.mesh.node_names().into_iter().enumerate()
+			{
+				if let NodeRef::NodeId(id) = inst.nodes[j] {
+					rv[id as usize].extend( names.into_iter().map(|n| format!("{}{}", inst.scope, n)) );
+				}
+			}
+		}
+		rv
+	}
+	/// Node maps (from the unit's own mesh to this one) of every instance of `unit`, at any depth
+	pub fn instances_of(&self, unit: &str) -> Vec<Vec<NodeRef>>
+	{
+		let mut rv = Vec::new();
+		for inst in self.instances.iter()
+		{
+			if inst.unit == unit {
+				rv.push( inst.nodes.clone() );
+			}
+			for nodes in inst.mesh.instances_of(unit) {
+				rv.push( nodes.iter().map(|n| match *n { NodeRef::NodeId(id) => inst.nodes[id as usize], c => c }).collect() );
+			}
+		}
+		rv
+	}
+	/// Map from each node name (including sub-unit names) to the node, for `lookup_in`
+	pub fn name_map(&self) -> HashMap<String,u32>
+	{
+		let mut rv = HashMap::new();
+		for (id,names) in self.node_names().into_iter().enumerate()
+		{
+			for name in names {
+				rv.insert(name, id as u32);
+			}
+		}
+		rv
+	}
+	/// Look up a line (`$name` or `name`) or group (`@name`) by name, in a map from `name_map`
+	///
+	/// Groups are returned LSB first. Bare names are tried as a line and then as a group.
+	pub fn lookup_in(names: &HashMap<String,u32>, name: &str) -> Option<Vec<NodeRef>>
+	{
+		let find = |n: &str| names.get(n).map(|&id| NodeRef::NodeId(id));
+		let group = |n: &str| {
+			let rv: Vec<_> = (0 ..).map_while(|i| find(&format!("{}[{:2}]", n, i))).collect();
+			if rv.is_empty() { None } else { Some(rv) }
+			};
+		if let Some(n) = name.strip_prefix('$') {
+			find(n).map(|v| vec![v])
+		}
+		else if let Some(n) = name.strip_prefix('@') {
+			group(n)
+		}
+		else {
+			find(name).map(|v| vec![v]).or_else(|| group(name))
+		}
+	}
+	
+	/// Split a node name into the name of the unit that defines it and the local signal name
+	pub fn split_node_name<'a>(&'a self, name: &'a str) -> (&'a str, &'a str)
+	{
+		match name.rfind('/')
+		{
+		Some(pos) => (self.scope_unit(&name[.. pos+1]), &name[pos+1 ..]),
+		None => (&self.name, name),
+		}
+	}
+	/// Get the unit name for a sub-unit path (e.g. "CPU#0/ALU#2/" is "ALU")
+	pub fn scope_unit<'a>(&'a self, scope: &'a str) -> &'a str
+	{
+		let scope = scope.trim_end_matches('/');
+		if scope.is_empty() {
+			return &self.name;
+		}
+		let inst = match scope.rfind('/') { Some(p) => &scope[p+1 ..], None => scope };
+		match inst.rfind('#') { Some(p) => &inst[.. p], None => inst }
+	}
 	
-	pub fn merge(&mut self, other: &Mesh, aliases: &Vec<Option<NodeRef>>)
+	/// Merge a flattened sub-unit into this mesh
+	///
+	/// @param prefix - Path prefix for names from the sub-unit (e.g. "ALU#0/")
+	/// @param block - Display block the sub-unit was instantiated in
+	pub fn merge(&mut self, other: &::std::rc::Rc<Mesh>, aliases: &Vec<Option<NodeRef>>, prefix: &str, block: &str)
 	{
 		for ele in other.elements.iter()
 		{
@@ -111,16 +317,37 @@ impl Mesh
 				inst: ele.inst.dup(),
 				inputs:  ele_inputs,
 				outputs: ele_outputs,
+				scope: format!("{}{}", prefix, ele.scope),
+				block: join_block(block, &ele.block),
 				};
 			self.push_ele( inst );
 		}
 		
+		for prop in other.properties.iter()
+		{
+			self.push_property( TestAssert {
+				line: prop.line,
+				conditions: noderefs_aliased(&prop.conditions, aliases),
+				values: noderefs_aliased(&prop.values, aliases),
+				expected: noderefs_aliased(&prop.expected, aliases),
+				} );
+		}
+		
+		self.instances.push( Instance {
+			unit: other.name.clone(),
+			scope: prefix.to_string(),
+			mesh: other.clone(),
+			nodes: aliases.iter().map(|a| a.unwrap()).collect(),
+			} );
+		
 		for di in other.dispitems.iter()
 		{
 			self.push_disp( Display {
 				condition: noderefs_aliased(&di.condition, aliases),
 				text: di.text.clone(),
+				format: di.format.clone(),
 				values: noderefs_aliased(&di.values, aliases),
+				block: join_block(block, &di.block),
 				} );
 		}
 	}
@@ -131,13 +358,28 @@ impl Test
 	pub fn new(flat: ::std::rc::Rc<Mesh>, exec_limit: u32, completion: Vec<NodeRef>, assertions: Vec<TestAssert>) -> Test {
 		Test {
 			exec_limit: exec_limit,
+			reset_hold: 0,
 			unit: flat,
 			completion: completion,
 			assertions: assertions,
+			vectors: None,
+			stimulus: Vec::new(),
+			timed_asserts: Vec::new(),
+			xfail: false,
 			}
 	}
 	
 	pub fn exec_limit(&self) -> u32 { self.exec_limit }
+	pub fn reset_hold(&self) -> u32 { self.reset_hold }
+	pub fn set_reset_hold(&mut self, ticks: u32) { self.reset_hold = ticks; }
+	pub fn vectors(&self) -> Option<&TestVectors> { self.vectors.as_ref() }
+	pub fn set_vectors(&mut self, vectors: TestVectors) { self.vectors = Some(vectors); }
+	pub fn stimulus(&self) -> &[Stimulus] { &self.stimulus }
+	pub fn set_stimulus(&mut self, stimulus: Vec<Stimulus>) { self.stimulus = stimulus; }
+	pub fn timed_asserts(&self) -> &[TimedAssert] { &self.timed_asserts }
+	pub fn set_timed_asserts(&mut self, asserts: Vec<TimedAssert>) { self.timed_asserts = asserts; }
+	pub fn xfail(&self) -> bool { self.xfail }
+	pub fn set_xfail(&mut self, xfail: bool) { self.xfail = xfail; }
 	pub fn get_mesh(&self) -> &Mesh { &*(self.unit) }
 	pub fn get_completion(&self) -> &Vec<NodeRef> { &self.completion }
 	pub fn iter_asserts(&self) -> ::std::slice::Iter<::cct_mesh::flat::TestAssert>
@@ -160,25 +402,46 @@ impl TestAssert
 
 impl Display
 {
-	pub fn new(text: String, conds: Vec<NodeRef>, values: Vec<NodeRef>) -> Display {
+	pub fn new(text: String, format: ::display::Format, block: String, conds: Vec<NodeRef>, values: Vec<NodeRef>) -> Display {
 		Display {
 			condition: conds,
 			text: text,
+			format,
 			values: values,
+			block,
 		}
 	}
 }
 
 impl Breakpoint
 {
-	pub fn new(name: String, conds: Vec<NodeRef>) -> Breakpoint {
+	pub fn new(name: String, conds: Vec<NodeRef>, error: bool) -> Breakpoint {
 		Breakpoint {
 			name: name,
 			conds: conds,
+			error,
 		}
 	}
 }
 
+/// Split the name of a group member (`group[ N]`) into the group name and index
+pub fn split_group_member(name: &str) -> Option<(&str, usize)>
+{
+	let (group, idx) = name.strip_suffix(']')?.rsplit_once('[')?;
+	idx.trim().parse().ok().map(|i| (group, i))
+}
+
+/// Join a display block path with a nested one (either may be empty)
+pub fn join_block(outer: &str, inner: &str) -> String
+{
+	match (outer.is_empty(), inner.is_empty())
+	{
+	(true, _) => inner.to_string(),
+	(_, true) => outer.to_string(),
+	_ => format!("{}.{}", outer, inner),
+	}
+}
+
 /// @brief Convert a LinkList into node references
 pub fn linklist_to_noderefs(unit: &super::Unit, links: &super::LinkList) -> Vec<NodeRef>
 {
@@ -36,26 +36,33 @@ pub struct Element
 	inst: Box<::elements::Element+'static>,
 	inputs: LinkList,
 	outputs: LinkList,
+	/// Display block (#block) path, e.g. "cpu.decode"
+	block: String,
 }
 
-//#[derive(Default)]
-//struct VisGroup
-//{
-//	name: String,
-//	elements: LinkedList<Element>
-//}
-
 struct Breakpoint
 {
 	conds: LinkList,
 	name: String,
+	/// Hitting this breakpoint is a failure (checked by `fuzz`)
+	error: bool,
 }
 
 struct DisplayItem
 {
 	condition: LinkList,
 	text: String,
+	format: ::display::Format,
 	values: LinkList,
+	block: String,
+}
+
+/// Initial contents for a ROM or memory (`#rom_data_hex`, `#rom_file`, `#mem_file`)
+struct DataBlock
+{
+	words: Rc<Vec<u64>>,
+	/// Source file and the offset in it of each word (for error reports)
+	source: Option<(::std::path::PathBuf, Vec<Option<usize>>)>,
 }
 
 struct UnitRef
@@ -63,6 +70,8 @@ struct UnitRef
 	name: String,
 	inputs: LinkList,
 	outputs: LinkList,
+	/// Block the sub-unit was instantiated in (prefixed to the sub-unit's own blocks)
+	block: String,
 }
 
 #[derive(Default)]
@@ -84,12 +93,18 @@ pub struct Unit
 	
 	breakpoints: LinkedList<Breakpoint>,
 	disp_items: LinkedList<DisplayItem>,
+	properties: LinkedList<TestAssert>,
+	/// Global reset line (#reset, only in the root unit and tests)
+	reset: Option<LinkRef>,
 	
-	//visgroups: LinkedList<VisGroup>,
+	/// Currently open #block names (outermost first)
+	blocks: Vec<String>,
 	
 	flattened: Option<Rc<flat::Mesh>>,
 
-	rom_data: Vec<Option<Rc<Vec<u64>>>>,
+	rom_data: Vec<Option<DataBlock>>,
+	/// Files attached to I/O elements, keyed by index and direction (true = transmit)
+	io_files: ::std::collections::HashMap<(usize,bool),::std::path::PathBuf>,
 }
 
 struct TestAssert
@@ -100,13 +115,46 @@ struct TestAssert
 	expected: LinkList,
 }
 
+/// Table of input values and expected outputs, one row applied every `period` ticks (#testvectors)
+pub struct TestVectors
+{
+	period: u32,
+	inputs: Vec<(String,LinkList)>,
+	outputs: Vec<(String,LinkList)>,
+	rows: Vec<VectorRow>,
+}
+struct VectorRow
+{
+	line: u32,
+	inputs: Vec<bool>,
+	expected: Vec<bool>,
+}
+
+/// Values forced onto lines from a given tick (#at)
+struct Stimulus
+{
+	tick: u32,
+	signals: LinkList,
+	values: Vec<bool>,
+}
+
 #[derive(Default)]
 pub struct Test
 {
 	exec_limit: u32,
+	/// Number of ticks to hold reset for at the start of the test
+	reset_hold: u32,
 	completion: LinkList,
 	unit: Unit,
 	assertions: LinkedList<TestAssert>,
+	vectors: Option<TestVectors>,
+	stimulus: Vec<Stimulus>,
+	/// Assertions checked at (or by) a given tick (#expect_at, #expect_within)
+	timed_asserts: Vec<(flat::AssertTiming,TestAssert)>,
+	/// Tags for test selection (`#testcase ... tags=a,b`)
+	tags: Vec<String>,
+	/// Expected to fail (#xfail), with an optional reason
+	xfail: Option<String>,
 }
 
 #[derive(Default)]
@@ -233,6 +281,10 @@ impl Unit
 		self.links.insert(String::from(name), val.clone());
 		val
 	}
+	/// Look up a named link, without creating it
+	pub fn find_link(&self, name: &str) -> Option<LinkRef> {
+		self.links.get(name).cloned()
+	}
 	fn make_anon_link(&mut self) -> LinkRef {
 		let name = format!("#{}", self.anon_links.len());
 		let link = self.make_link(name);
@@ -265,6 +317,15 @@ impl Unit
 		}
 	}
 	
+	/// Set the global reset line, returns true if it was already set
+	pub fn set_reset(&mut self, line: LinkRef) -> bool {
+		if self.reset.is_some() {
+			return true;
+		}
+		self.reset = Some(line);
+		false
+	}
+	
 	pub fn set_output(&mut self, outputs: LinkList) -> bool {
 		if self.outputs.len() > 0 {
 			return true;
@@ -275,7 +336,7 @@ impl Unit
 		}
 	}
 	
-	pub fn append_element(&mut self, meshroot: &Root, name: &str, params: Vec<u64>, inputs: LinkList, outputs: Option<LinkList>) -> Result<LinkList,String>
+	pub fn append_element(&mut self, meshroot: &Root, registry: &::elements::ElementRegistry, name: &str, params: Vec<u64>, inputs: LinkList, outputs: Option<LinkList>) -> Result<LinkList,String>
 	{
 		debug!("append_element('{}', {:?}, in={:?}, out={:?})", name, params, inputs, outputs);
 		match meshroot.get_unit(name)
@@ -295,36 +356,67 @@ impl Unit
 				name: String::from(name),
 				inputs: inputs,
 				outputs: out.clone(),
+				block: self.blocks.join("."),
 				};
 			self.subunits.push_back(r);
 			Ok( out )
 			},
 		None => {
-			let mut ele = try!(::elements::create(name, &*params, inputs.len()));
-			ele.finalise(self);
+			let mut ele = registry.create(name, &*params, inputs.len()).map_err(|e| e.to_string())?;
+			ele.finalise(self).map_err(|e| e.to_string())?;
 			
-			let out = match outputs { Some(o) => o, None => self.make_anon_links( ele.get_outputs(inputs.len()) ) };
+			let n_outputs = ele.get_outputs(inputs.len());
+			let out = match outputs { Some(o) => o, None => self.make_anon_links(n_outputs) };
+			if out.len() != n_outputs {
+				return Err(format!("Output count mismatch for {}, expected {} ({}), got {}",
+					ele.name(), n_outputs, ::elements::describe_pins(&ele.output_pins(inputs.len())), out.len()));
+			}
 			
 			self.elements.push_back( Element {
 				inst: ele,
 				inputs: inputs,
 				outputs: out.clone(),
+				block: self.blocks.join("."),
 				});
 			Ok( out )
 			}
 		}
 	}
-	pub fn append_display(&mut self, cond: LinkList, text: String, values: LinkList) {
+	pub fn append_display(&mut self, cond: LinkList, text: String, format: ::display::Format, values: LinkList) {
 		self.disp_items.push_back( DisplayItem {
 			condition: cond,
 			text: text,
+			format,
 			values: values,
+			block: self.blocks.join("."),
 			});
 	}
-	pub fn append_breakpoint(&mut self, name: String, cond: LinkList) {
+	
+	/// Open a (nested) display block
+	pub fn push_block(&mut self, name: String) {
+		self.blocks.push(name);
+	}
+	/// Close the innermost display block, returns false if none were open
+	pub fn pop_block(&mut self) -> bool {
+		self.blocks.pop().is_some()
+	}
+	/// Get the path of the innermost open block, if any
+	pub fn open_block(&self) -> Option<String> {
+		if self.blocks.is_empty() { None } else { Some(self.blocks.join(".")) }
+	}
+	pub fn append_breakpoint(&mut self, name: String, cond: LinkList, error: bool) {
 		self.breakpoints.push_back( Breakpoint {
 			conds: cond,
 			name: name,
+			error,
+			});
+	}
+	pub fn append_property(&mut self, line: u32, conds: LinkList, vals: LinkList, exp: LinkList) {
+		self.properties.push_back( TestAssert {
+			line,
+			conditions: conds,
+			values: vals,
+			expected: exp,
 			});
 	}
 	
@@ -413,22 +505,21 @@ impl Unit
 		
 		let mut ret = flat::Mesh::new(n_links, n_eles, n_bps, n_disp, self, &self.inputs, &self.outputs);
 
-		/*
 		// Add names to nodes
-		// - TODO: This is never propagated, it uses too much memory to do so. Maybe remove it?
-		let mut nodes = Vec::<flat::Node>::from_elem(n_links, flat::Node{..Default::default()});
-		for (i,link) in self.anon_links.iter().enumerate()
-		{
-			let id = link.borrow().get_alias().unwrap();
-			nodes.get_mut(id).names.push( format!("#{}", i) );
-		}
+		// - Only named links are recorded, anon links are internal to the unit
 		for (name,link) in self.links.iter()
 		{
-			let id = link.borrow().get_alias().unwrap();
-			nodes.get_mut(id).names.push( name.clone() );
+			if name.starts_with('=') {
+				continue ;
+			}
+			if let Some(id) = self.link_collection[link.0].get_alias() {
+				ret.push_node_name( *id, name.clone() );
+			}
+		}
+		for node in ret.nodes.iter_mut() {
+			node.names.sort();
 		}
 		debug!("- Links added");
-		*/
 		
 		// Add elements
 		for ele in self.elements.iter()
@@ -438,6 +529,8 @@ impl Unit
 				inst: ele.inst.dup(),
 				inputs:  flat::linklist_to_noderefs(self, &ele.inputs),
 				outputs: flat::linklist_to_noderefs(self, &ele.outputs),
+				scope: String::new(),
+				block: ele.block.clone(),
 				};
 			ret.push_ele( inst );
 		}
@@ -448,7 +541,8 @@ impl Unit
 			debug!("Breakpoint '{}'", bp.name);
 			ret.push_breakpoint( flat::Breakpoint::new(
 				bp.name.clone(),
-				flat::linklist_to_noderefs(self, &bp.conds)
+				flat::linklist_to_noderefs(self, &bp.conds),
+				bp.error,
 				) );
 		}
 		// Add display items
@@ -457,16 +551,31 @@ impl Unit
 			debug!("Display item '{}'", di.text);
 			ret.push_disp( flat::Display::new(
 				di.text.clone(),
+				di.format.clone(),
+				di.block.clone(),
 				flat::linklist_to_noderefs(self, &di.condition),
 				flat::linklist_to_noderefs(self, &di.values),
 				) );
 		}
+		if let Some(ref line) = self.reset {
+			ret.reset = flat::linklist_to_noderefs(self, &vec![line.clone()]).pop();
+		}
+		// Add properties
+		for prop in self.properties.iter()
+		{
+			ret.push_property( flat::TestAssert::new(
+				prop.line,
+				flat::linklist_to_noderefs(self, &prop.conditions),
+				flat::linklist_to_noderefs(self, &prop.values),
+				flat::linklist_to_noderefs(self, &prop.expected),
+				) );
+		}
 		
 		// Populate from sub-units
 		let mut bind_node_idx = n_local_links as u32;
 		for (i,subu) in self.subunits.iter().enumerate()
 		{
-			bind_node_idx += self.flatten_merge_subunit(&mut ret, &*subunits[i], subu, bind_node_idx);
+			bind_node_idx += self.flatten_merge_subunit(&mut ret, &subunits[i], subu, i, bind_node_idx);
 		}
 		assert!(bind_node_idx as usize == n_links);
 		assert!(ret.elements.len() == n_eles);
@@ -481,9 +590,10 @@ impl Unit
 	/// @param elements	- Output element list (new elements appeneded)
 	/// @param flattened	- Flattened sub-mesh
 	/// @param subu 	- Subunit reference (used for outside node IDs)
+	/// @param subu_idx	- Index of the subunit reference (used for node names)
 	/// @param bind_node_idx	- ID to use for the next internal node
 	/// @return Number of internal noes
-	fn flatten_merge_subunit(&self, mesh: &mut flat::Mesh, flattened: &flat::Mesh, subu: &UnitRef, bind_node_idx: u32) -> u32
+	fn flatten_merge_subunit(&self, mesh: &mut flat::Mesh, flattened: &Rc<flat::Mesh>, subu: &UnitRef, subu_idx: usize, bind_node_idx: u32) -> u32
 	{
 		let inputs  = flat::linklist_to_noderefs( self, &subu.inputs );
 		let outputs = flat::linklist_to_noderefs( self, &subu.outputs );
@@ -518,25 +628,8 @@ impl Unit
 		}
 		debug!("{} unbound nodes", unbound_nodes);
 		
-		/*
-		// Append node names to link name list
-		for (j,alias) in aliases.iter().enumerate()
-		{
-			match alias.unwrap()
-			{
-			NodeId(id) => {
-				for name in flattened.nodes[j].names.iter() {
-					nodes.get_mut(id).names.push( format!(">{}-{}:{}", subu.name, i, flattened.nodes[j].names) );
-				}
-				},
-			_ => {
-				}
-			}
-		}
-		*/
-		
 		// Import elements
-		mesh.merge(flattened, &aliases);
+		mesh.merge(flattened, &aliases, &format!("{}#{}/", subu.name, subu_idx), &subu.block);
 		
 		return unbound_nodes;
 	}
@@ -563,14 +656,50 @@ impl Unit
 		return ret;
 	}
 
-	pub fn get_rom(&self, index: usize) -> Rc<Vec<u64>> {
-		self.rom_data[index].clone().unwrap()
+	pub fn get_rom(&self, index: usize) -> Option<Rc<Vec<u64>>> {
+		self.rom_data.get(index).and_then(|v| v.as_ref()).map(|b| b.words.clone())
 	}
-	pub fn set_rom_data(&mut self, index: usize, data: Vec<u64>) {
+	/// Set a data block, optionally with the file (and per-word offsets) it was loaded from
+	pub fn set_rom_data(&mut self, index: usize, data: Vec<u64>, source: Option<(::std::path::PathBuf, Vec<Option<usize>>)>) {
 		if self.rom_data.len() <= index {
 			self.rom_data.resize_with(index+1, Default::default)
 		}
-		self.rom_data[index] = Some(Rc::new(data))
+		self.rom_data[index] = Some(DataBlock { words: Rc::new(data), source })
+	}
+	/// Check that a data block fits in a memory of `n_words` words of `word_bits` bits
+	pub fn check_rom(&self, index: usize, n_words: usize, word_bits: usize) -> Result<(),String> {
+		let block = match self.rom_data.get(index).and_then(|b| b.as_ref()) {
+			Some(b) => b,
+			None => return Ok( () ),
+			};
+		let describe = |i: usize| match block.source
+			{
+			Some((ref path, ref offsets)) => match offsets.get(i).cloned().flatten() {
+				Some(ofs) => format!("{} offset {}", path.display(), ofs),
+				None => format!("{} word {}", path.display(), i),
+				},
+			None => format!("word {}", i),
+			};
+		if block.words.len() > n_words {
+			// Report the first extra word that came from the file (rather than padding)
+			let i = match block.source {
+				Some((_, ref offsets)) => (n_words .. offsets.len()).find(|&i| offsets[i].is_some()).unwrap_or(n_words),
+				None => n_words,
+				};
+			return Err(format!("Data block {} ({}): {} words don't fit in the memory's {} words", index, describe(i), block.words.len(), n_words));
+		}
+		if word_bits < 64 {
+			if let Some(i) = block.words.iter().position(|&w| w >> word_bits != 0) {
+				return Err(format!("Data block {} ({}): Value {:#x} doesn't fit in {} bits", index, describe(i), block.words[i], word_bits));
+			}
+		}
+		Ok( () )
+	}
+	pub fn get_io_file(&self, index: usize, transmit: bool) -> Option<::std::path::PathBuf> {
+		self.io_files.get(&(index, transmit)).cloned()
+	}
+	pub fn set_io_file(&mut self, index: usize, transmit: bool, path: ::std::path::PathBuf) {
+		self.io_files.insert( (index, transmit), path );
 	}
 }
 
@@ -586,6 +715,24 @@ impl Test
 	pub fn get_unit(&mut self) -> &mut Unit {
 		&mut self.unit
 	}
+	pub fn set_reset_hold(&mut self, ticks: u32) {
+		self.reset_hold = ticks;
+	}
+	pub fn exec_limit(&self) -> u32 {
+		self.exec_limit
+	}
+	pub fn set_tags(&mut self, tags: Vec<String>) {
+		self.tags = tags;
+	}
+	pub fn tags(&self) -> &[String] {
+		&self.tags
+	}
+	pub fn set_xfail(&mut self, reason: String) {
+		self.xfail = Some(reason);
+	}
+	pub fn xfail(&self) -> Option<&str> {
+		self.xfail.as_ref().map(|r| &r[..])
+	}
 	
 	pub fn set_completion(&mut self, conds: LinkList) -> bool {
 		if self.completion.len() > 0 {
@@ -605,6 +752,34 @@ impl Test
 			});
 	}
 	
+	/// Force `signals` to `values` (LSB first) from the start of tick `tick` (held until changed by another `#at`)
+	pub fn add_stimulus(&mut self, tick: u32, signals: LinkList, values: Vec<bool>) {
+		self.stimulus.push( Stimulus { tick, signals, values } );
+	}
+	pub fn add_timed_assert(&mut self, timing: flat::AssertTiming, line: u32, vals: LinkList, exp: LinkList) {
+		self.timed_asserts.push( (timing, TestAssert {
+			line,
+			conditions: Vec::new(),
+			values: vals,
+			expected: exp,
+			}) );
+	}
+	pub fn has_completion(&self) -> bool {
+		!self.completion.is_empty()
+	}
+	/// Whether the test has a `#testvectors` block without any rows
+	pub fn has_empty_vectors(&self) -> bool {
+		self.vectors.as_ref().is_some_and(|v| v.rows.is_empty())
+	}
+	/// Set the test vectors, returns true if the test already has them
+	pub fn set_vectors(&mut self, vectors: TestVectors) -> bool {
+		if self.vectors.is_some() {
+			return true;
+		}
+		self.vectors = Some(vectors);
+		false
+	}
+	
 	pub fn flatten(&mut self, flat_units: &Flatmap) -> flat::Test
 	{
 		let flat = self.unit.flatten(flat_units);
@@ -616,7 +791,62 @@ impl Test
 				flat::linklist_to_noderefs(&self.unit, &a.expected),
 				)
 			).collect();
-		flat::Test::new(flat, self.exec_limit, flat::linklist_to_noderefs(&self.unit, &self.completion), asserts)
+		let mut rv = flat::Test::new(flat, self.exec_limit, flat::linklist_to_noderefs(&self.unit, &self.completion), asserts);
+		rv.set_reset_hold(self.reset_hold);
+		rv.set_xfail(self.xfail.is_some());
+		let mut stimulus: Vec<_> = self.stimulus.iter().map(|s| flat::Stimulus {
+			tick: s.tick,
+			nodes: flat::linklist_to_noderefs(&self.unit, &s.signals),
+			values: s.values.clone(),
+			}).collect();
+		stimulus.sort_by_key(|s| s.tick);
+		rv.set_stimulus(stimulus);
+		rv.set_timed_asserts( self.timed_asserts.iter().map(|&(timing, ref a)| flat::TimedAssert {
+			timing,
+			check: flat::TestAssert::new(
+				a.line,
+				Vec::new(),
+				flat::linklist_to_noderefs(&self.unit, &a.values),
+				flat::linklist_to_noderefs(&self.unit, &a.expected),
+				),
+			}).collect() );
+		if let Some(ref v) = self.vectors
+		{
+			let links = |signals: &Vec<(String,LinkList)>| signals.iter().flat_map(|s| s.1.iter().cloned()).collect::<LinkList>();
+			let outputs = flat::linklist_to_noderefs(&self.unit, &links(&v.outputs));
+			rv.set_vectors( flat::TestVectors {
+				period: v.period,
+				inputs: flat::linklist_to_noderefs(&self.unit, &links(&v.inputs)),
+				input_signals: v.inputs.iter().map(|s| (s.0.clone(), s.1.len())).collect(),
+				output_signals: v.outputs.iter().map(|s| (s.0.clone(), s.1.len())).collect(),
+				rows: v.rows.iter().map(|r| flat::VectorRow {
+					inputs: r.inputs.clone(),
+					check: flat::TestAssert::new(r.line, Vec::new(), outputs.clone(),
+						r.expected.iter().map(|&b| if b { flat::NodeRef::NodeOne } else { flat::NodeRef::NodeZero }).collect()),
+					}).collect(),
+				} );
+		}
+		rv
+	}
+}
+
+impl TestVectors
+{
+	pub fn new(period: u32, inputs: Vec<(String,LinkList)>, outputs: Vec<(String,LinkList)>) -> TestVectors {
+		TestVectors {
+			period,
+			inputs,
+			outputs,
+			rows: Vec::new(),
+		}
+	}
+	/// Add a row of values (LSB first, in the order of the input/output signals)
+	pub fn add_row(&mut self, line: u32, inputs: Vec<bool>, expected: Vec<bool>) {
+		self.rows.push( VectorRow { line, inputs, expected } );
+	}
+	/// Ticks needed to apply every row
+	pub fn n_ticks(&self) -> u64 {
+		self.rows.len() as u64 * self.period as u64
 	}
 }
 
@@ -642,6 +872,9 @@ impl Root
 	pub fn get_unit(&self, name: &str) -> Option<&Unit> {
 		self.units.get(name)
 	}
+	pub fn get_test(&self, name: &str) -> Option<&Test> {
+		self.tests.get(name)
+	}
 	pub fn add_test(&mut self, name: String, exec_limit: u32) -> Result<&mut Test,String> {
 		match self.tests.entry(name.clone())
 		{
@@ -661,26 +894,45 @@ impl Root
 		let ret = (*self.rootunit.flatten(&self.flat_units)).clone();
 		return ret;
 	}
-	pub fn flatten_tests(&mut self)
+	/// Flatten the named tests (and the units they use)
+	pub fn flatten_tests(&mut self, names: &[String])
 	{
-		for (name,test) in self.tests.iter()
+		for name in names.iter()
 		{
+			let test = self.tests.get_mut(name).unwrap_or_else(|| panic!("Unknown test '{}'", name));
 			info!("Flattening deps for '{}'", name);
 			for name in test.unit.get_subunits().iter()
 			{
 				flatten_unit(&mut self.units, &mut self.flat_units, name);
 			}
-		}
-		for (name,test) in self.tests.iter_mut()
-		{
 			self.flat_tests.insert( name.clone(), test.flatten(&self.flat_units) );
 		}
 	}
 	
+	/// Iterate test definitions (before flattening)
+	pub fn iter_test_defs(&self) -> ::std::collections::hash_map::Iter<'_,String,Test>
+	{
+		self.tests.iter()
+	}
+	/// Iterate flattened tests
 	pub fn iter_tests(&self) -> ::std::collections::hash_map::Iter<String,flat::Test>
 	{
 		self.flat_tests.iter()
 	}
+	/// Flatten a single unit (and the units it uses) by name
+	pub fn flatten_unit(&mut self, name: &str) -> Option<Rc<flat::Mesh>>
+	{
+		if !self.units.contains_key(name) {
+			return None;
+		}
+		flatten_unit(&mut self.units, &mut self.flat_units, name);
+		self.flat_units.get(name).cloned()
+	}
+	/// Get the flattened mesh for a unit (only valid after flattening)
+	pub fn get_flat_unit(&self, name: &str) -> Option<&Rc<flat::Mesh>>
+	{
+		self.flat_units.get(name)
+	}
 }
 
 fn flatten_unit(units: &mut HashMap<String,Unit>, flat_units: &mut Flatmap, name: &str)
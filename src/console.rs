@@ -0,0 +1,83 @@
+//
+//
+//
+//! Process-wide console input and output files (shared by all UART elements and `--tui`)
+//!
+//! Stdin is read by a single background thread (started on first use), so reads never block the
+//! simulation and multiple readers don't compete for bytes. Transmit files are created once per process
+//! and shared, so multiple elements (and test runs) append to the same output.
+use std::cell::{Cell,RefCell};
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+
+thread_local! {
+	static STDIN: RefCell<Option<mpsc::Receiver<u8>>> = const { RefCell::new(None) };
+	/// Set when the terminal view owns stdin (console reads then see no input)
+	static RESERVED: Cell<bool> = const { Cell::new(false) };
+	static TX_FILES: RefCell<HashMap<PathBuf,Rc<RefCell<::std::fs::File>>>> = RefCell::new(HashMap::new());
+}
+
+fn next_stdin() -> Result<u8,bool>
+{
+	STDIN.with(|rx| {
+		let mut rx = rx.borrow_mut();
+		let rx = rx.get_or_insert_with(|| {
+			let (tx, rx) = mpsc::channel();
+			::std::thread::spawn(move || {
+				use std::io::Read;
+				for b in ::std::io::stdin().lock().bytes() {
+					match b {
+					Ok(b) => if tx.send(b).is_err() { break },
+					Err(_) => break,
+					}
+				}
+				});
+			rx
+			});
+		match rx.try_recv()
+		{
+		Ok(b) => Ok(b),
+		Err(mpsc::TryRecvError::Empty) => Err(false),
+		Err(mpsc::TryRecvError::Disconnected) => Err(true),
+		}
+		})
+}
+
+/// Returns the next byte of stdin, or Err(true) at end of input (Err(false) if nothing is available yet)
+pub fn read_byte() -> Result<u8,bool>
+{
+	if RESERVED.with(|r| r.get()) {
+		return Err(false);
+	}
+	next_stdin()
+}
+
+/// Take stdin for the terminal view's key presses (console reads no longer get any input)
+pub fn reserve_for_keys()
+{
+	RESERVED.with(|r| r.set(true));
+}
+/// Returns the next key press (after `reserve_for_keys`), if any
+pub fn read_key() -> Option<u8>
+{
+	next_stdin().ok()
+}
+
+/// Open a transmit file, creating it on first use and sharing the handle afterwards
+pub fn open_tx(path: &Path) -> Result<Rc<RefCell<::std::fs::File>>,String>
+{
+	TX_FILES.with(|files| {
+		let mut files = files.borrow_mut();
+		if let Some(f) = files.get(path) {
+			return Ok(f.clone());
+		}
+		let f = ::std::fs::File::create(path).map_err(|e| format!("Unable to open '{}' for transmit: {}", path.display(), e))?;
+		let f = Rc::new(RefCell::new(f));
+		files.insert(path.to_owned(), f.clone());
+		Ok(f)
+		})
+}
+
+// vim: ft=rust
@@ -0,0 +1,309 @@
+//
+//
+//
+//! Toggle and selector coverage collection
+use std::collections::BTreeMap;
+use std::io::Write;
+use cct_mesh::flat;
+use elements::Element;
+
+/// Per-engine coverage collector
+pub struct Coverage
+{
+	/// Per node: bit 0 = seen low, bit 1 = seen high
+	nodes: Vec<u8>,
+	/// Per element: select values seen (empty if the element has no selector)
+	selects: Vec<Vec<bool>>,
+}
+
+#[derive(Default)]
+struct SignalCov
+{
+	zero: bool,
+	one: bool,
+}
+#[derive(Default)]
+struct SelectCov
+{
+	seen: Vec<bool>,
+}
+#[derive(Default)]
+struct UnitCov
+{
+	signals: BTreeMap<String,SignalCov>,
+	selects: BTreeMap<String,SelectCov>,
+}
+
+/// Coverage results merged across several meshes (grouped by unit and signal name)
+#[derive(Default)]
+pub struct Report
+{
+	units: BTreeMap<String,UnitCov>,
+	/// Count of nodes without a name (not included in the totals)
+	anon_nodes: usize,
+}
+
+impl Coverage
+{
+	pub fn new(mesh: &flat::Mesh) -> Coverage {
+		Coverage {
+			nodes: ::from_elem(mesh.n_nodes, 0),
+			selects: mesh.elements.iter().map(|e| ::from_elem(e.inst.select_range(), false)).collect(),
+		}
+	}
+
+	/// Record the state of all nodes after a tick
+	pub fn record_nodes(&mut self, state: &[bool]) {
+		for (c,v) in self.nodes.iter_mut().zip( state.iter() ) {
+			*c |= if *v { 2 } else { 1 };
+		}
+	}
+	/// Record the selection made by an element (if it has a selector)
+	pub fn record_select(&mut self, ele_idx: usize, ele: &dyn Element, inlines: &[bool]) {
+		let sel = &mut self.selects[ele_idx];
+		if !sel.is_empty() {
+			if let Some(v) = ele.select_value(inlines) {
+				sel[v] = true;
+			}
+		}
+	}
+}
+
+impl Report
+{
+	pub fn new() -> Report {
+		Default::default()
+	}
+
+	/// Merge the coverage from a single engine run into the report
+	pub fn merge(&mut self, mesh: &flat::Mesh, cov: &Coverage)
+	{
+		let node_names = mesh.node_names();
+		for (id,names) in node_names.iter().enumerate()
+		{
+			if names.is_empty() {
+				self.anon_nodes += 1;
+				continue ;
+			}
+			// All names of a node get the coverage, so sub-unit ports are credited to both sides
+			for name in names.iter()
+			{
+				let (unit, signal) = mesh.split_node_name(name);
+				let e = self.get_unit(unit).signals.entry(signal.to_string()).or_default();
+				e.zero |= cov.nodes[id] & 1 != 0;
+				e.one  |= cov.nodes[id] & 2 != 0;
+			}
+		}
+
+		for (ele,seen) in mesh.elements.iter().zip( cov.selects.iter() )
+		{
+			if seen.is_empty() {
+				continue ;
+			}
+			let outname = match ele.outputs.first()
+				{
+				Some(&flat::NodeRef::NodeId(id)) => node_names[id as usize].iter()
+					.find(|n| n.starts_with(&ele.scope[..]) && !n[ele.scope.len()..].contains('/'))
+					.cloned(),
+				_ => None,
+				};
+			let (unit, label) = match outname
+				{
+				Some(ref n) => {
+					let (unit, signal) = mesh.split_node_name(n);
+					(unit.to_string(), format!("{} -> {}", ele.inst.name(), signal))
+					},
+				None => {
+					(mesh.scope_unit(&ele.scope).to_string(), format!("{} -> (anon)", ele.inst.name()))
+					},
+				};
+			let e = self.get_unit(&unit).selects.entry(label).or_default();
+			if e.seen.len() < seen.len() {
+				e.seen.resize(seen.len(), false);
+			}
+			for (d,s) in e.seen.iter_mut().zip( seen.iter() ) {
+				*d |= *s;
+			}
+		}
+	}
+	fn get_unit(&mut self, name: &str) -> &mut UnitCov {
+		self.units.entry( name.to_string() ).or_default()
+	}
+
+	/// Returns (covered, total) toggle points (two per signal)
+	pub fn toggle_counts(&self) -> (usize, usize) {
+		let mut covered = 0;
+		let mut total = 0;
+		for u in self.units.values() {
+			for s in u.signals.values() {
+				covered += s.zero as usize + s.one as usize;
+				total += 2;
+			}
+		}
+		(covered, total)
+	}
+	/// Returns (covered, total) select values
+	pub fn select_counts(&self) -> (usize, usize) {
+		let mut covered = 0;
+		let mut total = 0;
+		for u in self.units.values() {
+			for s in u.selects.values() {
+				covered += s.seen.iter().filter(|v| **v).count();
+				total += s.seen.len();
+			}
+		}
+		(covered, total)
+	}
+	/// Overall coverage percentage (toggle and select points combined)
+	pub fn percent(&self) -> f64 {
+		let (tc, tt) = self.toggle_counts();
+		let (sc, st) = self.select_counts();
+		if tt + st == 0 {
+			100.0
+		}
+		else {
+			(tc + sc) as f64 * 100.0 / (tt + st) as f64
+		}
+	}
+	/// Check the total against a minimum percentage (`--coverage-threshold`)
+	pub fn check_threshold(&self, threshold: f64) -> Result<(),String> {
+		if self.percent() < threshold {
+			Err( format!("Coverage {:.1}% is below threshold {}%", self.percent(), threshold) )
+		}
+		else {
+			Ok( () )
+		}
+	}
+
+	pub fn write_text(&self, out: &mut dyn Write) -> ::std::io::Result<()>
+	{
+		for (unit_name,unit) in self.units.iter()
+		{
+			writeln!(out, "Unit {}", unit_display_name(unit_name))?;
+			for (name,s) in unit.signals.iter()
+			{
+				writeln!(out, "  {:30} {} {}{}", name,
+					if s.zero { "0" } else { "-" },
+					if s.one  { "1" } else { "-" },
+					if s.zero && s.one { "" } else { "  <<" }
+					)?;
+			}
+			for (name,s) in unit.selects.iter()
+			{
+				let missing: Vec<_> = s.seen.iter().enumerate().filter(|&(_,v)| !*v).map(|(i,_)| i).collect();
+				write!(out, "  {:30} {}/{}", name, s.seen.len() - missing.len(), s.seen.len())?;
+				if !missing.is_empty() {
+					write!(out, "  << missing {:?}", missing)?;
+				}
+				writeln!(out)?;
+			}
+		}
+		let (tc, tt) = self.toggle_counts();
+		let (sc, st) = self.select_counts();
+		writeln!(out, "Toggle coverage: {}/{} points", tc, tt)?;
+		writeln!(out, "Select coverage: {}/{} values", sc, st)?;
+		if self.anon_nodes > 0 {
+			writeln!(out, "({} anonymous nodes not included)", self.anon_nodes)?;
+		}
+		writeln!(out, "Total: {:.1}%", self.percent())?;
+		Ok( () )
+	}
+
+	pub fn write_json(&self, out: &mut dyn Write) -> ::std::io::Result<()>
+	{
+		let (tc, tt) = self.toggle_counts();
+		let (sc, st) = self.select_counts();
+		write!(out, "{{\"toggle\":{{\"covered\":{},\"total\":{}}},", tc, tt)?;
+		write!(out, "\"select\":{{\"covered\":{},\"total\":{}}},", sc, st)?;
+		write!(out, "\"percent\":{:.3},\"units\":{{", self.percent())?;
+		for (i,(unit_name,unit)) in self.units.iter().enumerate()
+		{
+			if i > 0 { write!(out, ",")?; }
+			write!(out, "{}:{{\"signals\":{{", json_str(unit_name))?;
+			for (j,(name,s)) in unit.signals.iter().enumerate()
+			{
+				if j > 0 { write!(out, ",")?; }
+				write!(out, "{}:{{\"zero\":{},\"one\":{}}}", json_str(name), s.zero, s.one)?;
+			}
+			write!(out, "}},\"selects\":{{")?;
+			for (j,(name,s)) in unit.selects.iter().enumerate()
+			{
+				if j > 0 { write!(out, ",")?; }
+				let seen: Vec<String> = s.seen.iter().enumerate().filter(|&(_,v)| *v).map(|(i,_)| i.to_string()).collect();
+				write!(out, "{}:{{\"total\":{},\"seen\":[{}]}}", json_str(name), s.seen.len(), seen.join(","))?;
+			}
+			write!(out, "}}}}")?;
+		}
+		writeln!(out, "}}}}")?;
+		Ok( () )
+	}
+}
+
+fn unit_display_name(name: &str) -> &str {
+	if name.is_empty() { "(root)" } else { name }
+}
+
+/// Quote and escape a string for JSON output
+pub fn json_str(s: &str) -> String
+{
+	let mut rv = String::with_capacity(s.len() + 2);
+	rv.push('"');
+	for c in s.chars()
+	{
+		match c
+		{
+		'"' => rv.push_str("\\\""),
+		'\\' => rv.push_str("\\\\"),
+		'\n' => rv.push_str("\\n"),
+		c if (c as u32) < 0x20 => rv.push_str(&format!("\\u{:04x}", c as u32)),
+		c => rv.push(c),
+		}
+	}
+	rv.push('"');
+	rv
+}
+
+#[test]
+fn test_report()
+{
+	// Two instances of a unit: one walks the decoder through 0-2, the other holds it at 2
+	let mut root = ::test_util::load(concat!(
+		"#defunit SEL\n",
+		"#input $en, @s[2]\n",
+		"#output @o[4]\n",
+		"@o = DECODER{2} $en, @s\n",
+		"#endunit\n",
+		"#testcase 8 \"t\"\n",
+		"#array q 2\n",
+		"#array o 4\n",
+		"#array p 4\n",
+		"@q, $tc = COUNTER{2} 1, 0, 0, 0, 0, 0\n",
+		"@o = SEL 1, @q\n",
+		"@p = SEL 1, 0, 1\n",
+		"#testcomplete $tc\n",
+		"#endtestcase\n",
+		));
+	root.flatten_tests(&["t".to_string()]);
+	let (_, test) = root.iter_tests().next().unwrap();
+	let mut report = Report::new();
+	assert_eq!( ::run_test(test, None, None, Some(&mut report)), ::TestStatus::Pass(3) );
+
+	// 11 test signals and 6 SEL signals (o[3] never goes high, p[*] only ever have one value)
+	assert_eq!( report.toggle_counts(), (28, 34) );
+	// Both instances' selections are merged into the one SEL decoder
+	assert_eq!( report.select_counts(), (3, 4) );
+	
+	let mut json = Vec::new();
+	report.write_json(&mut json).unwrap();
+	let json = String::from_utf8(json).unwrap();
+	assert!( json.starts_with("{\"toggle\":{\"covered\":28,\"total\":34},\"select\":{\"covered\":3,\"total\":4},\"percent\":81.579,") );
+	// p[2] is only ever high in the test, but SEL's o[2] also sees it low from the other instance
+	assert!( json.contains("\"p[ 2]\":{\"zero\":false,\"one\":true}") );
+	assert!( json.contains("\"SEL\":{\"signals\":{\"o[ 0]\":{\"zero\":true,\"one\":true},\"o[ 1]\":{\"zero\":true,\"one\":true},\"o[ 2]\":{\"zero\":true,\"one\":true},\"o[ 3]\":{\"zero\":true,\"one\":false},") );
+	assert!( json.contains("\"selects\":{\"ElementDECODER{2} -> o[ 0]\":{\"total\":4,\"seen\":[0,1,2]}}") );
+	
+	assert_eq!( report.check_threshold(80.0), Ok(()) );
+	assert_eq!( report.check_threshold(90.0), Err("Coverage 81.6% is below threshold 90%".to_string()) );
+}
+
+// vim: ft=rust
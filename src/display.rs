@@ -0,0 +1,332 @@
+//
+//
+//
+//! `#display` format strings
+//!
+//! Specifiers are `%[flags][width.][bits]conv`, taking `bits` (default 1, 8 for `c`) values LSB first.
+//! - Flags: `0` zero-pad, `-` left-align, `>` read the values MSB first
+//! - Conversions: `i` unsigned decimal, `s` signed decimal, `x`/`X` hex, `o` octal, `b` binary,
+//!   `c` ASCII character (`.` if not printable)
+//! - `%%` is a literal `%`
+//!
+//! Zero-padding without a width pads to the widest value that fits in `bits`.
+use simulator::{decode_u64_be, decode_u64_le};
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Conv
+{
+	Unsigned,
+	Signed,
+	Hex,
+	HexUpper,
+	Octal,
+	Binary,
+	Char,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+struct Spec
+{
+	conv: Conv,
+	bits: u8,
+	width: Option<usize>,
+	zero_pad: bool,
+	left_align: bool,
+	msb_first: bool,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Item
+{
+	Text(String),
+	Value(Spec),
+}
+
+/// A parsed `#display` format string
+#[derive(Clone,Debug,PartialEq)]
+pub struct Format
+{
+	items: Vec<Item>,
+}
+
+impl Format
+{
+	pub fn parse(fmt: &str) -> Result<Format,String>
+	{
+		let mut items = Vec::new();
+		let mut text = String::new();
+		let mut it = fmt.chars().peekable();
+		while let Some(c) = it.next()
+		{
+			if c != '%' {
+				text.push(c);
+				continue ;
+			}
+			if it.peek() == Some(&'%') {
+				it.next();
+				text.push('%');
+				continue ;
+			}
+
+			let mut spec = Spec { conv: Conv::Unsigned, bits: 1, width: None, zero_pad: false, left_align: false, msb_first: false };
+			loop
+			{
+				match it.peek()
+				{
+				Some(&'0') => spec.zero_pad = true,
+				Some(&'-') => spec.left_align = true,
+				Some(&'>') => spec.msb_first = true,
+				_ => break,
+				}
+				it.next();
+			}
+			let mut num = String::new();
+			while let Some(&d) = it.peek().filter(|c| c.is_ascii_digit()) {
+				num.push(d);
+				it.next();
+			}
+			let bits = if it.peek() == Some(&'.') {
+					it.next();
+					spec.width = Some( num.parse().map_err(|_| format!("Missing width before '.' in specifier {}", items.len()))? );
+					num.clear();
+					while let Some(&d) = it.peek().filter(|c| c.is_ascii_digit()) {
+						num.push(d);
+						it.next();
+					}
+					num
+				}
+				else {
+					num
+				};
+			spec.conv = match it.next()
+				{
+				Some('i') => Conv::Unsigned,
+				Some('s') => Conv::Signed,
+				Some('x') => Conv::Hex,
+				Some('X') => Conv::HexUpper,
+				Some('o') => Conv::Octal,
+				Some('b') => Conv::Binary,
+				Some('c') => Conv::Char,
+				Some(c) => return Err(format!("Unknown format specifier '%{}'", c)),
+				None => return Err("Unterminated format specifier at end of string".to_string()),
+				};
+			spec.bits = if bits.is_empty() {
+					if spec.conv == Conv::Char { 8 } else { 1 }
+				}
+				else {
+					match bits.parse::<u8>() {
+					Ok(v) if (1 ..= 64).contains(&v) => v,
+					_ => return Err(format!("Bit count {} out of range, must be 1--64", bits)),
+					}
+				};
+			if spec.conv == Conv::Char && spec.bits > 8 {
+				return Err(format!("Character specifier takes at most 8 bits, got {}", spec.bits));
+			}
+
+			if !text.is_empty() {
+				items.push( Item::Text(::std::mem::take(&mut text)) );
+			}
+			items.push( Item::Value(spec) );
+		}
+		if !text.is_empty() {
+			items.push( Item::Text(text) );
+		}
+		Ok(Format { items })
+	}
+
+	/// Number of values consumed by the specifiers
+	pub fn n_bits(&self) -> usize
+	{
+		self.items.iter().map(|i| match *i { Item::Value(ref s) => s.bits as usize, _ => 0 }).sum()
+	}
+	/// Returns true if the format contains any value specifiers
+	pub fn has_values(&self) -> bool
+	{
+		self.items.iter().any(|i| matches!(*i, Item::Value(_)))
+	}
+
+	/// Format the values
+	///
+	/// A format without specifiers has the values appended as raw bits (e.g. ">> 0101")
+	pub fn render(&self, vals: &[bool]) -> String
+	{
+		let mut rv = String::new();
+		let mut idx = 0;
+		for item in self.items.iter()
+		{
+			match *item
+			{
+			Item::Text(ref t) => rv.push_str(t),
+			Item::Value(ref spec) => {
+				let bits = &vals[idx ..][..spec.bits as usize];
+				idx += spec.bits as usize;
+				spec.render(&mut rv, if spec.msb_first { decode_u64_be(bits) } else { decode_u64_le(bits) });
+				},
+			}
+		}
+		if idx == 0 && !vals.is_empty()
+		{
+			rv.push_str(">> ");
+			for &v in vals.iter() {
+				rv.push(if v { '1' } else { '0' });
+			}
+		}
+		rv
+	}
+}
+
+impl Spec
+{
+	fn render(&self, out: &mut String, val: u64)
+	{
+		let max = !0u64 >> (64 - self.bits);
+		let (sign, digits) = match self.conv
+			{
+			Conv::Unsigned => ("", val.to_string()),
+			Conv::Signed => {
+				let v = ((val << (64 - self.bits)) as i64) >> (64 - self.bits);
+				(if v < 0 { "-" } else { "" }, v.unsigned_abs().to_string())
+				},
+			Conv::Hex => ("", format!("{:x}", val)),
+			Conv::HexUpper => ("", format!("{:X}", val)),
+			Conv::Octal => ("", format!("{:o}", val)),
+			Conv::Binary => ("", format!("{:b}", val)),
+			Conv::Char => {
+				let c = val as u8 as char;
+				("", if c.is_ascii_graphic() || c == ' ' { c.to_string() } else { ".".to_string() })
+				},
+			};
+		let width = match self.width
+			{
+			Some(w) => w,
+			// Zero-padding defaults to the widest possible value
+			None if self.zero_pad => match self.conv
+				{
+				Conv::Unsigned => max.to_string().len(),
+				Conv::Signed => 1 + (max >> 1).to_string().len(),
+				Conv::Hex | Conv::HexUpper => (self.bits as usize).div_ceil(4),
+				Conv::Octal => (self.bits as usize).div_ceil(3),
+				Conv::Binary => self.bits as usize,
+				Conv::Char => 1,
+				},
+			None => 0,
+			};
+		let pad = width.saturating_sub(sign.len() + digits.len());
+		if self.left_align {
+			out.push_str(sign);
+			out.push_str(&digits);
+			out.extend( ::std::iter::repeat_n(' ', pad) );
+		}
+		else if self.zero_pad && self.conv != Conv::Char {
+			out.push_str(sign);
+			out.extend( ::std::iter::repeat_n('0', pad) );
+			out.push_str(&digits);
+		}
+		else {
+			out.extend( ::std::iter::repeat_n(' ', pad) );
+			out.push_str(sign);
+			out.push_str(&digits);
+		}
+	}
+}
+
+/// Selects which display blocks are shown (`--display-block` / `--hide-block`)
+///
+/// Naming a block also selects the blocks nested in it.
+#[derive(Clone,Default)]
+pub struct BlockFilter
+{
+	/// If non-empty, only these blocks are shown
+	pub show: Vec<String>,
+	pub hide: Vec<String>,
+}
+impl BlockFilter
+{
+	pub fn is_shown(&self, block: &str) -> bool
+	{
+		let matches = |name: &String| block == name || (block.starts_with(&name[..]) && block[name.len()..].starts_with('.'));
+		if self.hide.iter().any(&matches) {
+			return false;
+		}
+		self.show.is_empty() || self.show.iter().any(&matches)
+	}
+}
+
+/// Destination for the lines shown by `#display` items
+pub trait Sink
+{
+	/// Called for each display item shown on `tick`
+	fn display(&mut self, tick: u64, text: &str);
+	/// Called after a tick that showed any display items
+	fn end_tick(&mut self, _tick: u64) {}
+}
+
+/// Prints display lines, followed by a marker line (e.g. the tick number) after each tick that showed any
+pub struct Stdout
+{
+	pub marker: fn(u64) -> String,
+}
+impl Sink for Stdout
+{
+	fn display(&mut self, _tick: u64, text: &str)
+	{
+		println!("{}", text);
+	}
+	fn end_tick(&mut self, tick: u64)
+	{
+		println!("{}", (self.marker)(tick));
+	}
+}
+
+/// Collects display lines (prefixed with their tick) for comparison against a golden log
+#[derive(Default)]
+pub struct Log
+{
+	text: String,
+}
+impl Log
+{
+	/// Start a named section (e.g. one per test)
+	pub fn section(&mut self, name: &str)
+	{
+		self.text.push_str(&format!("== {}\n", name));
+	}
+	pub fn text(&self) -> &str
+	{
+		&self.text
+	}
+}
+impl Sink for Log
+{
+	fn display(&mut self, tick: u64, text: &str)
+	{
+		self.text.push_str(&format!("{}: {}\n", tick, text));
+	}
+}
+
+#[test]
+fn test_format()
+{
+	use test_util::bits;
+	let fmt = |f: &str, vals: Vec<bool>| Format::parse(f).unwrap().render(&vals);
+
+	assert_eq!( fmt("a=%4i %%", bits(9, 4)), "a=9 %" );
+	assert_eq!( fmt("%4s %4s", [bits(0xE, 4), bits(5, 4)].concat()), "-2 5" );
+	assert_eq!( fmt("%08x|%6.8X|%-6.8o|", [bits(0xAB, 8), bits(0xAB, 8), bits(8, 8)].concat()), "ab|    AB|10    |" );
+	assert_eq!( fmt("%04b %>04b", [bits(3, 4), bits(3, 4)].concat()), "0011 1100" );
+	assert_eq!( fmt("%05.8s", bits(0xFF, 8)), "-0001" );
+	assert_eq!( fmt("%c%c", [bits(b'O' as u64, 8), bits(7, 8)].concat()), "O." );
+	assert_eq!( fmt("a=", bits(5, 3)), "a=>> 101" );
+
+	assert_eq!( Format::parse("%4i %8x %c").unwrap().n_bits(), 20 );
+	assert!( Format::parse("%q").unwrap_err().contains("'%q'") );
+	assert!( Format::parse("%65x").is_err() );
+	assert!( Format::parse("%12c").is_err() );
+	assert!( Format::parse("abc %").is_err() );
+	
+	let filter = BlockFilter { show: vec!["cpu".to_string()], hide: vec!["cpu.decode".to_string()] };
+	assert!( filter.is_shown("cpu") && filter.is_shown("cpu.alu") );
+	assert!( !filter.is_shown("cpu.decode.imm") && !filter.is_shown("cpux") && !filter.is_shown("") );
+}
+
+// vim: ft=rust
@@ -4,37 +4,156 @@
 use std::default::Default;
 use std::rc::Rc;
 use simulator::read_uint;
+use formal::{Cnf,Lit};
 
-//pub enum Error
-//{
-//	UnknownElement,
-//	ParamCount {
-//		expected: usize,
-//		have: usize,
-//		reason: &'static str
-//		},
-//	ParamRange {
-//		param_index: usize,
-//		min: usize,
-//		max: usize
-//		},
-//	InputCount {
-//		min: usize,
-//		max: usize
-//		},
-//}
+/// Reasons an element can't be created
+#[derive(Debug,PartialEq)]
+pub enum ElementError
+{
+	UnknownElement(String),
+	ParamCount {
+		min: usize,
+		max: usize,
+		have: usize,
+		},
+	ParamRange {
+		param_index: usize,
+		name: &'static str,
+		min: u64,
+		max: u64,
+		have: u64,
+		},
+	InputCount {
+		min: usize,
+		max: usize,
+		have: usize,
+		},
+	/// Configuration that isn't a simple count/range error (e.g. missing ROM data)
+	Invalid(String),
+}
+impl ::std::fmt::Display for ElementError
+{
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+	{
+		fn range(f: &mut ::std::fmt::Formatter, min: u64, max: u64) -> ::std::fmt::Result {
+			if min == max { write!(f, "{}", min) }
+			else if max == u64::MAX { write!(f, "at least {}", min) }
+			else if min == 0 { write!(f, "at most {}", max) }
+			else { write!(f, "{}--{}", min, max) }
+		}
+		fn count(max: usize) -> u64 {
+			if max == usize::MAX { u64::MAX } else { max as u64 }
+		}
+		match *self
+		{
+		ElementError::UnknownElement(ref name) => write!(f, "Unknown element '{}'", name),
+		ElementError::ParamCount { min, max, have } => {
+			write!(f, "Invalid parameter count, expected ")?;
+			range(f, min as u64, count(max))?;
+			write!(f, ", got {}", have)
+			},
+		ElementError::ParamRange { param_index, name, min, max, have } => {
+			write!(f, "Parameter {} ({}) out of range, must be ", param_index, name)?;
+			range(f, min, max)?;
+			write!(f, " (got {})", have)
+			},
+		ElementError::InputCount { min, max, have } => {
+			write!(f, "Incorrect input count, expected ")?;
+			range(f, min as u64, count(max))?;
+			write!(f, ", got {}", have)
+			},
+		ElementError::Invalid(ref msg) => f.write_str(msg),
+		}
+	}
+}
+
+/// Check that the parameter count is within `min`--`max`
+fn check_params(params: &[u64], min: usize, max: usize) -> Result<(),ElementError>
+{
+	if params.len() < min || params.len() > max {
+		return Err(ElementError::ParamCount { min, max, have: params.len() });
+	}
+	Ok( () )
+}
+/// Check that a parameter value is within `min`--`max` (inclusive)
+fn check_range(param_index: usize, name: &'static str, val: u64, min: u64, max: u64) -> Result<u64,ElementError>
+{
+	if val < min || val > max {
+		return Err(ElementError::ParamRange { param_index, name, min, max, have: val });
+	}
+	Ok(val)
+}
+/// Check the input count (use `usize::MAX` for no upper limit)
+fn check_inputs(n_inputs: usize, min: usize, max: usize) -> Result<(),ElementError>
+{
+	if n_inputs < min || n_inputs > max {
+		return Err(ElementError::InputCount { min, max, have: n_inputs });
+	}
+	Ok( () )
+}
+
+/// A named group of element inputs or outputs
+#[derive(Clone,Debug,PartialEq)]
+pub struct Pin
+{
+	pub name: String,
+	pub width: usize,
+}
+impl Pin
+{
+	pub fn new(name: &str, width: usize) -> Pin {
+		Pin { name: name.to_string(), width }
+	}
+}
+/// Short description of a pin list (e.g. "enable, select[2], in[4]")
+pub fn describe_pins(pins: &[Pin]) -> String
+{
+	pins.iter()
+		.map(|p| if p.width == 1 { p.name.clone() } else { format!("{}[{}]", p.name, p.width) })
+		.collect::<Vec<_>>()
+		.join(", ")
+}
 
 pub trait Element //: ::std::fmt::Display
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult where Self: Sized;
-	fn finalise(&mut self, unit: &::cct_mesh::Unit) {}
+	/// Called once the containing unit is complete (e.g. to look up ROM data)
+	fn finalise(&mut self, _unit: &::cct_mesh::Unit) -> Result<(),ElementError> { Ok( () ) }
 	fn name(&self) -> String;
 	fn get_outputs(&self, n_inputs: usize) -> usize;
 	fn dup(&self) -> Box<Element+'static>;
 	fn update(&mut self, outlines: &mut [bool], inlines: &[bool]);
+	/// Return to the power-on state
+	fn reset(&mut self) {}
+	/// Whether the element is held in reset (outputs low) while the `#reset` line is high
+	///
+	/// Registers opt in, memory contents and I/O aren't affected.
+	fn uses_reset_line(&self) -> bool { false }
+	
+	/// Input pins, in order (widths sum to `n_inputs`)
+	fn input_pins(&self, n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("in", n_inputs) ]
+	}
+	/// Output pins, in order (widths sum to `get_outputs`)
+	fn output_pins(&self, n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("out", self.get_outputs(n_inputs)) ]
+	}
+	
+	/// Number of distinct select values (zero if the element doesn't have a selector)
+	fn select_range(&self) -> usize { 0 }
+	/// Currently active select value (None if not selecting anything)
+	fn select_value(&self, _inlines: &[bool]) -> Option<usize> { None }
+	
+	/// Encode one tick of the element as CNF, returning the output literals (None if not supported)
+	///
+	/// `state` is private to this element instance, and starts empty.
+	fn encode(&self, _cnf: &mut Cnf, _state: &mut Vec<Lit>, _inlines: &[Lit]) -> Option<Vec<Lit>> { None }
+	
+	/// Word size and current contents, for elements that contain memory
+	fn memory(&self) -> Option<(usize, &[u64])> { None }
 }
 
-pub type NewEleResult = Result<Box<Element+'static>,String>;
+pub type NewEleResult = Result<Box<Element+'static>,ElementError>;
 
 fn write_uint(outlines: &mut [bool], base: usize, count: u8, val: u64)
 {
@@ -51,37 +170,92 @@ macro_rules! get_or{
 	($vec:expr, $idx:expr, $def:expr) => ({let _i=$idx; let _v=$vec; (if _i < _v.len(){_v[_i]}else{$def})}) 
 }
 
-pub fn create(name: &str, params: &[u64], n_inputs: usize) -> NewEleResult
+/// Constructor for a named element type
+pub type Constructor = Box<Fn(&[u64], usize) -> NewEleResult>;
+
+/// Maps element names (as used in `.cct` files) to their constructors
+///
+/// The default registry contains the built-in elements, extra elements can be added with `register`
+/// before the circuit is loaded. This is only used within the simulator (there's no library target), so
+/// new element types are added to the `Default` impl.
+pub struct ElementRegistry
+{
+	constructors: ::std::collections::HashMap<String,Constructor>,
+}
+impl ElementRegistry
 {
-	match name
+	/// An empty registry (see `Default` for one with the built-in elements)
+	pub fn empty() -> ElementRegistry {
+		ElementRegistry {
+			constructors: Default::default(),
+		}
+	}
+	
+	/// Add (or replace) an element type
+	pub fn register<F>(&mut self, name: &str, constructor: F)
+	where
+		F: Fn(&[u64], usize) -> NewEleResult + 'static
 	{
-	// Meta-gates
-	"DELAY" => ElementDELAY::new(params, n_inputs),
-	"PULSE" => ElementPULSE::new(params, n_inputs),
-	"HOLD"  => ElementHOLD::new(params, n_inputs),
-	"ENABLE" => ElementENABLE::new(params, n_inputs),
+		self.constructors.insert(name.to_string(), Box::new(constructor));
+	}
 	
-	// Builtin Units
-	"CLOCK" => ElementClock::new(params, n_inputs),
-	"JKFLIPFLOP" => ElementJkFlipFlop::new(params, n_inputs),
-	"LATCH" => ElementLATCH::new(params, n_inputs),
-	"MUX" => ElementMUX::new(params, n_inputs),
-	"DEMUX" => ElementDEMUX::new(params, n_inputs),
-	"SEQUENCER" => ElementSEQUENCER::new(params, n_inputs),
-	"MEMORY_DRAM" => ElementMEMORY_DRAM::new(params, n_inputs),
+	pub fn create(&self, name: &str, params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		match self.constructors.get(name)
+		{
+		Some(c) => c(params, n_inputs),
+		None => Err(ElementError::UnknownElement(name.to_string())),
+		}
+	}
+}
+impl Default for ElementRegistry
+{
+	fn default() -> ElementRegistry
+	{
+		let mut rv = ElementRegistry::empty();
+		// Meta-gates
+		rv.register("DELAY", ElementDELAY::new);
+		rv.register("PULSE", ElementPULSE::new);
+		rv.register("HOLD", ElementHOLD::new);
+		rv.register("ENABLE", ElementENABLE::new);
 
-	"ROM" => ElementROM::new(params, n_inputs),
-	
-	// Logic Gates
-	"AND" => ElementAND::new(params, n_inputs),
-	"OR"  => ElementOR::new(params, n_inputs),
-	"XOR" => ElementXOR::new(params, n_inputs),
-	"NAND" => ElementNAND::new(params, n_inputs),
-	"NOR" => ElementNOR::new(params, n_inputs),
-	"NXOR" => ElementNXOR::new(params, n_inputs),
-	"XNOR" => ElementNXOR::new(params, n_inputs),	// < same
-	"NOT" => ElementNOT::new(params, n_inputs),
-	_ => return Err("Unknown element".to_string())
+		// Builtin Units
+		rv.register("CLOCK", ElementClock::new);
+		rv.register("JKFLIPFLOP", ElementJkFlipFlop::new);
+		rv.register("LATCH", ElementLATCH::new);
+		rv.register("MUX", ElementMUX::new);
+		rv.register("DEMUX", ElementDEMUX::new);
+		rv.register("DECODER", ElementDECODER::new);
+		rv.register("ENCODER", ElementENCODER::new);
+		rv.register("PRIORITY", ElementPRIORITY::new);
+		rv.register("POPCOUNT", ElementPOPCOUNT::new);
+		rv.register("SEQUENCER", ElementSEQUENCER::new);
+		rv.register("COUNTER", ElementCOUNTER::new);
+		rv.register("SHIFTREG", ElementSHIFTREG::new);
+		rv.register("LFSR", ElementLFSR::new);
+		rv.register("MEMORY_DRAM", ElementMEMORY_DRAM::new);
+		rv.register("REGFILE", ElementREGFILE::new);
+		rv.register("RAM_DP", ElementRAM_DP::new);
+		rv.register("UART", ElementUART::new);
+
+		rv.register("ROM", ElementROM::new);
+
+		// Logic Gates
+		rv.register("AND", ElementAND::new);
+		rv.register("OR", ElementOR::new);
+		rv.register("XOR", ElementXOR::new);
+		rv.register("NAND", ElementNAND::new);
+		rv.register("NOR", ElementNOR::new);
+		rv.register("NXOR", ElementNXOR::new);
+		rv.register("XNOR", ElementNXOR::new);	// < same
+		rv.register("NOT", ElementNOT::new);
+
+		// Arithmetic
+		rv.register("ADD", ElementADD::new);
+		rv.register("SUB", ElementSUB::new);
+		rv.register("CMP", ElementCMP::new);
+		rv.register("MUL", ElementMUL::new);
+		rv
 	}
 }
 
@@ -99,7 +273,7 @@ impl Element for ElementDELAY
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		let count = get_or!(params, 0, 1u64) as usize - 1;
+		let count = check_range(0, "delay", get_or!(params, 0, 1u64), 1, u64::MAX)? as usize - 1;
 		Ok( Box::new(ElementDELAY {
 			count: count,
 			idx: 0,
@@ -143,6 +317,28 @@ impl Element for ElementDELAY
 			}
 		}
 	}
+	
+	fn encode(&self, cnf: &mut Cnf, state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		if self.count == 0 {
+			return Some( inlines.to_vec() );
+		}
+		// State is a FIFO of the last `count` input sets, oldest first
+		if state.is_empty() {
+			*state = ::from_elem(self.count * inlines.len(), cnf.lit_false());
+		}
+		let rv = state.drain(.. inlines.len()).collect();
+		state.extend_from_slice(inlines);
+		Some(rv)
+	}
+	
+	fn reset(&mut self) {
+		self.idx = 0;
+		for v in self.vals.iter_mut() {
+			*v = false;
+		}
+	}
+	fn uses_reset_line(&self) -> bool { true }
 }
 
 
@@ -151,9 +347,7 @@ impl Element for ElementENABLE
 {
 	fn new(_/*params*/: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		if n_inputs < 2 {
-			return Err(format!("Incorrect input count, expected at least two"));
-		}
+		check_inputs(n_inputs, 2, usize::MAX)?;
 		
 		return Ok( Box::new(ElementENABLE) as Box<Element> );
 	}
@@ -178,6 +372,15 @@ impl Element for ElementENABLE
 			}
 		}
 	}
+	
+	fn input_pins(&self, n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("in", n_inputs - 1) ]
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		Some( inlines[1..].iter().map(|&l| cnf.and(&[inlines[0], l])).collect() )
+	}
 }
 
 #[derive(Clone,Default)]
@@ -190,14 +393,9 @@ impl Element for ElementPULSE
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		let dir = match params.len() {
-			0 => false,
-			1 => params[0] != 0,
-			_ => return Err(format!("Too many parameter, expected only one")),
-			};
-		if n_inputs != 1 {
-			return Err(format!("Incorrect input count, expected one"));
-		}
+		check_params(params, 0, 1)?;
+		let dir = get_or!(params, 0, 0u64) != 0;
+		check_inputs(n_inputs, 1, 1)?;
 		
 		return Ok( Box::new(ElementPULSE { dir_is_falling: dir, last_value: false }) as Box<Element> );
 	}
@@ -224,6 +422,11 @@ impl Element for ElementPULSE
 		}
 		self.last_value = curval;
 	}
+	
+	fn reset(&mut self) {
+		self.last_value = false;
+	}
+	fn uses_reset_line(&self) -> bool { true }
 }
 
 #[derive(Clone)]
@@ -236,11 +439,8 @@ impl Element for ElementHOLD
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		let time = match params.len() {
-			0 => 1,
-			1 => params[0] as usize,
-			_ => return Err(format!("Too many parameters, expected only one")),
-			};
+		check_params(params, 0, 1)?;
+		let time = get_or!(params, 0, 1u64) as usize;
 		
 		return Ok( Box::new(ElementHOLD { hold_time: time, times: ::from_elem(n_inputs, 0) }) as Box<Element> );
 	}
@@ -268,6 +468,13 @@ impl Element for ElementHOLD
 			}
 		}
 	}
+	
+	fn reset(&mut self) {
+		for t in self.times.iter_mut() {
+			*t = 0;
+		}
+	}
+	fn uses_reset_line(&self) -> bool { true }
 }
 
 #[derive(Clone)]
@@ -285,8 +492,9 @@ impl Element for ElementClock
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		let period = get_or!(params, 0, 1u64) as usize - 1;
+		let period = check_range(0, "period", get_or!(params, 0, 1u64), 1, u64::MAX)? as usize - 1;
 		let duty = get_or!(params, 1, 1u64) as usize;
+		check_inputs(n_inputs, 1, 1)?;
 		Ok( Box::new(ElementClock {
 			period,
 			duty,
@@ -317,9 +525,18 @@ impl Element for ElementClock
 			}
 		}
 	}
+	
+	fn reset(&mut self) {
+		self.counter = 0;
+	}
+	fn uses_reset_line(&self) -> bool { true }
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1) ]
+	}
 }
 
-macro_rules! def_logic{ ($name:ident, $init:expr, $op:expr, $finish:expr) => (
+macro_rules! def_logic{ ($name:ident, $init:expr, $op:expr, $finish:expr, $enc:ident, $invert:expr) => (
 #[derive(Clone)]
 struct $name
 {
@@ -333,12 +550,8 @@ impl Element for $name
 		let bussize  = get_or!(params, 0, 1u64) as u8;
 		let buscount = get_or!(params, 1, 1u64) as u8;
 		let min_inputs = (bussize as usize) * (buscount as usize);
-		if n_inputs < min_inputs {
-			Err( format!("Too few inputs, need at least {}, got {}", min_inputs, n_inputs) )
-		}
-		else {
-			Ok( Box::new($name { bussize: bussize, buscount: buscount }) as Box<Element> )
-		}
+		check_inputs(n_inputs, min_inputs, usize::MAX)?;
+		Ok( Box::new($name { bussize: bussize, buscount: buscount }) as Box<Element> )
 	}
 	fn name(&self) -> String {
 		return format!("{}{{{},{}}}", stringify!($name), self.bussize, self.buscount);
@@ -375,15 +588,43 @@ impl Element for $name
 			outlines[i] |= val;
 		}
 	}
+	
+	fn input_pins(&self, n_inputs: usize) -> Vec<Pin> {
+		let fixed_lines = n_inputs - (self.bussize as usize)*(self.buscount as usize);
+		let mut rv = Vec::new();
+		if fixed_lines > 0 {
+			rv.push( Pin::new("in", fixed_lines) );
+		}
+		for i in 0 .. self.buscount as usize {
+			rv.push( Pin { name: format!("bus{}", i), width: self.bussize as usize } );
+		}
+		rv
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let fixed_lines = inlines.len() - (self.bussize as usize)*(self.buscount as usize);
+		let mut rv = Vec::with_capacity(self.bussize as usize);
+		for i in 0 .. self.bussize as usize
+		{
+			let mut lits = inlines[..fixed_lines].to_vec();
+			for j in 0 .. self.buscount as usize {
+				lits.push( inlines[fixed_lines + i + j * (self.bussize as usize)] );
+			}
+			let val = cnf.$enc(&lits);
+			rv.push( if $invert { !val } else { val } );
+		}
+		Some(rv)
+	}
 }
 ) }
 
-def_logic!{ ElementNXOR, false, |v:bool,i:bool| v^i, |v:bool| !v }
-def_logic!{ ElementNAND, true,  |v:bool,i:bool| v&i, |v:bool| !v }
-def_logic!{ ElementNOR,  false, |v:bool,i:bool| v|i, |v:bool| !v }
-def_logic!{ ElementXOR,  false, |v:bool,i:bool| v^i, |v| v }
-def_logic!{ ElementAND,  true,  |v:bool,i:bool| v&i, |v| v }
-def_logic!{ ElementOR,   false, |v:bool,i:bool| v|i, |v| v }
+def_logic!{ ElementNXOR, false, |v:bool,i:bool| v^i, |v:bool| !v, xor_all, true }
+def_logic!{ ElementNAND, true,  |v:bool,i:bool| v&i, |v:bool| !v, and, true }
+def_logic!{ ElementNOR,  false, |v:bool,i:bool| v|i, |v:bool| !v, or, true }
+def_logic!{ ElementXOR,  false, |v:bool,i:bool| v^i, |v| v, xor_all, false }
+def_logic!{ ElementAND,  true,  |v:bool,i:bool| v&i, |v| v, and, false }
+def_logic!{ ElementOR,   false, |v:bool,i:bool| v|i, |v| v, or, false }
 
 struct ElementNOT;
 impl Element for ElementNOT
@@ -410,6 +651,282 @@ impl Element for ElementNOT
 			*line = !inlines[i];
 		}
 	}
+	
+	fn encode(&self, _cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		Some( inlines.iter().map(|&l| !l).collect() )
+	}
+}
+
+//
+// Arithmetic
+//
+/// Check the `{width}` parameter of an arithmetic element, and that there are two operands plus `n_extra` inputs
+fn arith_width(params: &[u64], n_inputs: usize, n_extra: usize) -> Result<u8,ElementError>
+{
+	check_params(params, 1, 1)?;
+	let width = check_range(0, "width", params[0], 1, 64)?;
+	let exp_inputs = 2 * width as usize + n_extra;
+	check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+	Ok(width as u8)
+}
+/// Sign-extend a `width`-bit value
+fn sign_extend(val: u64, width: u8) -> i64
+{
+	((val << (64 - width)) as i64) >> (64 - width)
+}
+/// Add two words (with carry), returning the sum, carry out and signed overflow
+fn add_words(a: u64, b: u64, carry_in: bool, width: u8) -> (u64, bool, bool)
+{
+	let sum = a as u128 + b as u128 + carry_in as u128;
+	let mask = !0u64 >> (64 - width);
+	let res = sum as u64 & mask;
+	let sign = |v: u64| (v >> (width - 1)) & 1 != 0;
+	let overflow = sign(a) == sign(b) && sign(res) != sign(a);
+	(res, (sum >> width) & 1 != 0, overflow)
+}
+
+/// Adder
+///
+/// Inputs: A, B, carry in
+/// Outputs: sum, carry out, overflow (signed)
+#[derive(Clone)]
+struct ElementADD
+{
+	width: u8,
+}
+impl Element for ElementADD
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		let width = arith_width(params, n_inputs, 1)?;
+		Ok( Box::new(ElementADD { width }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementADD{{{}}}", self.width)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.width as usize + 2
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let w = self.width as usize;
+		let a = read_uint(inlines, 0, self.width);
+		let b = read_uint(inlines, w, self.width);
+		let (sum, carry, overflow) = add_words(a, b, inlines[2*w], self.width);
+		write_uint(outlines, 0, self.width, sum);
+		outlines[w] = carry;
+		outlines[w+1] = overflow;
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let w = self.width as usize;
+		vec![ Pin::new("a", w), Pin::new("b", w), Pin::new("cin", 1) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("sum", self.width as usize), Pin::new("cout", 1), Pin::new("ovf", 1) ]
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let w = self.width as usize;
+		let (mut rv, carry, overflow) = cnf.add(&inlines[..w], &inlines[w..2*w], inlines[2*w]);
+		rv.push(carry);
+		rv.push(overflow);
+		Some(rv)
+	}
+}
+
+/// Subtractor (A - B - borrow)
+///
+/// Inputs: A, B, borrow in
+/// Outputs: difference, borrow out, overflow (signed)
+#[derive(Clone)]
+struct ElementSUB
+{
+	width: u8,
+}
+impl Element for ElementSUB
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		let width = arith_width(params, n_inputs, 1)?;
+		Ok( Box::new(ElementSUB { width }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementSUB{{{}}}", self.width)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.width as usize + 2
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let w = self.width as usize;
+		let mask = !0u64 >> (64 - self.width);
+		let a = read_uint(inlines, 0, self.width);
+		let b = read_uint(inlines, w, self.width);
+		// A + ~B + ~borrow
+		let (diff, carry, overflow) = add_words(a, !b & mask, !inlines[2*w], self.width);
+		write_uint(outlines, 0, self.width, diff);
+		outlines[w] = !carry;
+		outlines[w+1] = overflow;
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let w = self.width as usize;
+		vec![ Pin::new("a", w), Pin::new("b", w), Pin::new("bin", 1) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("diff", self.width as usize), Pin::new("bout", 1), Pin::new("ovf", 1) ]
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let w = self.width as usize;
+		let not_b: Vec<Lit> = inlines[w..2*w].iter().map(|&l| !l).collect();
+		let (mut rv, carry, overflow) = cnf.add(&inlines[..w], &not_b, !inlines[2*w]);
+		rv.push(!carry);
+		rv.push(overflow);
+		Some(rv)
+	}
+}
+
+/// Comparator
+///
+/// Inputs: A, B
+/// Outputs: A==B, A<B (unsigned), A>B (unsigned), A<B (signed), A>B (signed)
+#[derive(Clone)]
+struct ElementCMP
+{
+	width: u8,
+}
+impl Element for ElementCMP
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		let width = arith_width(params, n_inputs, 0)?;
+		Ok( Box::new(ElementCMP { width }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementCMP{{{}}}", self.width)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		5
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let a = read_uint(inlines, 0, self.width);
+		let b = read_uint(inlines, self.width as usize, self.width);
+		let (sa, sb) = (sign_extend(a, self.width), sign_extend(b, self.width));
+		outlines[0] = a == b;
+		outlines[1] = a < b;
+		outlines[2] = a > b;
+		outlines[3] = sa < sb;
+		outlines[4] = sa > sb;
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let w = self.width as usize;
+		vec![ Pin::new("a", w), Pin::new("b", w) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		["eq", "ult", "ugt", "slt", "sgt"].iter().map(|n| Pin::new(n, 1)).collect()
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let w = self.width as usize;
+		let (a, b) = (&inlines[..w], &inlines[w..]);
+		let not_b: Vec<Lit> = b.iter().map(|&l| !l).collect();
+		let not_a: Vec<Lit> = a.iter().map(|&l| !l).collect();
+		let true_ = cnf.lit_true();
+		// A - B and B - A, borrow (no carry) means less-than
+		let (diff, carry_ab, ovf_ab) = cnf.add(a, &not_b, true_);
+		let (_, carry_ba, _) = cnf.add(b, &not_a, true_);
+		let diffs: Vec<Lit> = a.iter().zip(b.iter()).map(|(&x,&y)| cnf.xor(x, y)).collect();
+		let eq = !cnf.or(&diffs);
+		let slt = cnf.xor(diff[w-1], ovf_ab);
+		let sgt = cnf.and(&[!slt, !eq]);
+		Some( vec![eq, !carry_ab, !carry_ba, slt, sgt] )
+	}
+}
+
+/// Unsigned multiplier
+///
+/// Inputs: A, B
+/// Outputs: product (double width)
+#[derive(Clone)]
+struct ElementMUL
+{
+	width: u8,
+}
+impl Element for ElementMUL
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		let width = arith_width(params, n_inputs, 0)?;
+		Ok( Box::new(ElementMUL { width }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementMUL{{{}}}", self.width)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		2 * self.width as usize
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let a = read_uint(inlines, 0, self.width);
+		let b = read_uint(inlines, self.width as usize, self.width);
+		let prod = a as u128 * b as u128;
+		write_uint(outlines, 0, self.width, prod as u64);
+		write_uint(outlines, self.width as usize, self.width, (prod >> self.width) as u64);
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let w = self.width as usize;
+		vec![ Pin::new("a", w), Pin::new("b", w) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("product", 2 * self.width as usize) ]
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		// Shift-and-add array
+		let w = self.width as usize;
+		let (a, b) = (&inlines[..w], &inlines[w..]);
+		let zero = cnf.lit_false();
+		let mut acc: Vec<Lit> = ::from_elem(2*w, zero);
+		for (i,&bit) in b.iter().enumerate()
+		{
+			let mut partial: Vec<Lit> = ::from_elem(2*w, zero);
+			for (j,&x) in a.iter().enumerate() {
+				partial[i+j] = cnf.and(&[x, bit]);
+			}
+			acc = cnf.add(&acc, &partial, zero).0;
+		}
+		Some(acc)
+	}
 }
 
 //
@@ -425,13 +942,8 @@ impl Element for ElementLATCH
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		let size = get_or!(params, 0, 1u64) as usize;
-		if size == 0 {
-			return Err( format!("Size invalid, must be non-zero") );
-		}
-		if n_inputs != 2 + size {
-			return Err( format!("Invalid input count, expected {}, got {}", n_inputs, 2+size) );
-		}
+		let size = check_range(0, "size", get_or!(params, 0, 1u64), 1, u64::MAX)? as usize;
+		check_inputs(n_inputs, 2 + size, 2 + size)?;
 		Ok( Box::new(ElementLATCH { vals: ::from_elem(size, false), ..Default::default() }) as Box<Element> )
 	}
 	fn name(&self) -> String {
@@ -475,6 +987,20 @@ impl Element for ElementLATCH
 			}
 		}
 	}
+	
+	fn reset(&mut self) {
+		for v in self.vals.iter_mut() {
+			*v = false;
+		}
+	}
+	fn uses_reset_line(&self) -> bool { true }
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("reset", 1), Pin::new("set", self.vals.len()) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("valid", 1), Pin::new("value", self.vals.len()) ]
+	}
 }
 
 #[derive(Clone,Default)]
@@ -487,9 +1013,7 @@ impl Element for ElementJkFlipFlop
 {
 	fn new(_params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		if n_inputs != 3 {
-			return Err("".into());
-		}
+		check_inputs(n_inputs, 3, 3)?;
 		Ok(Box::new(Self::default()))
 	}
 	fn name(&self) -> String {
@@ -531,6 +1055,18 @@ impl Element for ElementJkFlipFlop
 		outlines[0] = self.state;
 		outlines[1] = !self.state;
 	}
+	
+	fn reset(&mut self) {
+		*self = Self::default();
+	}
+	fn uses_reset_line(&self) -> bool { true }
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("clk", 1), Pin::new("j", 1), Pin::new("k", 1) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("q", 1), Pin::new("q_n", 1) ]
+	}
 }
 
 #[derive(Clone)]
@@ -543,21 +1079,12 @@ impl Element for ElementMUX
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		if params.len() > 2 { return Err(format!("Too many parameters, expected at most 2")); }
-		let bits    = if params.len() >= 1 { params[0] as u8 } else { 1 };
-		let bussize = if params.len() >= 2 { params[1] as u8 } else { 1 };
-
-		if bits == 0 || bits > 10 {
-			return Err(format!("Bit count sanity check failure, must be 1--10 inclusive (got {})", bits));
-		}
-		if bussize == 0 {
-			return Err(format!("Bus size sanity check failure, must >0 (got {})", bussize));
-		}
+		check_params(params, 0, 2)?;
+		let bits    = check_range(0, "bits", get_or!(params, 0, 1u64), 1, 10)? as u8;
+		let bussize = check_range(1, "bus size", get_or!(params, 1, 1u64), 1, 255)? as u8;
 		
 		let exp_inputs: usize =  1 + bits as usize + (1 << bits as usize)*(bussize as usize);
-		if n_inputs != exp_inputs {
-			return Err(format!("Incorrect input count, expected {}, got {}", exp_inputs, n_inputs));
-		}
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
 		
 		Ok( Box::new(ElementMUX{bits: bits, bussize: bussize}) as Box<Element> )
 	}
@@ -585,6 +1112,39 @@ impl Element for ElementMUX
 			}
 		}
 	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let mut rv = vec![ Pin::new("enable", 1), Pin::new("select", self.bits as usize) ];
+		for i in 0 .. 1 << self.bits as usize {
+			rv.push( Pin { name: format!("in{}", i), width: self.bussize as usize } );
+		}
+		rv
+	}
+	
+	fn select_range(&self) -> usize {
+		1 << self.bits as usize
+	}
+	fn select_value(&self, inlines: &[bool]) -> Option<usize> {
+		if inlines[0] { Some(read_uint(inlines, 1, self.bits) as usize) } else { None }
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let bits = self.bits as usize;
+		let bussize = self.bussize as usize;
+		let selected: Vec<Lit> = (0 .. 1 << bits)
+			.map(|idx| { let eq = cnf.equals_const(&inlines[1 .. 1+bits], idx as u64); cnf.and(&[inlines[0], eq]) })
+			.collect();
+		let mut rv = Vec::with_capacity(bussize);
+		for i in 0 .. bussize
+		{
+			let terms: Vec<Lit> = selected.iter().enumerate()
+				.map(|(idx,&sel)| cnf.and(&[sel, inlines[1 + bits + idx*bussize + i]]))
+				.collect();
+			rv.push( cnf.or(&terms) );
+		}
+		Some(rv)
+	}
 }
 
 #[derive(Clone)]
@@ -596,20 +1156,11 @@ impl Element for ElementDEMUX
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		let (bits, bussize) = match params.len()
-				{
-				0 => return Err(format!("Not enough parameters, need at least 1")),
-				1 => (params[0] as u8, 1),
-				2 => (params[0] as u8, params[1] as u8),
-				_ => return Err(format!("Too many parameters, at most 2")),
-				};
-		if bits == 0 || bits > 10 {
-			return Err(format!("Bit count sanity check failure, must be 1--10 inclusive (got {})", bits));
-		}
-		let exp_inputs = 1 + bits + bussize;
-		if n_inputs < exp_inputs as usize {
-			return Err(format!("Incorrect input count, expected {}, got {}", exp_inputs, n_inputs));
-		}
+		check_params(params, 1, 2)?;
+		let bits = check_range(0, "bits", params[0], 1, 10)? as u8;
+		let bussize = get_or!(params, 1, 1u64) as u8;
+		let exp_inputs = 1 + bits as usize + bussize as usize;
+		check_inputs(n_inputs, exp_inputs, usize::MAX)?;
 		
 		Ok( Box::new(ElementDEMUX{bits: bits}) as Box<Element> )
 	}
@@ -639,26 +1190,315 @@ impl Element for ElementDEMUX
 			}
 		}
 	}
-}
-
-#[derive(Clone)]
-struct ElementSEQUENCER
-{
-	count: u16,
-	position: u16,
-}
-impl Element for ElementSEQUENCER
+	
+	fn input_pins(&self, n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("select", self.bits as usize), Pin::new("in", n_inputs - 1 - self.bits as usize) ]
+	}
+	fn output_pins(&self, n_inputs: usize) -> Vec<Pin> {
+		let bussize = n_inputs - 1 - self.bits as usize;
+		(0 .. 1 << self.bits as usize).map(|i| Pin { name: format!("out{}", i), width: bussize }).collect()
+	}
+	
+	fn select_range(&self) -> usize {
+		1 << self.bits as usize
+	}
+	fn select_value(&self, inlines: &[bool]) -> Option<usize> {
+		if inlines[0] { Some(read_uint(inlines, 1, self.bits) as usize) } else { None }
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let bits = self.bits as usize;
+		let ofs = 1 + bits;
+		let bussize = inlines.len() - ofs;
+		let mut rv = Vec::with_capacity(bussize << bits);
+		for idx in 0 .. 1 << bits
+		{
+			let eq = cnf.equals_const(&inlines[1 .. ofs], idx as u64);
+			let sel = cnf.and(&[inlines[0], eq]);
+			for i in 0 .. bussize {
+				rv.push( cnf.and(&[sel, inlines[ofs+i]]) );
+			}
+		}
+		Some(rv)
+	}
+}
+
+/// Binary to one-hot decoder
+///
+/// Inputs: enable, select
+/// Outputs: one line per select value
+#[derive(Clone)]
+struct ElementDECODER
+{
+	bits: u8
+}
+impl Element for ElementDECODER
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		if params.len() != 1 {
-			return Err(format!("Invalid parameter count, expected 1, got {}", params.len()));
+		check_params(params, 1, 1)?;
+		let bits = check_range(0, "bits", params[0], 1, 10)?;
+		let exp_inputs = 1 + bits as usize;
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+		Ok( Box::new(ElementDECODER { bits: bits as u8 }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementDECODER{{{}}}", self.bits)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		1 << self.bits as usize
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		if inlines[0] {
+			outlines[read_uint(inlines, 1, self.bits) as usize] = true;
 		}
-		let count = params[0] as u16;
-		let exp_inputs = 3;
-		if n_inputs != exp_inputs {
-			return Err(format!("Incorrect input count, expected {}, got {}", exp_inputs, n_inputs));
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("select", self.bits as usize) ]
+	}
+	
+	fn select_range(&self) -> usize {
+		1 << self.bits as usize
+	}
+	fn select_value(&self, inlines: &[bool]) -> Option<usize> {
+		if inlines[0] { Some(read_uint(inlines, 1, self.bits) as usize) } else { None }
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		Some( (0 .. 1 << self.bits as usize).map(|idx| {
+			let eq = cnf.equals_const(&inlines[1..], idx as u64);
+			cnf.and(&[inlines[0], eq])
+			}).collect() )
+	}
+}
+
+/// One-hot to binary encoder
+///
+/// Inputs: enable, one line per value
+/// Outputs: value, valid (any line set)
+///
+/// If several lines are set, the output is the OR of their values.
+#[derive(Clone)]
+struct ElementENCODER
+{
+	bits: u8
+}
+impl Element for ElementENCODER
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		check_params(params, 1, 1)?;
+		let bits = check_range(0, "bits", params[0], 1, 10)?;
+		let exp_inputs = 1 + (1 << bits as usize);
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+		Ok( Box::new(ElementENCODER { bits: bits as u8 }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementENCODER{{{}}}", self.bits)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.bits as usize + 1
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		if inlines[0]
+		{
+			for (idx,_) in inlines[1..].iter().enumerate().filter(|&(_,&v)| v)
+			{
+				write_uint(outlines, 0, self.bits, idx as u64);
+				outlines[self.bits as usize] = true;
+			}
 		}
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("in", 1 << self.bits as usize) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("value", self.bits as usize), Pin::new("valid", 1) ]
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let lines = &inlines[1..];
+		let mut rv: Vec<Lit> = (0 .. self.bits as usize).map(|b| {
+			let set: Vec<Lit> = lines.iter().enumerate().filter(|&(idx,_)| (idx >> b) & 1 != 0).map(|(_,&l)| l).collect();
+			let any = cnf.or(&set);
+			cnf.and(&[inlines[0], any])
+			}).collect();
+		let any = cnf.or(lines);
+		rv.push( cnf.and(&[inlines[0], any]) );
+		Some(rv)
+	}
+}
+
+/// Priority encoder
+///
+/// Params: bits, direction (0 = lowest line wins, 1 = highest line wins)
+/// Inputs: enable, one line per value
+/// Outputs: value of the winning line, valid (any line set)
+#[derive(Clone)]
+struct ElementPRIORITY
+{
+	bits: u8,
+	highest_first: bool,
+}
+impl ElementPRIORITY
+{
+	/// Line indexes in priority order
+	fn order(&self) -> Box<Iterator<Item=usize>> {
+		let n = 1 << self.bits as usize;
+		if self.highest_first { Box::new((0 .. n).rev()) } else { Box::new(0 .. n) }
+	}
+}
+impl Element for ElementPRIORITY
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		check_params(params, 1, 2)?;
+		let bits = check_range(0, "bits", params[0], 1, 10)?;
+		let dir = check_range(1, "direction", get_or!(params, 1, 0u64), 0, 1)?;
+		let exp_inputs = 1 + (1 << bits as usize);
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+		Ok( Box::new(ElementPRIORITY { bits: bits as u8, highest_first: dir == 1 }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementPRIORITY{{{},{}}}", self.bits, self.highest_first as u8)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.bits as usize + 1
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		if inlines[0]
+		{
+			if let Some(idx) = self.order().find(|&i| inlines[1+i])
+			{
+				write_uint(outlines, 0, self.bits, idx as u64);
+				outlines[self.bits as usize] = true;
+			}
+		}
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("request", 1 << self.bits as usize) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("value", self.bits as usize), Pin::new("valid", 1) ]
+	}
+	
+	fn select_range(&self) -> usize {
+		1 << self.bits as usize
+	}
+	fn select_value(&self, inlines: &[bool]) -> Option<usize> {
+		if inlines[0] { self.order().find(|&i| inlines[1+i]) } else { None }
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		// A line is granted if it is set and no higher-priority line is
+		let mut grants: Vec<Lit> = ::from_elem(1 << self.bits as usize, cnf.lit_false());
+		let mut blocked = !inlines[0];
+		for idx in self.order()
+		{
+			grants[idx] = cnf.and(&[!blocked, inlines[1+idx]]);
+			blocked = cnf.or(&[blocked, inlines[1+idx]]);
+		}
+		let mut rv: Vec<Lit> = (0 .. self.bits as usize).map(|b| {
+			let set: Vec<Lit> = grants.iter().enumerate().filter(|&(idx,_)| (idx >> b) & 1 != 0).map(|(_,&l)| l).collect();
+			cnf.or(&set)
+			}).collect();
+		rv.push( cnf.or(&grants) );
+		Some(rv)
+	}
+}
+
+/// Population count (number of set inputs)
+#[derive(Clone)]
+struct ElementPOPCOUNT
+{
+	width: u8,
+}
+impl ElementPOPCOUNT
+{
+	fn out_bits(&self) -> usize {
+		64 - (self.width as u64).leading_zeros() as usize
+	}
+}
+impl Element for ElementPOPCOUNT
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		check_params(params, 1, 1)?;
+		let width = check_range(0, "width", params[0], 1, 64)?;
+		check_inputs(n_inputs, width as usize, width as usize)?;
+		Ok( Box::new(ElementPOPCOUNT { width: width as u8 }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementPOPCOUNT{{{}}}", self.width)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.out_bits()
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let count = inlines.iter().filter(|&&v| v).count();
+		write_uint(outlines, 0, self.out_bits() as u8, count as u64);
+	}
+	
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("count", self.out_bits()) ]
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		let zero = cnf.lit_false();
+		let mut acc: Vec<Lit> = ::from_elem(self.out_bits(), zero);
+		let no_bits: Vec<Lit> = acc.clone();
+		for &l in inlines {
+			acc = cnf.add(&acc, &no_bits, l).0;
+		}
+		Some(acc)
+	}
+}
+
+#[derive(Clone)]
+struct ElementSEQUENCER
+{
+	count: u16,
+	position: u16,
+}
+impl Element for ElementSEQUENCER
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		check_params(params, 1, 1)?;
+		let count = check_range(0, "count", params[0], 1, u16::MAX as u64)? as u16;
+		check_inputs(n_inputs, 3, 3)?;
 		
 		return Ok( Box::new(ElementSEQUENCER { count: count, position: 0 }) as Box<Element> );
 	}
@@ -697,8 +1537,241 @@ impl Element for ElementSEQUENCER
 			outlines[self.position as usize] = true;
 		}
 	}
+	
+	fn reset(&mut self) {
+		self.position = 0;
+	}
+	fn uses_reset_line(&self) -> bool { true }
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("reset", 1), Pin::new("next", 1) ]
+	}
+}
+
+/// Check a `{width}` parameter for the register elements
+fn reg_width(params: &[u64], min_params: usize, max_params: usize) -> Result<u8,ElementError>
+{
+	check_params(params, min_params, max_params)?;
+	Ok( check_range(0, "width", params[0], 1, 64)? as u8 )
+}
+
+/// Up/down counter
+///
+/// Inputs: enable, reset, load, down, load value
+/// Outputs: count, terminal count
+///
+/// The count is always output, and only changes while enabled. Terminal count is high when enabled and
+/// the count is at its last value (all ones counting up, zero counting down), for cascading counters.
+#[derive(Clone)]
+struct ElementCOUNTER
+{
+	width: u8,
+	value: u64,
+}
+impl Element for ElementCOUNTER
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		let width = reg_width(params, 1, 1)?;
+		let exp_inputs = 4 + width as usize;
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+		Ok( Box::new(ElementCOUNTER { width, value: 0 }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementCOUNTER{{{}}}", self.width)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.width as usize + 1
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let mask = !0u64 >> (64 - self.width);
+		let enable = inlines[0];
+		let reset  = inlines[1];
+		let load   = inlines[2];
+		let down   = inlines[3];
+		
+		if enable
+		{
+			if reset {
+				self.value = 0;
+			}
+			else if load {
+				self.value = read_uint(inlines, 4, self.width);
+			}
+			else if down {
+				self.value = self.value.wrapping_sub(1) & mask;
+			}
+			else {
+				self.value = self.value.wrapping_add(1) & mask;
+			}
+		}
+		write_uint(outlines, 0, self.width, self.value);
+		outlines[self.width as usize] = enable && self.value == if down { 0 } else { mask };
+	}
+	
+	fn reset(&mut self) {
+		self.value = 0;
+	}
+	fn uses_reset_line(&self) -> bool { true }
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("reset", 1), Pin::new("load", 1), Pin::new("down", 1), Pin::new("value", self.width as usize) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("count", self.width as usize), Pin::new("tc", 1) ]
+	}
+}
+
+/// Shift register (shifts towards the MSB)
+///
+/// Inputs: enable, reset, load, serial in, parallel in
+/// Outputs: parallel out, serial out (the MSB)
+///
+/// The contents are always output, and only change while enabled.
+#[derive(Clone)]
+struct ElementSHIFTREG
+{
+	width: u8,
+	value: u64,
+}
+impl Element for ElementSHIFTREG
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		let width = reg_width(params, 1, 1)?;
+		let exp_inputs = 4 + width as usize;
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+		Ok( Box::new(ElementSHIFTREG { width, value: 0 }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementSHIFTREG{{{}}}", self.width)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.width as usize + 1
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let mask = !0u64 >> (64 - self.width);
+		let enable = inlines[0];
+		let reset  = inlines[1];
+		let load   = inlines[2];
+		let serial = inlines[3];
+		
+		if enable
+		{
+			if reset {
+				self.value = 0;
+			}
+			else if load {
+				self.value = read_uint(inlines, 4, self.width);
+			}
+			else {
+				self.value = ((self.value << 1) | serial as u64) & mask;
+			}
+		}
+		write_uint(outlines, 0, self.width, self.value);
+		outlines[self.width as usize] = (self.value >> (self.width - 1)) & 1 != 0;
+	}
+	
+	fn reset(&mut self) {
+		self.value = 0;
+	}
+	fn uses_reset_line(&self) -> bool { true }
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("reset", 1), Pin::new("load", 1), Pin::new("serial", 1), Pin::new("parallel", self.width as usize) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("value", self.width as usize), Pin::new("serial_out", 1) ]
+	}
+}
+
+/// Fibonacci linear-feedback shift register
+///
+/// Params: width, tap mask (bit N set taps bit N), optional seed (defaults to 1)
+/// Inputs: enable, reset
+/// Outputs: state, serial out (the MSB)
+///
+/// Each enabled tick shifts the state towards the MSB, feeding in the parity of the tapped bits.
+/// Reset reloads the seed.
+#[derive(Clone)]
+struct ElementLFSR
+{
+	width: u8,
+	taps: u64,
+	seed: u64,
+	value: u64,
+}
+impl Element for ElementLFSR
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		let width = reg_width(params, 2, 3)?;
+		let mask = !0u64 >> (64 - width);
+		let taps = check_range(1, "taps", params[1], 1, mask)?;
+		let seed = check_range(2, "seed", get_or!(params, 2, 1u64), 1, mask)?;
+		check_inputs(n_inputs, 2, 2)?;
+		Ok( Box::new(ElementLFSR { width, taps, seed, value: seed }) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementLFSR{{{},{:#x}}}", self.width, self.taps)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.width as usize + 1
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let mask = !0u64 >> (64 - self.width);
+		let enable = inlines[0];
+		let reset  = inlines[1];
+		
+		if enable
+		{
+			if reset {
+				self.value = self.seed;
+			}
+			else {
+				let feedback = (self.value & self.taps).count_ones() & 1;
+				self.value = ((self.value << 1) | feedback as u64) & mask;
+			}
+		}
+		write_uint(outlines, 0, self.width, self.value);
+		outlines[self.width as usize] = (self.value >> (self.width - 1)) & 1 != 0;
+	}
+	
+	fn reset(&mut self) {
+		self.value = self.seed;
+	}
+	fn uses_reset_line(&self) -> bool { true }
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("reset", 1) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("state", self.width as usize), Pin::new("serial_out", 1) ]
+	}
 }
 
+/// Word-addressed memory
+///
+/// Inputs: enable, address (LSB first), write, write mask, write data
+/// Outputs: data valid, read data
 #[allow(non_camel_case_types)]
 #[derive(Default)]
 #[derive(Clone)]
@@ -706,39 +1779,41 @@ struct ElementMEMORY_DRAM
 {
 	wordsize: u8,
 	addrbits: u8,
-	data: Vec<u32>,
+	/// Index of the unit data block used to initialise the memory
+	init_index: Option<usize>,
+	init: Option<Rc<Vec<u64>>>,
+	/// Memory contents (allocated on first update)
+	data: Vec<u64>,
 }
 impl Element for ElementMEMORY_DRAM
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		if params.len() != 2 {
-			return Err(format!("Invalid parameter count, expected 2, got {}", params.len()));
-		}
-		let wordsize = params[0] as u8;
-		if wordsize == 0 || wordsize > 64 {
-			return Err(format!("Word size sanity check failure, must be 0--64 (got {})", wordsize));
-		}
-		let addrbits = params[1] as u8;
-		if addrbits == 0 || addrbits > 20 {	// allows up to 8MiB
-			return Err(format!("Address bit sanity check failure, must be 1--14 (got {})", addrbits));
-		}
-		let exp_inputs = 1 + addrbits + 1 + 2*wordsize;
-		if n_inputs != exp_inputs as usize {
-			return Err(format!("Incorrect input count, expected {}, got {}", exp_inputs, n_inputs));
-		}
+		check_params(params, 2, 3)?;
+		let wordsize = check_range(0, "word size", params[0], 1, 64)?;
+		let addrbits = check_range(1, "address bits", params[1], 1, 24)?;	// allows up to 16M words
+		let exp_inputs = (1 + addrbits + 1 + 2*wordsize) as usize;
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
 		
 		Ok( Box::new(ElementMEMORY_DRAM {
-			wordsize: wordsize,
-			addrbits: addrbits,
+			wordsize: wordsize as u8,
+			addrbits: addrbits as u8,
+			init_index: params.get(2).map(|&v| v as usize),
 			..Default::default()
 			}) as Box<Element> )
 	}
+	fn finalise(&mut self, unit: &::cct_mesh::Unit) -> Result<(),ElementError> {
+		if let Some(idx) = self.init_index {
+			self.init = Some( unit.get_rom(idx).ok_or_else(|| ElementError::Invalid(format!("Memory data block {} not defined", idx)))? );
+			unit.check_rom(idx, 1 << self.addrbits, self.wordsize as usize).map_err(ElementError::Invalid)?;
+		}
+		Ok( () )
+	}
 	fn name(&self) -> String {
 		format!("ElementMEMORY_DRAM{{{},{}}}", self.wordsize, self.addrbits)
 	}
 	fn get_outputs(&self, _n_inputs: usize) -> usize {
-		1 + 1 << self.wordsize as usize
+		1 + self.wordsize as usize
 	}
 	
 	fn dup(&self) -> Box<Element+'static> {
@@ -747,55 +1822,440 @@ impl Element for ElementMEMORY_DRAM
 
 	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
 	{
-		if self.data.len() == 0 {
-			self.data = ::from_elem( 1 << self.addrbits * self.wordsize / 32, 0 );
+		if self.data.is_empty() {
+			self.data = ::from_elem(1 << self.addrbits, 0);
+			if let Some(ref init) = self.init {
+				for (d,v) in self.data.iter_mut().zip( init.iter() ) {
+					*d = *v;
+				}
+			}
 		}
+		let wordmask = !0u64 >> (64 - self.wordsize);
 		let enable = inlines[0];
 		let wordnum = read_uint(inlines, 1, self.addrbits) as usize;
 		let write = inlines[1 + self.addrbits as usize];
 		let writemask = read_uint(inlines, (1+self.addrbits+1) as usize, self.wordsize);
 		let writeval = read_uint(inlines, (1+self.addrbits+1+self.wordsize) as usize, self.wordsize);
 		
-		assert!( self.wordsize == 32 );
 		if enable
 		{
+			let val = &mut self.data[wordnum];
 			if write
 			{
-				let val = &mut self.data[wordnum];
-				*val &= !writemask as u32;
-				*val |= writeval as u32;
+				*val = (*val & !writemask) | (writeval & writemask);
 			}
-			write_uint(outlines, 1, self.wordsize, self.data[wordnum] as u64);
+			outlines[0] = true;
+			write_uint(outlines, 1, self.wordsize, *val & wordmask);
+		}
+	}
+	
+	fn reset(&mut self) {
+		// Re-initialised on the next update
+		self.data.clear();
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let w = self.wordsize as usize;
+		vec![ Pin::new("enable", 1), Pin::new("addr", self.addrbits as usize), Pin::new("write", 1), Pin::new("write_mask", w), Pin::new("write_data", w) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("valid", 1), Pin::new("data", self.wordsize as usize) ]
+	}
+	
+	fn memory(&self) -> Option<(usize, &[u64])> {
+		if !self.data.is_empty() {
+			Some( (self.wordsize as usize, &self.data[..]) )
+		}
+		else {
+			Some( (self.wordsize as usize, self.init.as_ref().map(|v| &v[..]).unwrap_or(&[])) )
 		}
 	}
 }
 
+/// Register file with independent read and write ports
+///
+/// Inputs: per read port: enable, address; then per write port: enable, address, data
+/// Outputs: per read port: data
+///
+/// Reads return the value from before this tick's writes, and if several write ports write the same
+/// register in one tick, the highest-numbered port wins.
+#[derive(Clone)]
+struct ElementREGFILE
+{
+	width: u8,
+	addrbits: u8,
+	read_ports: usize,
+	write_ports: usize,
+	data: Vec<u64>,
+}
+impl Element for ElementREGFILE
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		check_params(params, 2, 4)?;
+		let width       = check_range(0, "width", params[0], 1, 64)?;
+		let addrbits    = check_range(1, "address bits", params[1], 1, 10)?;
+		let read_ports  = check_range(2, "read ports", get_or!(params, 2, 2u64), 1, 16)?;
+		let write_ports = check_range(3, "write ports", get_or!(params, 3, 1u64), 1, 16)?;
+		
+		let exp_inputs = (read_ports * (1 + addrbits) + write_ports * (1 + addrbits + width)) as usize;
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+		
+		Ok( Box::new(ElementREGFILE {
+			width: width as u8,
+			addrbits: addrbits as u8,
+			read_ports: read_ports as usize,
+			write_ports: write_ports as usize,
+			data: ::from_elem(1 << addrbits, 0),
+			}) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementREGFILE{{{},{},{},{}}}", self.width, self.addrbits, self.read_ports, self.write_ports)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		self.read_ports * self.width as usize
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		let addrbits = self.addrbits as usize;
+		let width = self.width as usize;
+		for port in 0 .. self.read_ports
+		{
+			let base = port * (1 + addrbits);
+			if inlines[base]
+			{
+				let idx = read_uint(inlines, base + 1, self.addrbits) as usize;
+				write_uint(outlines, port * width, self.width, self.data[idx]);
+			}
+		}
+		for port in 0 .. self.write_ports
+		{
+			let base = self.read_ports * (1 + addrbits) + port * (1 + addrbits + width);
+			if inlines[base]
+			{
+				let idx = read_uint(inlines, base + 1, self.addrbits) as usize;
+				self.data[idx] = read_uint(inlines, base + 1 + addrbits, self.width);
+			}
+		}
+	}
+	
+	fn reset(&mut self) {
+		for v in self.data.iter_mut() {
+			*v = 0;
+		}
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let (w, a) = (self.width as usize, self.addrbits as usize);
+		let mut rv = Vec::new();
+		for i in 0 .. self.read_ports {
+			rv.push( Pin { name: format!("r{}_enable", i), width: 1 } );
+			rv.push( Pin { name: format!("r{}_addr", i), width: a } );
+		}
+		for i in 0 .. self.write_ports {
+			rv.push( Pin { name: format!("w{}_enable", i), width: 1 } );
+			rv.push( Pin { name: format!("w{}_addr", i), width: a } );
+			rv.push( Pin { name: format!("w{}_data", i), width: w } );
+		}
+		rv
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		(0 .. self.read_ports).map(|i| Pin { name: format!("r{}_data", i), width: self.width as usize }).collect()
+	}
+	
+	fn memory(&self) -> Option<(usize, &[u64])> {
+		Some( (self.width as usize, &self.data[..]) )
+	}
+}
+
+/// True dual-port RAM
+///
+/// Inputs: per port (A then B): enable, write, address, data
+/// Outputs: per port: data
+///
+/// Writes happen before reads (so a port reads back what it just wrote), and if both ports write the same
+/// word in one tick, port B wins.
+#[allow(non_camel_case_types)]
+#[derive(Clone)]
+struct ElementRAM_DP
+{
+	width: u8,
+	addrbits: u8,
+	/// Memory contents (allocated on first update)
+	data: Vec<u64>,
+}
+impl Element for ElementRAM_DP
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		check_params(params, 2, 2)?;
+		let width    = check_range(0, "width", params[0], 1, 64)?;
+		let addrbits = check_range(1, "address bits", params[1], 1, 24)?;
+		
+		let exp_inputs = 2 * (1 + 1 + addrbits + width) as usize;
+		check_inputs(n_inputs, exp_inputs, exp_inputs)?;
+		
+		Ok( Box::new(ElementRAM_DP {
+			width: width as u8,
+			addrbits: addrbits as u8,
+			data: Vec::new(),
+			}) as Box<Element> )
+	}
+	fn name(&self) -> String {
+		format!("ElementRAM_DP{{{},{}}}", self.width, self.addrbits)
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		2 * self.width as usize
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		if self.data.is_empty() {
+			self.data = ::from_elem(1 << self.addrbits, 0);
+		}
+		let addrbits = self.addrbits as usize;
+		let width = self.width as usize;
+		let port_size = 1 + 1 + addrbits + width;
+		for port in 0 .. 2
+		{
+			let base = port * port_size;
+			if inlines[base] && inlines[base + 1]
+			{
+				let idx = read_uint(inlines, base + 2, self.addrbits) as usize;
+				self.data[idx] = read_uint(inlines, base + 2 + addrbits, self.width);
+			}
+		}
+		for port in 0 .. 2
+		{
+			let base = port * port_size;
+			if inlines[base]
+			{
+				let idx = read_uint(inlines, base + 2, self.addrbits) as usize;
+				write_uint(outlines, port * width, self.width, self.data[idx]);
+			}
+		}
+	}
+	
+	fn reset(&mut self) {
+		self.data.clear();
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		let (w, a) = (self.width as usize, self.addrbits as usize);
+		let mut rv = Vec::new();
+		for p in &["a", "b"] {
+			rv.push( Pin { name: format!("{}_enable", p), width: 1 } );
+			rv.push( Pin { name: format!("{}_write", p), width: 1 } );
+			rv.push( Pin { name: format!("{}_addr", p), width: a } );
+			rv.push( Pin { name: format!("{}_data", p), width: w } );
+		}
+		rv
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("a_data", self.width as usize), Pin::new("b_data", self.width as usize) ]
+	}
+	
+	fn memory(&self) -> Option<(usize, &[u64])> {
+		Some( (self.width as usize, &self.data[..]) )
+	}
+}
+
+/// Receive side of a UART (bytes from stdin or a file)
+#[derive(Clone)]
+enum UartRx
+{
+	/// Shared process-wide reader (see `console`), so the simulation doesn't block waiting for input
+	Stdin,
+	/// File input (read up-front, so runs are repeatable)
+	Buffered(::std::collections::VecDeque<u8>),
+}
+impl UartRx
+{
+	/// Returns the next byte, or Err(true) at end of input (Err(false) if nothing is available yet)
+	fn next(&mut self) -> Result<u8,bool>
+	{
+		match *self
+		{
+		UartRx::Stdin => ::console::read_byte(),
+		UartRx::Buffered(ref mut data) => data.pop_front().ok_or(true),
+		}
+	}
+}
+
+/// Byte-wide console I/O device
+///
+/// Params: optional index for `#uart_file` (defaults to stdin/stdout)
+/// Inputs: enable, transmit strobe, transmit data[8], receive acknowledge
+/// Outputs: receive ready, receive end-of-input, receive data[8]
+///
+/// A byte is transmitted on each rising edge of the strobe. A received byte is presented with ready high
+/// until it is acknowledged, ready then drops for at least one tick before the next byte.
+///
+/// Stdin is shared by every UART (and by `--tui`, which takes it for key presses), so use `#uart_file` for
+/// repeatable input. A transmit file is created once per run and appended to by every instance and test.
+#[derive(Clone)]
+struct ElementUART
+{
+	index: Option<usize>,
+	
+	rx: UartRx,
+	/// Transmit file (stdout if None)
+	tx_file: Option<Rc<::std::cell::RefCell<::std::fs::File>>>,
+	/// Set once a transmit fails (further output is dropped)
+	tx_failed: bool,
+	last_strobe: bool,
+	rx_byte: Option<u8>,
+	rx_eof: bool,
+}
+impl Element for ElementUART
+{
+	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
+	{
+		check_params(params, 0, 1)?;
+		check_inputs(n_inputs, 1 + 1 + 8 + 1, 1 + 1 + 8 + 1)?;
+		Ok( Box::new(ElementUART {
+			index: params.first().map(|&v| v as usize),
+			rx: UartRx::Stdin,
+			tx_file: None,
+			tx_failed: false,
+			last_strobe: false,
+			rx_byte: None,
+			rx_eof: false,
+			}) as Box<Element> )
+	}
+	fn finalise(&mut self, unit: &::cct_mesh::Unit) -> Result<(),ElementError> {
+		if let Some(idx) = self.index {
+			if let Some(p) = unit.get_io_file(idx, false) {
+				let data = ::std::fs::read(&p)
+					.map_err(|e| ElementError::Invalid(format!("UART: Unable to open '{}' for receive: {}", p.display(), e)))?;
+				self.rx = UartRx::Buffered(data.into_iter().collect());
+			}
+			if let Some(p) = unit.get_io_file(idx, true) {
+				self.tx_file = Some( ::console::open_tx(&p).map_err(|e| ElementError::Invalid(format!("UART: {}", e)))? );
+			}
+		}
+		Ok( () )
+	}
+	fn name(&self) -> String {
+		match self.index
+		{
+		Some(i) => format!("ElementUART{{{}}}", i),
+		None => "ElementUART".to_string(),
+		}
+	}
+	fn get_outputs(&self, _n_inputs: usize) -> usize {
+		1 + 1 + 8
+	}
+	
+	fn dup(&self) -> Box<Element+'static> {
+		Box::new(self.clone()) as Box<Element>
+	}
+
+	fn update(&mut self, outlines: &mut [bool], inlines: &[bool])
+	{
+		use std::io::Write;
+		let enable = inlines[0];
+		let strobe = inlines[1];
+		let ack    = inlines[10];
+		if !enable {
+			self.last_strobe = strobe;
+			return ;
+		}
+		
+		// Transmit
+		if strobe && !self.last_strobe && !self.tx_failed
+		{
+			let byte = [read_uint(inlines, 2, 8) as u8];
+			let rv = match self.tx_file {
+				Some(ref f) => f.borrow_mut().write_all(&byte),
+				None => { let mut so = ::std::io::stdout(); so.write_all(&byte).and_then(|_| so.flush()) },
+				};
+			if let Err(e) = rv {
+				eprintln!("UART: Transmit failed: {} (further output dropped)", e);
+				self.tx_failed = true;
+			}
+		}
+		self.last_strobe = strobe;
+		
+		// Receive
+		if self.rx_byte.is_some()
+		{
+			if ack {
+				self.rx_byte = None;
+			}
+		}
+		else if !self.rx_eof
+		{
+			match self.rx.next()
+			{
+			Ok(b) => self.rx_byte = Some(b),
+			Err(eof) => self.rx_eof = eof,
+			}
+		}
+		if let Some(b) = self.rx_byte {
+			outlines[0] = true;
+			write_uint(outlines, 2, 8, b as u64);
+		}
+		outlines[1] = self.rx_eof;
+	}
+	
+	fn reset(&mut self) {
+		// Pending input is kept, the console doesn't get reset
+		self.last_strobe = false;
+	}
+	
+	fn input_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("enable", 1), Pin::new("tx_strobe", 1), Pin::new("tx_data", 8), Pin::new("rx_ack", 1) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("rx_ready", 1), Pin::new("rx_eof", 1), Pin::new("rx_data", 8) ]
+	}
+}
+
 #[derive(Clone)]
 struct ElementROM
 {
 	file_index: usize,
 	wordsize: usize,
+	addr_bits: usize,
 	romdata: Option<Rc<Vec<u64>>>,
 }
 impl Element for ElementROM
 {
 	fn new(params: &[u64], n_inputs: usize) -> NewEleResult
 	{
-		//let addr_size = n_inputs - 1;
 
+		check_params(params, 1, 2)?;
+		check_inputs(n_inputs, 1, usize::MAX)?;
 		let mut params_it = params.iter().cloned();
-		let file_index = params_it.next().ok_or_else(|| format!("No ROM index passed"))? as usize;
+		let file_index = params_it.next().unwrap() as usize;
 		// TODO: How can this easily get the ROM data?
 		// - At this stage, will the data be known?
 		let wordsize = params_it.next().unwrap_or(8) as usize;
 		Ok( Box::new(ElementROM {
 			file_index,
 			wordsize,
+			addr_bits: n_inputs - 1,
 			romdata: None,
 			}) as Box<Element> )
 	}
-	fn finalise(&mut self, unit: &::cct_mesh::Unit) {
-		self.romdata = Some( unit.get_rom(self.file_index) );
+	fn finalise(&mut self, unit: &::cct_mesh::Unit) -> Result<(),ElementError> {
+		match unit.get_rom(self.file_index)
+		{
+		Some(d) => self.romdata = Some(d),
+		None => return Err(ElementError::Invalid(format!("ROM data block {} not defined", self.file_index))),
+		}
+		let n_words = 1usize.checked_shl(self.addr_bits as u32).unwrap_or(usize::MAX);
+		unit.check_rom(self.file_index, n_words, self.wordsize).map_err(ElementError::Invalid)
 	}
 	fn name(&self) -> String
 	{
@@ -828,7 +2288,252 @@ impl Element for ElementROM
 			}
 		}
 	}
+	
+	fn input_pins(&self, n_inputs: usize) -> Vec<Pin> {
+		// NOTE: Address is MSB first
+		vec![ Pin::new("enable", 1), Pin::new("addr", n_inputs - 1) ]
+	}
+	fn output_pins(&self, _n_inputs: usize) -> Vec<Pin> {
+		vec![ Pin::new("data", self.wordsize) ]
+	}
+	
+	fn encode(&self, cnf: &mut Cnf, _state: &mut Vec<Lit>, inlines: &[Lit]) -> Option<Vec<Lit>>
+	{
+		// Each data bit is an OR of the (enabled) address matches where that bit is set, so
+		// large ROMs aren't worth encoding
+		const MAX_ADDR_BITS: usize = 12;
+		let romdata = self.romdata.as_ref().unwrap();
+		let addr_bits = inlines.len() - 1;
+		if addr_bits > MAX_ADDR_BITS {
+			return None;
+		}
+		// Address is MSB first
+		let addr: Vec<Lit> = inlines[1..].iter().rev().cloned().collect();
+		let n_words = ::std::cmp::min(romdata.len(), 1 << addr_bits);
+		let selected: Vec<Lit> = (0 .. n_words)
+			.map(|a| { let eq = cnf.equals_const(&addr, a as u64); cnf.and(&[inlines[0], eq]) })
+			.collect();
+		Some( (0 .. self.wordsize).map(|i| {
+			let terms: Vec<Lit> = selected.iter().zip( romdata.iter() )
+				.filter(|&(_,d)| (d >> i) & 1 != 0)
+				.map(|(&l,_)| l)
+				.collect();
+			cnf.or(&terms)
+			}).collect() )
+	}
+}
+
+
+#[test]
+fn test_arith()
+{
+	use test_util::bits;
+	let run = |name: &str, ins: Vec<bool>| ::test_util::eval_element(name, &[4], ins);
+	// 7 + 9 + 1 = 17 -> 1, carry, no signed overflow (7 + -7 + 1)
+	assert_eq!( run("ADD", [bits(7,4), bits(9,4), vec![true]].concat()), [bits(1,4), vec![true, false]].concat() );
+	// 7 + 1 overflows signed
+	assert_eq!( run("ADD", [bits(7,4), bits(1,4), vec![false]].concat()), [bits(8,4), vec![false, true]].concat() );
+	// 2 - 3 = -1, borrow
+	assert_eq!( run("SUB", [bits(2,4), bits(3,4), vec![false]].concat()), [bits(15,4), vec![true, false]].concat() );
+	// 2 vs 0xE (-2): unsigned less, signed greater
+	assert_eq!( run("CMP", [bits(2,4), bits(0xE,4)].concat()), vec![false, true, false, false, true] );
+	assert_eq!( run("MUL", [bits(15,4), bits(13,4)].concat()), bits(195,8) );
+}
+
+#[test]
+fn test_registers()
+{
+	use test_util::{bits, run_element as run};
+	let out = |v: u64, flag: bool| -> Vec<bool> { [bits(v, 4), vec![flag]].concat() };
+
+	// COUNTER: enable, reset, load, down, value -> count, terminal count
+	let ctr = |en: bool, reset: bool, load: bool, down: bool, v: u64| [vec![en, reset, load, down], bits(v, 4)].concat();
+	assert_eq!( run("COUNTER", &[4], &[
+		ctr(true, false, true, false, 14),	// load
+		ctr(true, false, false, false, 0),	// up to the terminal count
+		ctr(true, false, false, false, 0),	// wrap
+		ctr(false, false, true, false, 7),	// disabled, load ignored
+		ctr(true, false, false, true, 0),	// down wraps
+		ctr(true, false, true, true, 1),
+		ctr(true, false, false, true, 0),	// down to the terminal count
+		ctr(true, true, true, false, 9),	// reset beats load
+		]), vec![out(14, false), out(15, true), out(0, false), out(0, false), out(15, false), out(1, false), out(0, true), out(0, false)] );
+
+	// SHIFTREG: enable, reset, load, serial in, parallel in -> value, serial out
+	let sr = |en: bool, reset: bool, load: bool, serial: bool, v: u64| [vec![en, reset, load, serial], bits(v, 4)].concat();
+	assert_eq!( run("SHIFTREG", &[4], &[
+		sr(true, false, false, true, 0),
+		sr(true, false, false, false, 0),
+		sr(true, false, false, true, 0),
+		sr(false, false, false, true, 0),	// disabled
+		sr(true, false, false, true, 0),
+		sr(true, false, false, false, 0),	// MSB shifted out
+		sr(true, false, true, false, 9),	// load
+		sr(true, true, false, true, 0),
+		]), vec![out(1, false), out(2, false), out(5, false), out(5, false), out(11, true), out(6, false), out(9, true), out(0, false)] );
+
+	// LFSR: x^4 + x^3 + 1 visits all 15 non-zero states before returning to the seed
+	let mut ticks = vec![vec![true, false]; 15];
+	ticks.push( vec![false, false] );
+	ticks.push( vec![true, false] );
+	ticks.push( vec![true, true] );
+	let outs = run("LFSR", &[4, 0xC], &ticks);
+	let states: Vec<u64> = outs.iter().map(|o| ::simulator::decode_u64_le(&o[..4])).collect();
+	let mut seen = states[..15].to_vec();
+	seen.sort();
+	assert_eq!( seen, (1 .. 16).collect::<Vec<u64>>() );
+	assert_eq!( states[14], 1 );
+	assert_eq!( &states[15 ..], &[1, states[0], 1] );	// held while disabled, reset reloads the seed
+	assert!( outs.iter().all(|o| o[4] == o[3]) );	// Serial out is the MSB
 }
 
+#[test]
+fn test_decoders()
+{
+	use test_util::{bits, eval_element as run};
+	let with_enable = |en: bool, v: u64, n: usize| [vec![en], bits(v, n)].concat();
+	// Encoder style outputs: 2-bit value and valid
+	let enc = |v: u64, valid: bool| [bits(v, 2), vec![valid]].concat();
+
+	for sel in 0 .. 4
+	{
+		assert_eq!( run("DECODER", &[2], with_enable(true, sel, 2)), bits(1 << sel, 4) );
+		assert_eq!( run("DECODER", &[2], with_enable(false, sel, 2)), bits(0, 4) );
+	}
+
+	// Full truth tables over the four request lines
+	for lines in 0 .. 16u64
+	{
+		let set: Vec<u64> = (0 .. 4).filter(|i| (lines >> i) & 1 != 0).collect();
+		let any = !set.is_empty();
+		assert_eq!( run("ENCODER", &[2], with_enable(true, lines, 4)), enc(set.iter().fold(0, |a,&b| a | b), any), "ENCODER {:04b}", lines );
+		assert_eq!( run("PRIORITY", &[2, 0], with_enable(true, lines, 4)), enc(set.first().cloned().unwrap_or(0), any), "PRIORITY low {:04b}", lines );
+		assert_eq!( run("PRIORITY", &[2, 1], with_enable(true, lines, 4)), enc(set.last().cloned().unwrap_or(0), any), "PRIORITY high {:04b}", lines );
+		for &(name, params) in &[("ENCODER", &[2][..]), ("PRIORITY", &[2, 0][..])] {
+			assert_eq!( run(name, params, with_enable(false, lines, 4)), enc(0, false), "{} disabled {:04b}", name, lines );
+		}
+	}
+	// No lines, and several lines (1 and 2) set
+	assert_eq!( run("ENCODER", &[2], with_enable(true, 0b0000, 4)), enc(0, false) );
+	assert_eq!( run("ENCODER", &[2], with_enable(true, 0b0110, 4)), enc(3, true) );
+	assert_eq!( run("PRIORITY", &[2, 0], with_enable(true, 0b0110, 4)), enc(1, true) );
+	assert_eq!( run("PRIORITY", &[2, 1], with_enable(true, 0b0110, 4)), enc(2, true) );
+
+	for v in 0 .. 32u64 {
+		assert_eq!( run("POPCOUNT", &[5], bits(v, 5)), bits(v.count_ones() as u64, 3), "POPCOUNT {:05b}", v );
+	}
+}
+
+#[test]
+fn test_memories()
+{
+	use test_util::{bits, run_element as run};
+	let out = |a: u64, b: u64| [bits(a, 4), bits(b, 4)].concat();
+
+	// REGFILE{4,2,2,2}: per read port enable, address; per write port enable, address, data
+	let rd = |en: bool, addr: u64| [vec![en], bits(addr, 2)].concat();
+	let wr = |en: bool, addr: u64, v: u64| [vec![en], bits(addr, 2), bits(v, 4)].concat();
+	assert_eq!( run("REGFILE", &[4, 2, 2, 2], &[
+		[rd(true, 1), rd(false, 0), wr(true, 1, 5), wr(false, 0, 0)].concat(),	// read sees the value from before the write
+		[rd(true, 1), rd(true, 2), wr(true, 2, 3), wr(true, 2, 9)].concat(),	// both write ports hit register 2
+		[rd(true, 1), rd(true, 2), wr(false, 0, 0), wr(false, 0, 0)].concat(),	// the highest-numbered port won
+		]), vec![out(0, 0), out(5, 0), out(5, 9)] );
+
+	// RAM_DP{4,2}: per port enable, write, address, data
+	let port = |en: bool, write: bool, addr: u64, v: u64| [vec![en, write], bits(addr, 2), bits(v, 4)].concat();
+	assert_eq!( run("RAM_DP", &[4, 2], &[
+		[port(true, true, 1, 6), port(true, false, 1, 0)].concat(),	// both ports read back this tick's write
+		[port(true, true, 2, 3), port(true, true, 2, 12)].concat(),	// both ports write word 2
+		[port(true, false, 2, 0), port(true, false, 1, 0)].concat(),	// port B won
+		]), vec![out(6, 6), out(12, 12), out(12, 6)] );
+
+	let registry = ElementRegistry::default();
+	assert_eq!( registry.create("REGFILE", &[4, 2, 0, 1], 17).err(), Some(ElementError::ParamRange { param_index: 2, name: "read ports", min: 1, max: 16, have: 0 }) );
+	assert_eq!( registry.create("REGFILE", &[4, 2, 2, 17], 20).err(), Some(ElementError::ParamRange { param_index: 3, name: "write ports", min: 1, max: 16, have: 17 }) );
+	assert_eq!( registry.create("REGFILE", &[4, 2, 2, 2, 1], 20).err(), Some(ElementError::ParamCount { min: 2, max: 4, have: 5 }) );
+	// Two read ports (3 inputs each) and two write ports (7 each)
+	assert_eq!( registry.create("REGFILE", &[4, 2, 2, 2], 19).err(), Some(ElementError::InputCount { min: 20, max: 20, have: 19 }) );
+	assert_eq!( registry.create("RAM_DP", &[4, 2], 15).err(), Some(ElementError::InputCount { min: 16, max: 16, have: 15 }) );
+	assert_eq!( registry.create("RAM_DP", &[4], 16).err(), Some(ElementError::ParamCount { min: 2, max: 2, have: 1 }) );
+}
+
+#[test]
+fn test_uart()
+{
+	use test_util::{bits, run_element};
+	let dir = ::std::env::temp_dir();
+	let (rx_path, tx_path) = (dir.join(format!("lc_uart_rx_{}", ::std::process::id())), dir.join(format!("lc_uart_tx_{}", ::std::process::id())));
+	::std::fs::write(&rx_path, "ok").unwrap();
+	// Transmit on the two rising strobe edges, and acknowledge each received byte
+	let src = format!(concat!(
+		"#testcase 12 \"t\"\n",
+		"#uart_file 1 rx \"{}\"\n",
+		"#uart_file 1 tx \"{}\"\n",
+		"#array d 8\n",
+		"#array r 8\n",
+		"$rdy, $eof, @r = UART{{1}} 1, $s, @d, $ack\n",
+		"#at 0 @d, $s = 72, 1\n",
+		"#at 2 $s = 0\n",
+		"#at 3 @d, $s = 105, 1\n",
+		"#at 4 $ack = 1\n",
+		"#at 5 $ack = 0\n",
+		"#at 7 $ack = 1\n",
+		"#at 8 $ack = 0\n",
+		// 'o' is held until acknowledged, then ready drops for a tick before 'k'
+		"#expect_at 3 $rdy, $eof, @r 1, 0, 111[0:7]\n",
+		"#expect_at 4 $rdy, @r 0, 0[0:7]\n",
+		"#expect_at 6 $rdy, @r 1, 107[0:7]\n",
+		// Out of input: ready stays low and end-of-input is raised
+		"#expect_at 7 $rdy, $eof 0, 0\n",
+		"#expect_at 8 $rdy, $eof 0, 1\n",
+		"#expect_at 11 $rdy, $eof 0, 1\n",
+		"#endtestcase\n",
+		), rx_path.display(), tx_path.display());
+	let status = ::test_util::run_test(&src);
+	let sent = ::std::fs::read(&tx_path).unwrap();
+	let _ = ::std::fs::remove_file(&rx_path);
+	let _ = ::std::fs::remove_file(&tx_path);
+	assert_eq!( status, ::TestStatus::Pass(12) );
+	assert_eq!( sent, b"Hi" );
+
+	// Nothing available on the console yet: ready and end-of-input both stay low, acknowledging is harmless
+	::console::reserve_for_keys();
+	let idle = |ack: bool| [vec![true, false], bits(0, 8), vec![ack]].concat();
+	let outs = run_element("UART", &[], &[idle(false), idle(true), idle(false)]);
+	assert!( outs.iter().all(|o| o.iter().all(|&v| !v)), "{:?}", outs );
+}
+
+#[test]
+fn test_pins()
+{
+	let cases: &[(&str, &[u64], usize)] = &[
+		("DELAY", &[3], 4), ("ENABLE", &[], 5), ("CLOCK", &[4], 1), ("AND", &[2], 6), ("NOT", &[], 3),
+		("LATCH", &[4], 6), ("JKFLIPFLOP", &[], 3), ("MUX", &[2, 3], 15), ("DEMUX", &[2], 6),
+		("DECODER", &[3], 4), ("ENCODER", &[2], 5), ("PRIORITY", &[2, 1], 5), ("POPCOUNT", &[5], 5),
+		("SEQUENCER", &[4], 3), ("COUNTER", &[4], 8), ("SHIFTREG", &[4], 8), ("LFSR", &[4, 9], 2),
+		("MEMORY_DRAM", &[8, 4], 22), ("REGFILE", &[8, 3, 2, 1], 20), ("RAM_DP", &[8, 4], 28),
+		("UART", &[], 11), ("ROM", &[0, 8], 5), ("ADD", &[4], 9), ("CMP", &[4], 8), ("MUL", &[4], 8),
+		];
+	let registry = ElementRegistry::default();
+	for &(name, params, n_inputs) in cases
+	{
+		let ele = registry.create(name, params, n_inputs).unwrap_or_else(|e| panic!("{}: {}", name, e));
+		let total = |pins: Vec<Pin>| pins.iter().map(|p| p.width).sum::<usize>();
+		assert_eq!( total(ele.input_pins(n_inputs)), n_inputs, "{} inputs", name );
+		assert_eq!( total(ele.output_pins(n_inputs)), ele.get_outputs(n_inputs), "{} outputs", name );
+	}
+	
+	assert_eq!( registry.create("LFSR", &[4, 16], 2).err(), Some(ElementError::ParamRange { param_index: 1, name: "taps", min: 1, max: 15, have: 16 }) );
+	assert_eq!( registry.create("JKFLIPFLOP", &[], 2).err(), Some(ElementError::InputCount { min: 3, max: 3, have: 2 }) );
+}
+
+#[test]
+fn test_registry()
+{
+	let mut registry = ElementRegistry::empty();
+	assert_eq!( registry.create("NOT", &[], 1).err(), Some(ElementError::UnknownElement("NOT".to_string())) );
+	registry.register("INV", |_, n_inputs| ElementNOT::new(&[], n_inputs));
+	assert_eq!( registry.create("INV", &[], 2).unwrap().name(), "ElementNOT" );
+}
 
 // vim: ft=rust
@@ -0,0 +1,177 @@
+//
+//
+//
+//! Stuck-at fault simulation (grades how well tests detect broken nodes)
+use cct_mesh::flat;
+use cct_mesh::flat::NodeRef;
+
+/// A single stuck-at fault on a node of the unit under test
+#[derive(Clone,Copy)]
+pub struct Fault
+{
+	pub node: u32,
+	pub value: bool,
+}
+
+pub struct Report
+{
+	pub unit: String,
+	pub n_tests: usize,
+	pub n_faults: usize,
+	pub undetected: Vec<String>,
+}
+
+/// Enumerate all stuck-at faults for a unit (optionally a random sample of them)
+pub fn enumerate_faults(unit: &flat::Mesh, sample: Option<(usize,u64)>) -> Vec<Fault>
+{
+	// Only nodes that are connected to something (the constant links are allocated nodes, but never used)
+	let mut used: Vec<bool> = ::from_elem(unit.n_nodes, false);
+	for ele in unit.elements.iter()
+	{
+		for nr in ele.inputs.iter().chain( ele.outputs.iter() ) {
+			if let NodeRef::NodeId(id) = *nr {
+				used[id as usize] = true;
+			}
+		}
+	}
+	let mut faults: Vec<Fault> = (0 .. unit.n_nodes as u32)
+		.filter(|&id| used[id as usize])
+		.flat_map(|id| vec![ Fault { node: id, value: false }, Fault { node: id, value: true } ])
+		.collect();
+	if let Some( (count, seed) ) = sample
+	{
+		// Partial Fisher-Yates shuffle
+		let mut rng = ::rng::Rng::new(seed);
+		let count = ::std::cmp::min(count, faults.len());
+		for i in 0 .. count
+		{
+			let j = i + rng.below( (faults.len() - i) as u64 ) as usize;
+			faults.swap(i, j);
+		}
+		faults.truncate(count);
+	}
+	faults
+}
+
+/// Run each fault against all tests that instantiate the unit
+///
+/// A fault is applied to every instance of the unit within a test, and is detected if any test that passes
+/// on the fault-free circuit fails or times out.
+pub fn run(unit: &flat::Mesh, tests: &[(&String, &flat::Test)], faults: &[Fault]) -> Report
+{
+	// Only tests that contain the unit and pass without faults are useful
+	let relevant: Vec<_> = tests.iter()
+		.filter(|&&(_,t)| !t.get_mesh().instances_of(&unit.name).is_empty())
+		.filter(|&&(name,t)| match ::run_test(t, None, None, None)
+			{
+			::TestStatus::Pass(_) => true,
+			_ => {
+				println!("Test '{}' fails without faults, ignoring", name);
+				false
+				},
+			})
+		.collect();
+
+	let mut undetected = Vec::new();
+	for fault in faults.iter()
+	{
+		let detected = relevant.iter().any(|&&(name,test)| {
+			let mesh = test.get_mesh();
+			let mut sim = ::simulator::Engine::new(mesh);
+			let mut n_forced = 0;
+			for inst in mesh.instances_of(&unit.name)
+			{
+				if let NodeRef::NodeId(id) = inst[fault.node as usize] {
+					sim.force_node(id, fault.value);
+					n_forced += 1;
+				}
+			}
+			if n_forced == 0 {
+				// Node is tied to a constant in every instance, can't inject
+				return false;
+			}
+			match ::run_test_inner(&mut sim, test, None, None)
+			{
+			::TestStatus::Pass(_) => false,
+			_ => {
+				debug!("Fault {} SA{} detected by '{}'", unit.node_name(fault.node), fault.value as u8, name);
+				true
+				},
+			}
+			});
+		if !detected {
+			undetected.push( format!("{} stuck-at-{}", unit.node_name(fault.node), fault.value as u8) );
+		}
+	}
+
+	Report {
+		unit: unit.name.clone(),
+		n_tests: relevant.len(),
+		n_faults: faults.len(),
+		undetected,
+	}
+}
+
+impl Report
+{
+	pub fn percent_detected(&self) -> f64 {
+		if self.n_faults == 0 {
+			100.0
+		}
+		else {
+			(self.n_faults - self.undetected.len()) as f64 * 100.0 / self.n_faults as f64
+		}
+	}
+
+	pub fn print(&self)
+	{
+		println!("Fault simulation of '{}': {} faults, {} tests", self.unit, self.n_faults, self.n_tests);
+		println!("Detected {}/{} ({:.1}%)", self.n_faults - self.undetected.len(), self.n_faults, self.percent_detected());
+		if !self.undetected.is_empty()
+		{
+			println!("Undetected faults:");
+			for f in self.undetected.iter() {
+				println!("  {}", f);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_faults()
+{
+	// Only ever drives a=1, b=0 and checks y=1, so only faults pulling y low are caught
+	let mut root = ::test_util::load(concat!(
+		"#defunit OR2\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y = OR $a, $b\n",
+		"#endunit\n",
+		"#testcase 4 \"t\"\n",
+		"$a = DELAY{1} 1\n",
+		"$b = DELAY{1} 0\n",
+		"$y = OR2 $a, $b\n",
+		"#expect_at 2 $y 1\n",
+		"#endtestcase\n",
+		));
+	root.flatten_tests(&["t".to_string()]);
+	let tests: Vec<_> = root.iter_tests().collect();
+	let unit = root.get_flat_unit("OR2").unwrap();
+	let faults = enumerate_faults(unit, None);
+	assert_eq!( faults.len(), 6 );
+	
+	let report = run(unit, &tests, &faults);
+	assert_eq!( report.n_tests, 1 );
+	// Detected: a stuck-at-0 and y stuck-at-0 (the list follows node numbering, which isn't fixed)
+	let mut undetected = report.undetected.clone();
+	undetected.sort();
+	assert_eq!( undetected, ["a stuck-at-1", "b stuck-at-0", "b stuck-at-1", "y stuck-at-1"] );
+	assert!( (report.percent_detected() - 100.0 / 3.0).abs() < 1e-9 );
+	
+	// A fault sample is a subset of the full list
+	let sample = enumerate_faults(unit, Some((3, 1)));
+	assert_eq!( sample.len(), 3 );
+	assert!( sample.iter().all(|f| faults.iter().any(|g| g.node == f.node && g.value == f.value)) );
+}
+
+// vim: ft=rust
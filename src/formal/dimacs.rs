@@ -0,0 +1,143 @@
+//
+//
+//
+//! DIMACS CNF export of an unrolled unit (for use with external solvers)
+use std::io::Write;
+use cct_mesh::flat;
+use cct_mesh::flat::NodeRef;
+use super::{Cnf,Lit};
+
+/// Nodes represented by each variable, as `(tick, node, negated)`
+pub type VarMap = Vec<Vec<(usize,u32,bool)>>;
+
+/// Unroll a unit with free inputs, and build the variable map
+pub fn export(mesh: &flat::Mesh, n_ticks: usize, free_unsupported: bool) -> Result<(Cnf, super::Unrolled, VarMap),String>
+{
+	let mut cnf = Cnf::new();
+	let free: Vec<(u32,Vec<Lit>)> = mesh.inputs.iter()
+		.filter_map(|nr| match *nr { NodeRef::NodeId(id) => Some(id), _ => None })
+		.map(|id| (id, (0 .. n_ticks+1).map(|_| cnf.new_var()).collect()))
+		.collect();
+	let unrolled = super::unroll(&mut cnf, mesh, n_ticks, &free, free_unsupported)?;
+
+	let mut varmap: VarMap = ::from_elem(cnf.n_vars() as usize + 1, Vec::new());
+	for (tick,nodes) in unrolled.nodes.iter().enumerate()
+	{
+		for (id,l) in nodes.iter().enumerate()
+		{
+			// Constant nodes are all represented by the TRUE variable, which isn't interesting
+			if l.var() != cnf.lit_true().var() {
+				varmap[l.var() as usize].push( (tick, id as u32, l.is_neg()) );
+			}
+		}
+	}
+	Ok( (cnf, unrolled, varmap) )
+}
+
+/// Write the variable map as JSON (`{"vars": {"N": [{"tick":T,"signal":"S","negated":B}, ...]}}`)
+pub fn write_varmap(out: &mut dyn Write, mesh: &flat::Mesh, n_ticks: usize, unrolled: &super::Unrolled, varmap: &VarMap) -> ::std::io::Result<()>
+{
+	use coverage::json_str;
+	writeln!(out, "{{")?;
+	writeln!(out, "  \"unit\": {},", json_str(&mesh.name))?;
+	writeln!(out, "  \"ticks\": {},", n_ticks)?;
+	writeln!(out, "  \"true_var\": 1,")?;
+	let freed: Vec<String> = unrolled.freed.iter().map(|s| json_str(s)).collect();
+	writeln!(out, "  \"free_elements\": [{}],", freed.join(", "))?;
+	writeln!(out, "  \"vars\": {{")?;
+	let used: Vec<_> = varmap.iter().enumerate().filter(|&(_,e)| !e.is_empty()).collect();
+	let names = mesh.node_names();
+	let name = |id: u32| names[id as usize].first().cloned().unwrap_or_else(|| format!("#{}", id));
+	for (i,&(var,entries)) in used.iter().enumerate()
+	{
+		let entries: Vec<String> = entries.iter()
+			.map(|&(tick,id,neg)| format!("{{\"tick\": {}, \"signal\": {}, \"negated\": {}}}", tick, json_str(&name(id)), neg))
+			.collect();
+		writeln!(out, "    \"{}\": [{}]{}", var, entries.join(", "), if i + 1 < used.len() { "," } else { "" })?;
+	}
+	writeln!(out, "  }}")?;
+	writeln!(out, "}}")?;
+	Ok( () )
+}
+
+/// Handle the `cnf UNIT TICKS FILE` command (writes FILE and FILE.json)
+pub fn cmd_cnf(mesh: &flat::Mesh, n_ticks: usize, path: &str, free_unsupported: bool) -> bool
+{
+	let (cnf, unrolled, varmap) = match export(mesh, n_ticks, free_unsupported)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			println!("Cannot export '{}': {}", mesh.name, e);
+			println!("(use --free-unsupported to model these elements as free inputs)");
+			return false;
+			},
+		};
+	for e in unrolled.freed.iter() {
+		println!("Modelling {} as a free input", e);
+	}
+	let map_path = format!("{}.json", path);
+	let rv = ::std::fs::File::create(path)
+		.and_then(|mut fp| cnf.write_dimacs(&mut fp))
+		.and_then(|_| ::std::fs::File::create(&map_path))
+		.and_then(|mut fp| write_varmap(&mut fp, mesh, n_ticks, &unrolled, &varmap));
+	match rv
+	{
+	Ok(_) => {
+		println!("Wrote {} ({} vars, {} clauses) and {}", path, cnf.n_vars(), cnf.clauses().len(), map_path);
+		true
+		},
+	Err(e) => {
+		println!("Unable to write '{}': {}", path, e);
+		false
+		},
+	}
+}
+
+#[test]
+fn test_export()
+{
+	let mut root = ::test_util::load(concat!(
+		"#defunit AND2\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$n = NAND $a, $b\n",
+		"$y = NOT $n\n",
+		"#endunit\n",
+		"#defunit LATCHED\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y, $z = LATCH $a, $b, 0\n",
+		"#endunit\n",
+		));
+	let and2 = root.flatten_unit("AND2").unwrap();
+	let latched = root.flatten_unit("LATCHED").unwrap();
+
+	// Ticks 0 and 1: the inputs get a variable per tick (a = 2,3, b = 4,5), and the NAND on tick 1 is a new one
+	let (cnf, unrolled, varmap) = export(&and2, 1, false).unwrap();
+	let mut dimacs = Vec::new();
+	cnf.write_dimacs(&mut dimacs).unwrap();
+	let dimacs = String::from_utf8(dimacs).unwrap();
+	// The TRUE unit clause, and three for the NAND's AND
+	assert_eq!( dimacs.lines().next(), Some("p cnf 6 4") );
+	assert_eq!( dimacs.lines().count(), 5 );
+	let node = |name: &str| and2.nodes.iter().position(|n| n.names.iter().any(|v| v == name)).unwrap() as u32;
+	let (a, b, n) = (node("a"), node("b"), node("n"));
+	assert_eq!( varmap, vec![vec![], vec![], vec![(0,a,false)], vec![(1,a,false)], vec![(0,b,false)], vec![(1,b,false)], vec![(1,n,true)]] );
+	
+	let mut json = Vec::new();
+	write_varmap(&mut json, &and2, 1, &unrolled, &varmap).unwrap();
+	let json = String::from_utf8(json).unwrap();
+	assert!( json.contains("\"free_elements\": [],") );
+	assert!( json.contains("    \"3\": [{\"tick\": 1, \"signal\": \"a\", \"negated\": false}],\n") );
+	assert!( json.contains("    \"6\": [{\"tick\": 1, \"signal\": \"n\", \"negated\": true}]\n") );
+
+	assert!( export(&latched, 1, false).is_err() );
+	let (_, unrolled, varmap) = export(&latched, 1, true).unwrap();
+	assert_eq!( unrolled.freed, ["ElementLATCH{1} (driving y)"] );
+	let mut json = Vec::new();
+	write_varmap(&mut json, &latched, 1, &unrolled, &varmap).unwrap();
+	let json = String::from_utf8(json).unwrap();
+	assert!( json.contains("\"free_elements\": [\"ElementLATCH{1} (driving y)\"],") );
+}
+
+// vim: ft=rust
@@ -0,0 +1,438 @@
+//
+//
+//
+//! Formal verification: bit-blasting of flattened meshes into CNF
+use std::rc::Rc;
+use std::io::Write;
+use cct_mesh::flat;
+use cct_mesh::flat::NodeRef;
+
+pub mod sat;
+pub mod prove;
+pub mod dimacs;
+
+/// CNF literal (DIMACS-style: positive variable number, negative when inverted)
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub struct Lit(i32);
+impl Lit {
+	pub fn from_dimacs(v: i32) -> Lit { assert!(v != 0); Lit(v) }
+	pub fn var(self) -> u32 { self.0.unsigned_abs() }
+	pub fn is_neg(self) -> bool { self.0 < 0 }
+}
+impl ::std::ops::Not for Lit {
+	type Output = Lit;
+	fn not(self) -> Lit { Lit(-self.0) }
+}
+
+/// CNF formula under construction (variable 1 is always true)
+pub struct Cnf
+{
+	n_vars: u32,
+	clauses: Vec<Vec<Lit>>,
+}
+
+impl Cnf
+{
+	pub fn new() -> Cnf {
+		Cnf {
+			n_vars: 1,
+			clauses: vec![ vec![Lit(1)] ],
+		}
+	}
+	pub fn n_vars(&self) -> u32 { self.n_vars }
+	pub fn clauses(&self) -> &[Vec<Lit>] { &self.clauses }
+
+	pub fn new_var(&mut self) -> Lit {
+		self.n_vars += 1;
+		Lit(self.n_vars as i32)
+	}
+	pub fn lit_true(&self) -> Lit { Lit(1) }
+	pub fn lit_false(&self) -> Lit { Lit(-1) }
+	fn const_val(l: Lit) -> Option<bool> {
+		match l.0 { 1 => Some(true), -1 => Some(false), _ => None }
+	}
+
+	pub fn add_clause(&mut self, c: Vec<Lit>) {
+		self.clauses.push(c);
+	}
+	
+	/// Write the formula in DIMACS CNF format
+	pub fn write_dimacs(&self, out: &mut dyn Write) -> ::std::io::Result<()>
+	{
+		writeln!(out, "p cnf {} {}", self.n_vars, self.clauses.len())?;
+		for c in self.clauses.iter()
+		{
+			for l in c.iter() {
+				write!(out, "{} ", l.0)?;
+			}
+			writeln!(out, "0")?;
+		}
+		Ok( () )
+	}
+
+	/// Tseitin AND of several literals (constants are folded)
+	pub fn and(&mut self, ins: &[Lit]) -> Lit
+	{
+		let mut lits = Vec::with_capacity(ins.len());
+		for &l in ins
+		{
+			match Cnf::const_val(l)
+			{
+			Some(false) => return self.lit_false(),
+			Some(true) => {},
+			None => if !lits.contains(&l) { lits.push(l) },
+			}
+		}
+		match lits.len()
+		{
+		0 => self.lit_true(),
+		1 => lits[0],
+		_ => {
+			let out = self.new_var();
+			let mut big = vec![ out ];
+			for &l in lits.iter() {
+				self.add_clause( vec![!out, l] );
+				big.push( !l );
+			}
+			self.add_clause( big );
+			out
+			},
+		}
+	}
+	/// Tseitin OR of several literals
+	pub fn or(&mut self, ins: &[Lit]) -> Lit
+	{
+		let inv: Vec<Lit> = ins.iter().map(|&l| !l).collect();
+		!self.and(&inv)
+	}
+	/// Tseitin XOR of two literals
+	pub fn xor(&mut self, a: Lit, b: Lit) -> Lit
+	{
+		match (Cnf::const_val(a), Cnf::const_val(b))
+		{
+		(Some(x), _) => if x { !b } else { b },
+		(_, Some(y)) => if y { !a } else { a },
+		_ if a == b => self.lit_false(),
+		_ if a == !b => self.lit_true(),
+		_ => {
+			let out = self.new_var();
+			self.add_clause( vec![!out,  a,  b] );
+			self.add_clause( vec![!out, !a, !b] );
+			self.add_clause( vec![ out, !a,  b] );
+			self.add_clause( vec![ out,  a, !b] );
+			out
+			},
+		}
+	}
+	/// Tseitin XOR of several literals (parity)
+	pub fn xor_all(&mut self, ins: &[Lit]) -> Lit
+	{
+		let mut val = self.lit_false();
+		for &l in ins {
+			val = self.xor(val, l);
+		}
+		val
+	}
+	/// Ripple-carry adder over two LSB-first words
+	///
+	/// Returns the sum bits, the carry out, and the signed overflow flag.
+	pub fn add(&mut self, a: &[Lit], b: &[Lit], carry_in: Lit) -> (Vec<Lit>, Lit, Lit)
+	{
+		assert_eq!(a.len(), b.len());
+		let mut sum = Vec::with_capacity(a.len());
+		let mut carry = carry_in;
+		let mut carry_msb = carry_in;
+		for (&x,&y) in a.iter().zip(b.iter())
+		{
+			carry_msb = carry;
+			let half = self.xor(x, y);
+			sum.push( self.xor(half, carry) );
+			let (c1, c2) = (self.and(&[x, y]), self.and(&[half, carry]));
+			carry = self.or(&[c1, c2]);
+		}
+		let overflow = self.xor(carry_msb, carry);
+		(sum, carry, overflow)
+	}
+	/// Literal that is true when the bits (LSB first) equal `value`
+	pub fn equals_const(&mut self, bits: &[Lit], value: u64) -> Lit
+	{
+		let lits: Vec<Lit> = bits.iter().enumerate()
+			.map(|(i,&l)| if (value >> i) & 1 != 0 { l } else { !l })
+			.collect();
+		self.and(&lits)
+	}
+}
+
+/// Per-tick node literals for an unrolled mesh
+pub struct Unrolled
+{
+	/// Node values, indexed by tick then node
+	pub nodes: Vec<Vec<Lit>>,
+	/// Elements that couldn't be encoded, and were modelled as free inputs
+	pub freed: Vec<String>,
+}
+impl Unrolled
+{
+	pub fn get(&self, cnf: &Cnf, tick: usize, nr: NodeRef) -> Lit {
+		match nr
+		{
+		NodeRef::NodeZero => cnf.lit_false(),
+		NodeRef::NodeOne => cnf.lit_true(),
+		NodeRef::NodeId(id) => self.nodes[tick][id as usize],
+		}
+	}
+}
+
+/// Unroll a mesh for `n_ticks` ticks from the all-zero initial state
+///
+/// `free_nodes` lists nodes (e.g. unit inputs) that take the given literal at each tick instead of being driven.
+/// If `free_unsupported` is set, elements that can't be encoded have their outputs modelled as free inputs,
+/// otherwise they cause an error.
+pub fn unroll(cnf: &mut Cnf, mesh: &flat::Mesh, n_ticks: usize, free_nodes: &[(u32, Vec<Lit>)], free_unsupported: bool) -> Result<Unrolled,String>
+{
+	let mut ele_state: Vec<Vec<Lit>> = mesh.elements.iter().map(|_| Vec::new()).collect();
+	let mut nodes: Vec<Vec<Lit>> = Vec::with_capacity(n_ticks + 1);
+	// Classify elements up front (with a scratch encoding), so unsupported ones are reported even for zero ticks
+	let unsupported: Vec<bool> = mesh.elements.iter().map(|ele| {
+		let mut scratch = Cnf::new();
+		let inlines: Vec<Lit> = ele.inputs.iter().map(|_| scratch.new_var()).collect();
+		ele.inst.encode(&mut scratch, &mut Vec::new(), &inlines).is_none()
+		}).collect();
+	if !free_unsupported && unsupported.iter().any(|&u| u)
+	{
+		let names: Vec<String> = mesh.elements.iter().zip( unsupported.iter() )
+			.filter(|&(_,&u)| u)
+			.map(|(ele,_)| format!("{} ({})", ele.inst.name(), describe_element(mesh, ele)))
+			.collect();
+		return Err(format!("{} element(s) cannot be encoded: {}", names.len(), names.join(", ")));
+	}
+
+	let mut initial: Vec<Lit> = ::from_elem(mesh.n_nodes, cnf.lit_false());
+	for &(id, ref lits) in free_nodes {
+		initial[id as usize] = lits[0];
+	}
+	nodes.push(initial);
+
+	for tick in 0 .. n_ticks
+	{
+		let mut drivers: Vec<Vec<Lit>> = ::from_elem(mesh.n_nodes, Vec::new());
+		for (idx,(ele,state)) in mesh.elements.iter().zip( ele_state.iter_mut() ).enumerate()
+		{
+			let inlines: Vec<Lit> = ele.inputs.iter().map(|nr| match *nr
+				{
+				NodeRef::NodeZero => cnf.lit_false(),
+				NodeRef::NodeOne => cnf.lit_true(),
+				NodeRef::NodeId(id) => nodes[tick][id as usize],
+				}).collect();
+			let outlines = if unsupported[idx] {
+					ele.outputs.iter().map(|_| cnf.new_var()).collect()
+				}
+				else {
+					ele.inst.encode(cnf, state, &inlines).ok_or_else(|| format!("{} ({}) cannot be encoded after tick 0", ele.inst.name(), describe_element(mesh, ele)))?
+				};
+			assert_eq!(outlines.len(), ele.outputs.len());
+			for (nr,l) in ele.outputs.iter().zip( outlines ) {
+				if let NodeRef::NodeId(id) = *nr {
+					drivers[id as usize].push(l);
+				}
+			}
+		}
+
+		// Multiple drivers of a node are ORed together (same as the simulator)
+		let mut next: Vec<Lit> = drivers.iter().map(|d| cnf.or(d)).collect();
+		for &(id, ref lits) in free_nodes {
+			next[id as usize] = lits[tick + 1];
+		}
+		nodes.push(next);
+	}
+	let freed = mesh.elements.iter().zip( unsupported.iter() )
+		.filter(|&(_,&u)| u)
+		.map(|(ele,_)| format!("{} ({})", ele.inst.name(), describe_element(mesh, ele)))
+		.collect();
+	Ok( Unrolled { nodes, freed } )
+}
+
+/// Human-readable location of an element (scope and first output name)
+pub fn describe_element(mesh: &flat::Mesh, ele: &flat::ElementInst) -> String
+{
+	let rv = match ele.outputs.first()
+		{
+		Some(&NodeRef::NodeId(id)) => format!("driving {}", mesh.node_name(id)),
+		_ => format!("in '{}'", mesh.scope_unit(&ele.scope)),
+		};
+	if ele.block.is_empty() {
+		rv
+	}
+	else {
+		format!("{} [{}]", rv, ele.block)
+	}
+}
+
+/// Counterexample from a failed equivalence check
+pub struct EquivFailure
+{
+	/// Input values at each tick (index 0 is applied before the first tick)
+	pub inputs: Vec<Vec<bool>>,
+	pub tick: usize,
+	pub output_idx: usize,
+	pub values: (bool, bool),
+}
+
+/// Check that two units produce identical outputs for all input sequences of up to `n_ticks` ticks
+pub fn check_equivalence(a: &flat::Mesh, b: &flat::Mesh, n_ticks: usize) -> Result<Option<EquivFailure>,String>
+{
+	if a.inputs.len() != b.inputs.len() {
+		return Err(format!("Input width mismatch, '{}' has {} and '{}' has {}", a.name, a.inputs.len(), b.name, b.inputs.len()));
+	}
+	if a.outputs.len() != b.outputs.len() {
+		return Err(format!("Output width mismatch, '{}' has {} and '{}' has {}", a.name, a.outputs.len(), b.name, b.outputs.len()));
+	}
+
+	let mut cnf = Cnf::new();
+	// Shared free inputs: [port][tick]
+	let input_lits: Vec<Vec<Lit>> = a.inputs.iter()
+		.map(|_| (0 .. n_ticks+1).map(|_| cnf.new_var()).collect())
+		.collect();
+	let free = |mesh: &flat::Mesh| -> Vec<(u32,Vec<Lit>)> {
+		mesh.inputs.iter().zip( input_lits.iter() )
+			.filter_map(|(nr,lits)| match *nr { NodeRef::NodeId(id) => Some( (id, lits.clone()) ), _ => None })
+			.collect()
+		};
+	let ua = unroll(&mut cnf, a, n_ticks, &free(a), false)?;
+	let ub = unroll(&mut cnf, b, n_ticks, &free(b), false)?;
+
+	// Miter: at least one output differs at some tick
+	let mut diffs = Vec::new();
+	for tick in 1 .. n_ticks+1
+	{
+		for (oa,ob) in a.outputs.iter().zip( b.outputs.iter() )
+		{
+			let la = ua.get(&cnf, tick, *oa);
+			let lb = ub.get(&cnf, tick, *ob);
+			diffs.push( (tick, cnf.xor(la, lb)) );
+		}
+	}
+	let lits: Vec<Lit> = diffs.iter().map(|&(_,l)| l).collect();
+	let miter = cnf.or(&lits);
+	cnf.add_clause( vec![miter] );
+
+	info!("Equivalence check: {} vars, {} clauses", cnf.n_vars(), cnf.clauses().len());
+	let model = match solve(&cnf) {
+		Some(m) => m,
+		None => return Ok(None),
+		};
+	let val = |l: Lit| model[l.var() as usize] != l.is_neg();
+
+	// Find the first differing output
+	let (idx, tick) = diffs.iter().enumerate()
+		.find(|&(_, &(_,l))| val(l))
+		.map(|(i, &(t,_))| (i % a.outputs.len(), t))
+		.expect("BUG: SAT model without a differing output");
+	Ok(Some(EquivFailure {
+		inputs: (0 .. tick+1).map(|t| input_lits.iter().map(|lits| val(lits[t])).collect()).collect(),
+		tick,
+		output_idx: idx,
+		values: ( val(ua.get(&cnf, tick, a.outputs[idx])), val(ub.get(&cnf, tick, b.outputs[idx])) ),
+		}))
+}
+
+/// Run the embedded SAT solver on a formula, returning a model (indexed by variable)
+pub fn solve(cnf: &Cnf) -> Option<Vec<bool>>
+{
+	let mut solver = sat::Solver::new(cnf.n_vars());
+	for c in cnf.clauses() {
+		solver.add_clause(c);
+	}
+	solver.solve()
+}
+
+/// Print an input trace (one line per tick, inputs LSB first)
+pub fn print_trace(mesh: &flat::Mesh, inputs: &[Vec<bool>])
+{
+	let names: Vec<String> = mesh.inputs.iter().map(|nr| match *nr
+		{
+		NodeRef::NodeId(id) => mesh.node_name(id),
+		NodeRef::NodeZero => "0".to_string(),
+		NodeRef::NodeOne => "1".to_string(),
+		}).collect();
+	println!("Tick | {}", names.join(" "));
+	for (tick,vals) in inputs.iter().enumerate()
+	{
+		let bits: Vec<String> = vals.iter().zip( names.iter() )
+			.map(|(v,n)| format!("{:>w$}", *v as u8, w = n.len()))
+			.collect();
+		println!("{:4} | {}", tick, bits.join(" "));
+	}
+}
+
+/// Handle the `equiv UNIT_A UNIT_B [TICKS]` command
+pub fn cmd_equiv(unit_a: &Rc<flat::Mesh>, unit_b: &Rc<flat::Mesh>, n_ticks: usize) -> bool
+{
+	match check_equivalence(unit_a, unit_b, n_ticks)
+	{
+	Err(e) => {
+		println!("Cannot check equivalence: {}", e);
+		false
+		},
+	Ok(None) => {
+		println!("'{}' and '{}' are equivalent for {} ticks", unit_a.name, unit_b.name, n_ticks);
+		true
+		},
+	Ok(Some(f)) => {
+		let out_name = |m: &flat::Mesh| match m.outputs[f.output_idx] { NodeRef::NodeId(id) => m.node_name(id), _ => "(const)".to_string() };
+		println!("'{}' and '{}' differ at tick {}: output #{} ({} = {}, {} = {})",
+			unit_a.name, unit_b.name, f.tick, f.output_idx,
+			out_name(unit_a), f.values.0 as u8, out_name(unit_b), f.values.1 as u8);
+		println!("Counterexample inputs:");
+		print_trace(unit_a, &f.inputs);
+		false
+		},
+	}
+}
+
+#[test]
+fn test_equivalence()
+{
+	let mut root = ::test_util::load(concat!(
+		"#defunit AND2\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y = NOT (DELAY (NAND $a, $b))\n",
+		"#endunit\n",
+		// De Morgan, with the same three ticks of latency
+		"#defunit DEMORGAN\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y = NOT (OR (NOT $a), (NOT $b))\n",
+		"#endunit\n",
+		// Actually an OR
+		"#defunit WRONG\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y = NOT (AND (NOT $a), (NOT $b))\n",
+		"#endunit\n",
+		"#defunit LATCHED\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y, $z = LATCH $a, $b, 0\n",
+		"#endunit\n",
+		));
+	let and2 = root.flatten_unit("AND2").unwrap();
+	let demorgan = root.flatten_unit("DEMORGAN").unwrap();
+	let wrong = root.flatten_unit("WRONG").unwrap();
+	let latched = root.flatten_unit("LATCHED").unwrap();
+
+	assert!( check_equivalence(&and2, &demorgan, 6).unwrap().is_none() );
+
+	// The outputs first depend on the inputs at tick 3, and differ when exactly one was set at tick 0
+	let f = check_equivalence(&and2, &wrong, 6).unwrap().expect("no counterexample");
+	assert_eq!( f.tick, 3 );
+	assert_eq!( f.output_idx, 0 );
+	assert_eq!( f.values, (false, true) );
+	assert_eq!( f.inputs.len(), 4 );
+	assert!( f.inputs[0][0] != f.inputs[0][1], "inputs {:?}", f.inputs );
+
+	assert_eq!( check_equivalence(&and2, &latched, 6).err(), Some("1 element(s) cannot be encoded: ElementLATCH{1} (driving y)".to_string()) );
+}
+
+// vim: ft=rust
@@ -0,0 +1,158 @@
+//
+//
+//
+//! Bounded model checking of #property assertions
+use cct_mesh::flat;
+use cct_mesh::flat::NodeRef;
+use super::{Cnf,Lit};
+
+pub struct Violation
+{
+	pub property: usize,
+	pub tick: usize,
+	/// Mesh input values for each tick up to the violation
+	pub inputs: Vec<Vec<bool>>,
+	/// All node values for each tick up to the violation
+	pub nodes: Vec<Vec<bool>>,
+}
+
+/// Search for a property violation within `n_ticks` ticks of reset, with unconstrained mesh inputs
+pub fn prove(mesh: &flat::Mesh, n_ticks: usize) -> Result<Option<Violation>,String>
+{
+	if mesh.properties.is_empty() {
+		return Err(format!("'{}' has no #property definitions", mesh.name));
+	}
+	let mut cnf = Cnf::new();
+	let free: Vec<(u32,Vec<Lit>)> = mesh.inputs.iter()
+		.filter_map(|nr| match *nr { NodeRef::NodeId(id) => Some(id), _ => None })
+		.map(|id| (id, (0 .. n_ticks+1).map(|_| cnf.new_var()).collect()))
+		.collect();
+	let unrolled = super::unroll(&mut cnf, mesh, n_ticks, &free, false)?;
+
+	// A property is violated when all conditions are high and any value differs from the expected value
+	let mut violations = Vec::new();
+	for tick in 1 .. n_ticks+1
+	{
+		for (idx,prop) in mesh.properties.iter().enumerate()
+		{
+			let conds: Vec<Lit> = prop.conditions.iter().map(|nr| unrolled.get(&cnf, tick, *nr)).collect();
+			let diffs: Vec<Lit> = prop.values.iter().zip( prop.expected.iter() )
+				.map(|(v,e)| { let (v, e) = (unrolled.get(&cnf, tick, *v), unrolled.get(&cnf, tick, *e)); cnf.xor(v, e) })
+				.collect();
+			let cond = cnf.and(&conds);
+			let diff = cnf.or(&diffs);
+			violations.push( (tick, idx, cnf.and(&[cond, diff])) );
+		}
+	}
+	let lits: Vec<Lit> = violations.iter().map(|&(_,_,l)| l).collect();
+	let any = cnf.or(&lits);
+	cnf.add_clause( vec![any] );
+
+	info!("Property check: {} vars, {} clauses", cnf.n_vars(), cnf.clauses().len());
+	let model = match super::solve(&cnf) {
+		Some(m) => m,
+		None => return Ok(None),
+		};
+	let val = |l: Lit| model[l.var() as usize] != l.is_neg();
+
+	let &(tick, property, _) = violations.iter().find(|&&(_,_,l)| val(l)).expect("BUG: SAT model without a violation");
+	Ok(Some(Violation {
+		property,
+		tick,
+		inputs: (0 .. tick+1).map(|t| mesh.inputs.iter().map(|nr| val(unrolled.get(&cnf, t, *nr))).collect()).collect(),
+		nodes: (0 .. tick+1).map(|t| unrolled.nodes[t].iter().map(|&l| val(l)).collect()).collect(),
+		}))
+}
+
+/// Handle the `prove [UNIT] [TICKS]` command
+pub fn cmd_prove(mesh: &flat::Mesh, n_ticks: usize, vcd_path: Option<String>) -> bool
+{
+	match prove(mesh, n_ticks)
+	{
+	Err(e) => {
+		println!("Cannot check properties: {}", e);
+		false
+		},
+	Ok(None) => {
+		println!("All {} properties hold for {} ticks", mesh.properties.len(), n_ticks);
+		true
+		},
+	Ok(Some(v)) => {
+		let prop = &mesh.properties[v.property];
+		let get = |nrs: &[NodeRef]| -> Vec<bool> { nrs.iter().map(|nr| match *nr
+			{
+			NodeRef::NodeZero => false,
+			NodeRef::NodeOne => true,
+			NodeRef::NodeId(id) => v.nodes[v.tick][id as usize],
+			}).collect() };
+		println!("Property #{} (line {}) violated at tick {} - have:{:?} != exp:{:?}",
+			v.property, prop.line, v.tick, get(&prop.values), get(&prop.expected));
+		println!("Counterexample inputs:");
+		super::print_trace(mesh, &v.inputs);
+
+		if let Some(path) = vcd_path
+		{
+			let signals = ::vcd::named_nodes(mesh);
+			let names: Vec<String> = signals.iter().map(|s| s.1.clone()).collect();
+			let fp = match ::std::fs::File::create(&path) {
+				Ok(fp) => fp,
+				Err(e) => panic!("Unable to open VCD output '{}': {}", path, e),
+				};
+			let mut vcd = ::vcd::Writer::new(::std::io::BufWriter::new(fp), &mesh.name, &names).unwrap();
+			for (tick,nodes) in v.nodes.iter().enumerate() {
+				let vals: Vec<bool> = signals.iter().map(|s| nodes[s.0 as usize]).collect();
+				vcd.sample(tick as u64, &vals).unwrap();
+			}
+			println!("Trace written to '{}'", path);
+		}
+		false
+		},
+	}
+}
+
+#[test]
+fn test_prove()
+{
+	let mut root = ::test_util::load(concat!(
+		"#defunit HOLDS\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y = AND $a, $b\n",
+		"$z = OR $a, $b\n",
+		"#property $y $z 1\n",
+		"#endunit\n",
+		// $x is (a AND NOT b) delayed, so it can first go high on tick 5
+		"#defunit LATE\n",
+		"#input $a, $b\n",
+		"#output $y\n",
+		"$y = AND $a, $b\n",
+		"$z = OR $a, $b\n",
+		"$x = DELAY{3} (AND $a, (NOT $b))\n",
+		"#property $y $z 1\n",
+		"#property 1 $x 0\n",
+		"#endunit\n",
+		"#defunit NONE\n",
+		"#input $a\n",
+		"#output $y\n",
+		"$y = NOT $a\n",
+		"#endunit\n",
+		));
+	let holds = root.flatten_unit("HOLDS").unwrap();
+	let late = root.flatten_unit("LATE").unwrap();
+	let none = root.flatten_unit("NONE").unwrap();
+
+	assert!( prove(&holds, 8).unwrap().is_none() );
+	assert!( prove(&late, 4).unwrap().is_none() );
+
+	let v = prove(&late, 8).unwrap().expect("no violation");
+	assert_eq!( v.property, 1 );
+	assert_eq!( v.tick, 5 );
+	assert_eq!( v.inputs.len(), 6 );
+	assert_eq!( v.nodes.len(), 6 );
+	// The AND on tick 2 saw a from tick 1 and the inversion of b from tick 0
+	assert!( v.inputs[1][0] && !v.inputs[0][1], "inputs {:?}", v.inputs );
+
+	assert_eq!( prove(&none, 8).err(), Some("'NONE' has no #property definitions".to_string()) );
+}
+
+// vim: ft=rust
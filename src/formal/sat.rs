@@ -0,0 +1,462 @@
+//
+//
+//
+//! Small CDCL SAT solver (two watched literals, 1UIP learning, VSIDS, restarts)
+use super::Lit;
+
+// Internal literal encoding: 2*var + (1 if negated)
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+struct ILit(usize);
+impl ILit {
+	fn from_lit(l: Lit) -> ILit {
+		let v = l.var() as usize;
+		ILit( 2*v + (l.is_neg() as usize) )
+	}
+	fn var(self) -> usize { self.0 >> 1 }
+	fn neg(self) -> ILit { ILit(self.0 ^ 1) }
+	fn is_neg(self) -> bool { self.0 & 1 != 0 }
+}
+
+const UNDEF: i8 = 0;
+
+pub struct Solver
+{
+	clauses: Vec<Vec<ILit>>,
+	/// Indexed by literal, clauses where the negation of the literal is watched
+	watches: Vec<Vec<usize>>,
+	/// Per-variable assignment (1 = true, -1 = false, 0 = unassigned)
+	assigns: Vec<i8>,
+	level: Vec<usize>,
+	reason: Vec<Option<usize>>,
+	polarity: Vec<bool>,
+	trail: Vec<ILit>,
+	trail_lim: Vec<usize>,
+	qhead: usize,
+
+	activity: Vec<f64>,
+	var_inc: f64,
+	order: VarOrder,
+
+	/// Set if an empty clause was added (or derived at level zero)
+	unsat: bool,
+}
+
+/// Binary max-heap of variables ordered by activity
+struct VarOrder
+{
+	heap: Vec<usize>,
+	indices: Vec<Option<usize>>,
+}
+
+impl VarOrder
+{
+	fn contains(&self, v: usize) -> bool {
+		self.indices[v].is_some()
+	}
+	fn insert(&mut self, v: usize, act: &[f64]) {
+		if !self.contains(v) {
+			self.indices[v] = Some(self.heap.len());
+			self.heap.push(v);
+			let i = self.heap.len() - 1;
+			self.percolate_up(i, act);
+		}
+	}
+	fn pop(&mut self, act: &[f64]) -> Option<usize> {
+		if self.heap.is_empty() {
+			return None;
+		}
+		let rv = self.heap.swap_remove(0);
+		self.indices[rv] = None;
+		if !self.heap.is_empty() {
+			self.indices[self.heap[0]] = Some(0);
+			self.percolate_down(0, act);
+		}
+		Some(rv)
+	}
+	fn increased(&mut self, v: usize, act: &[f64]) {
+		if let Some(i) = self.indices[v] {
+			self.percolate_up(i, act);
+		}
+	}
+	fn percolate_up(&mut self, mut i: usize, act: &[f64]) {
+		let v = self.heap[i];
+		while i > 0 {
+			let parent = (i - 1) / 2;
+			if act[self.heap[parent]] >= act[v] {
+				break;
+			}
+			self.heap[i] = self.heap[parent];
+			self.indices[self.heap[i]] = Some(i);
+			i = parent;
+		}
+		self.heap[i] = v;
+		self.indices[v] = Some(i);
+	}
+	fn percolate_down(&mut self, mut i: usize, act: &[f64]) {
+		let v = self.heap[i];
+		loop {
+			let l = 2*i + 1;
+			if l >= self.heap.len() {
+				break;
+			}
+			let r = l + 1;
+			let child = if r < self.heap.len() && act[self.heap[r]] > act[self.heap[l]] { r } else { l };
+			if act[self.heap[child]] <= act[v] {
+				break;
+			}
+			self.heap[i] = self.heap[child];
+			self.indices[self.heap[i]] = Some(i);
+			i = child;
+		}
+		self.heap[i] = v;
+		self.indices[v] = Some(i);
+	}
+}
+
+/// Luby restart sequence (1,1,2,1,1,2,4,...)
+fn luby(mut x: u64) -> u64
+{
+	let mut size = 1;
+	let mut seq = 0;
+	while size < x + 1 {
+		seq += 1;
+		size = 2*size + 1;
+	}
+	while size - 1 != x {
+		size = (size - 1) >> 1;
+		seq -= 1;
+		x %= size;
+	}
+	1 << seq
+}
+
+impl Solver
+{
+	/// Create a solver for variables 1 ..= n_vars
+	pub fn new(n_vars: u32) -> Solver
+	{
+		let n = n_vars as usize + 1;
+		let mut rv = Solver {
+			clauses: Vec::new(),
+			watches: (0 .. 2*n).map(|_| Vec::new()).collect(),
+			assigns: ::from_elem(n, UNDEF),
+			level: ::from_elem(n, 0),
+			reason: ::from_elem(n, None),
+			polarity: ::from_elem(n, true),
+			trail: Vec::new(),
+			trail_lim: Vec::new(),
+			qhead: 0,
+			activity: ::from_elem(n, 0.0),
+			var_inc: 1.0,
+			order: VarOrder { heap: Vec::new(), indices: ::from_elem(n, None) },
+			unsat: false,
+		};
+		for v in 1 .. n {
+			rv.order.insert(v, &rv.activity);
+		}
+		rv
+	}
+
+	fn value(&self, l: ILit) -> i8 {
+		let a = self.assigns[l.var()];
+		if l.is_neg() { -a } else { a }
+	}
+	fn decision_level(&self) -> usize {
+		self.trail_lim.len()
+	}
+	fn enqueue(&mut self, l: ILit, reason: Option<usize>) {
+		let v = l.var();
+		self.assigns[v] = if l.is_neg() { -1 } else { 1 };
+		self.level[v] = self.decision_level();
+		self.reason[v] = reason;
+		self.trail.push(l);
+	}
+
+	pub fn add_clause(&mut self, clause: &[Lit])
+	{
+		if self.unsat {
+			return ;
+		}
+		assert!(self.decision_level() == 0);
+		let mut c: Vec<ILit> = Vec::with_capacity(clause.len());
+		for l in clause.iter().map(|&l| ILit::from_lit(l))
+		{
+			match self.value(l)
+			{
+			1 => return,	// Already satisfied
+			-1 => {},	// Already false, drop
+			_ => {
+				if c.contains(&l.neg()) {
+					return ;	// Tautology
+				}
+				if !c.contains(&l) {
+					c.push(l);
+				}
+				},
+			}
+		}
+		match c.len()
+		{
+		0 => { self.unsat = true; },
+		1 => {
+			self.enqueue(c[0], None);
+			if self.propagate().is_some() {
+				self.unsat = true;
+			}
+			},
+		_ => { self.attach(c); },
+		}
+	}
+	fn attach(&mut self, c: Vec<ILit>) -> usize {
+		let idx = self.clauses.len();
+		self.watches[c[0].neg().0].push(idx);
+		self.watches[c[1].neg().0].push(idx);
+		self.clauses.push(c);
+		idx
+	}
+
+	/// Unit propagation, returns the index of a conflicting clause
+	fn propagate(&mut self) -> Option<usize>
+	{
+		while self.qhead < self.trail.len()
+		{
+			let p = self.trail[self.qhead];
+			self.qhead += 1;
+			let false_lit = p.neg();
+			let ws = ::std::mem::take(&mut self.watches[p.0]);
+			let mut kept = Vec::with_capacity(ws.len());
+			let mut conflict = None;
+			let mut it = ws.into_iter();
+			while let Some(ci) = it.next()
+			{
+				// Ensure the false literal is in slot 1
+				if self.clauses[ci][0] == false_lit {
+					self.clauses[ci].swap(0, 1);
+				}
+				let first = self.clauses[ci][0];
+				if self.value(first) == 1 {
+					kept.push(ci);
+					continue ;
+				}
+				// Look for a new literal to watch
+				let mut found = None;
+				for k in 2 .. self.clauses[ci].len() {
+					if self.value(self.clauses[ci][k]) != -1 {
+						found = Some(k);
+						break;
+					}
+				}
+				if let Some(k) = found {
+					self.clauses[ci].swap(1, k);
+					let w = self.clauses[ci][1].neg();
+					self.watches[w.0].push(ci);
+					continue ;
+				}
+				// Clause is unit or conflicting
+				kept.push(ci);
+				if self.value(first) == -1 {
+					conflict = Some(ci);
+					kept.extend(it);
+					break;
+				}
+				self.enqueue(first, Some(ci));
+			}
+			self.watches[p.0] = kept;
+			if conflict.is_some() {
+				self.qhead = self.trail.len();
+				return conflict;
+			}
+		}
+		None
+	}
+
+	fn bump(&mut self, v: usize) {
+		self.activity[v] += self.var_inc;
+		if self.activity[v] > 1e100 {
+			for a in self.activity.iter_mut() {
+				*a *= 1e-100;
+			}
+			self.var_inc *= 1e-100;
+		}
+		self.order.increased(v, &self.activity);
+	}
+
+	/// First-UIP conflict analysis, returns the learnt clause and the level to backtrack to
+	fn analyze(&mut self, mut confl: usize) -> (Vec<ILit>, usize)
+	{
+		let mut seen: Vec<bool> = ::from_elem(self.assigns.len(), false);
+		let mut learnt = vec![ ILit(0) ];
+		let mut path_count = 0;
+		let mut p: Option<ILit> = None;
+		let mut index = self.trail.len();
+		loop
+		{
+			let start = if p.is_none() { 0 } else { 1 };
+			for j in start .. self.clauses[confl].len()
+			{
+				let q = self.clauses[confl][j];
+				let v = q.var();
+				if !seen[v] && self.level[v] > 0
+				{
+					seen[v] = true;
+					self.bump(v);
+					if self.level[v] >= self.decision_level() {
+						path_count += 1;
+					}
+					else {
+						learnt.push(q);
+					}
+				}
+			}
+			// Select the next literal on the trail to resolve on
+			loop {
+				index -= 1;
+				if seen[self.trail[index].var()] {
+					break;
+				}
+			}
+			let pl = self.trail[index];
+			seen[pl.var()] = false;
+			path_count -= 1;
+			p = Some(pl);
+			if path_count == 0 {
+				break;
+			}
+			confl = self.reason[pl.var()].expect("BUG: Non-decision literal without reason");
+		}
+		learnt[0] = p.unwrap().neg();
+
+		let mut bt_level = 0;
+		if learnt.len() > 1 {
+			let mut max_i = 1;
+			for i in 2 .. learnt.len() {
+				if self.level[learnt[i].var()] > self.level[learnt[max_i].var()] {
+					max_i = i;
+				}
+			}
+			learnt.swap(1, max_i);
+			bt_level = self.level[learnt[1].var()];
+		}
+		(learnt, bt_level)
+	}
+
+	fn cancel_until(&mut self, level: usize)
+	{
+		if self.decision_level() > level
+		{
+			let lim = self.trail_lim[level];
+			for i in (lim .. self.trail.len()).rev() {
+				let v = self.trail[i].var();
+				self.polarity[v] = self.trail[i].is_neg();
+				self.assigns[v] = UNDEF;
+				self.reason[v] = None;
+				self.order.insert(v, &self.activity);
+			}
+			self.trail.truncate(lim);
+			self.trail_lim.truncate(level);
+			self.qhead = lim;
+		}
+	}
+
+	fn pick_branch(&mut self) -> Option<ILit>
+	{
+		while let Some(v) = self.order.pop(&self.activity)
+		{
+			if self.assigns[v] == UNDEF {
+				return Some( ILit(2*v + self.polarity[v] as usize) );
+			}
+		}
+		None
+	}
+
+	/// Solve the formula, returning a model (indexed by variable number) if satisfiable
+	pub fn solve(&mut self) -> Option<Vec<bool>>
+	{
+		if self.unsat {
+			return None;
+		}
+		if self.propagate().is_some() {
+			return None;
+		}
+		let mut n_restarts = 0;
+		loop
+		{
+			let limit = luby(n_restarts) * 100;
+			let mut n_conflicts = 0;
+			loop
+			{
+				if let Some(confl) = self.propagate()
+				{
+					if self.decision_level() == 0 {
+						self.unsat = true;
+						return None;
+					}
+					n_conflicts += 1;
+					let (learnt, bt_level) = self.analyze(confl);
+					self.cancel_until(bt_level);
+					if learnt.len() == 1 {
+						self.enqueue(learnt[0], None);
+					}
+					else {
+						let first = learnt[0];
+						let ci = self.attach(learnt);
+						self.enqueue(first, Some(ci));
+					}
+					self.var_inc /= 0.95;
+				}
+				else if n_conflicts >= limit
+				{
+					self.cancel_until(0);
+					break;
+				}
+				else
+				{
+					match self.pick_branch()
+					{
+					Some(l) => {
+						self.trail_lim.push(self.trail.len());
+						self.enqueue(l, None);
+						},
+					None => {
+						let model = self.assigns.iter().map(|&a| a == 1).collect();
+						self.cancel_until(0);
+						return Some(model);
+						},
+					}
+				}
+			}
+			n_restarts += 1;
+		}
+	}
+}
+
+#[test]
+fn test_solver()
+{
+	use super::Lit;
+	// Pigeonhole: 4 pigeons in 3 holes is unsatisfiable
+	let var = |p: i32, h: i32| Lit::from_dimacs(p*3 + h + 1);
+	let mut s = Solver::new(12);
+	for p in 0 .. 4 {
+		s.add_clause(&[var(p,0), var(p,1), var(p,2)]);
+	}
+	for h in 0 .. 3 {
+		for p1 in 0 .. 4 {
+			for p2 in p1+1 .. 4 {
+				s.add_clause(&[!var(p1,h), !var(p2,h)]);
+			}
+		}
+	}
+	assert!( s.solve().is_none() );
+
+	// (a | b) & (!a | b) & (a | !b) => a & b
+	let mut s = Solver::new(2);
+	let (a, b) = (Lit::from_dimacs(1), Lit::from_dimacs(2));
+	s.add_clause(&[a, b]);
+	s.add_clause(&[!a, b]);
+	s.add_clause(&[a, !b]);
+	let m = s.solve().unwrap();
+	assert!( m[1] && m[2] );
+}
+
+// vim: ft=rust
@@ -0,0 +1,370 @@
+//
+//
+//
+//! Randomised stimulus fuzzing of a unit (`fuzz UNIT [TICKS] [RUNS]`)
+//!
+//! Each run resets the unit and drives its inputs with random values for a number of ticks, checking
+//! `#property` assertions and `#breakpoint`s marked as `error`. The first failing input sequence is shrunk
+//! (fewer ticks, simpler values) before being reported.
+//!
+//! Inputs can be constrained with `NAME=SPEC` (`--fuzz-constraint`), where SPEC is one of
+//! - `V` - always `V`
+//! - `LO..HI` - uniform in `LO` to `HI` (inclusive)
+//! - `A|B|C` - one of the listed values
+//! - `P%` - each bit set with probability `P` percent
+use cct_mesh::flat::{self, NodeRef};
+use rng::Rng;
+
+pub struct Options
+{
+	pub n_ticks: usize,
+	pub n_runs: usize,
+	pub seed: u64,
+	pub constraints: Vec<String>,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Constraint
+{
+	Const(u64),
+	Range(u64, u64),
+	Choice(Vec<u64>),
+	Percent(u64),
+}
+
+/// A mesh input line or group (a contiguous run of `mesh.inputs`)
+struct Signal
+{
+	name: String,
+	first: usize,
+	width: usize,
+	constraint: Option<Constraint>,
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum FailureKind
+{
+	/// Index into `mesh.properties`
+	Property(usize),
+	/// Index into `mesh.breakpoints`
+	Breakpoint(usize),
+}
+struct Failure
+{
+	kind: FailureKind,
+	tick: usize,
+	message: String,
+}
+
+/// Input values (one per signal) for each tick
+type Sequence = Vec<Vec<u64>>;
+
+fn parse_number(s: &str) -> Result<u64,String>
+{
+	let s = s.trim();
+	match s.strip_prefix("0x")
+	{
+	Some(hex) => u64::from_str_radix(hex, 16),
+	None => s.parse(),
+	}.map_err(|e| format!("Bad value '{}': {}", s, e))
+}
+
+impl Constraint
+{
+	fn parse(spec: &str) -> Result<Constraint,String>
+	{
+		if let Some(p) = spec.strip_suffix('%') {
+			let p = parse_number(p)?;
+			if p > 100 {
+				return Err(format!("Probability {}% out of range", p));
+			}
+			Ok( Constraint::Percent(p) )
+		}
+		else if let Some((lo, hi)) = spec.split_once("..") {
+			let (lo, hi) = (parse_number(lo)?, parse_number(hi)?);
+			if lo > hi {
+				return Err(format!("Empty range {}..{}", lo, hi));
+			}
+			Ok( Constraint::Range(lo, hi) )
+		}
+		else if spec.contains('|') {
+			Ok( Constraint::Choice(spec.split('|').map(parse_number).collect::<Result<_,_>>()?) )
+		}
+		else {
+			Ok( Constraint::Const(parse_number(spec)?) )
+		}
+	}
+	/// Largest value the constraint can produce
+	fn max(&self) -> u64
+	{
+		match *self
+		{
+		Constraint::Const(v) => v,
+		Constraint::Range(_, hi) => hi,
+		Constraint::Choice(ref vs) => vs.iter().cloned().max().unwrap_or(0),
+		Constraint::Percent(_) => 0,
+		}
+	}
+}
+
+impl Signal
+{
+	fn mask(&self) -> u64
+	{
+		!0u64 >> (64 - self.width)
+	}
+	fn generate(&self, rng: &mut Rng) -> u64
+	{
+		match self.constraint
+		{
+		None => rng.next_u64() & self.mask(),
+		Some(Constraint::Const(v)) => v,
+		Some(Constraint::Range(lo, hi)) => match (hi - lo).checked_add(1) {
+			Some(n) => lo + rng.below(n),
+			None => rng.next_u64(),
+			},
+		Some(Constraint::Choice(ref vs)) => vs[rng.below(vs.len() as u64) as usize],
+		Some(Constraint::Percent(p)) => (0 .. self.width).filter(|_| rng.below(100) < p).fold(0, |v,b| v | 1 << b),
+		}
+	}
+	/// Simplest value allowed by the constraint (used when shrinking)
+	fn simplest(&self) -> u64
+	{
+		match self.constraint
+		{
+		None | Some(Constraint::Percent(_)) => 0,
+		Some(Constraint::Const(v)) => v,
+		Some(Constraint::Range(lo, _)) => lo,
+		Some(Constraint::Choice(ref vs)) => vs[0],
+		}
+	}
+	/// Returns true if any bit pattern is allowed (so single bits can be cleared when shrinking)
+	fn is_free(&self) -> bool
+	{
+		matches!(self.constraint, None | Some(Constraint::Percent(_)))
+	}
+}
+
+/// Group the mesh inputs into lines and groups, and apply constraints
+fn get_signals(mesh: &flat::Mesh, constraints: &[String]) -> Result<Vec<Signal>,String>
+{
+	let mut signals: Vec<Signal> = Vec::new();
+	for (i,nr) in mesh.inputs.iter().enumerate()
+	{
+		let name = match *nr {
+			NodeRef::NodeId(id) => mesh.node_name(id),
+			_ => return Err(format!("Input #{} is tied to a constant", i)),
+			};
+		if let Some((group, idx)) = flat::split_group_member(&name)
+		{
+			let group = format!("@{}", group);
+			match signals.last_mut()
+			{
+			Some(s) if s.name == group && s.width == idx => { s.width += 1; continue },
+			_ => {},
+			}
+			signals.push( Signal { name: group, first: i, width: 1, constraint: None } );
+		}
+		else
+		{
+			signals.push( Signal { name: format!("${}", name), first: i, width: 1, constraint: None } );
+		}
+	}
+	if let Some(s) = signals.iter().find(|s| s.width > 64) {
+		return Err(format!("Input {} is wider than 64 bits", s.name));
+	}
+
+	for c in constraints.iter()
+	{
+		let (name, spec) = c.split_once('=').ok_or_else(|| format!("Constraint '{}' should be NAME=SPEC", c))?;
+		let sig = signals.iter_mut()
+			.find(|s| s.name == name || s.name[1..] == *name)
+			.ok_or_else(|| format!("Constraint '{}': {} is not an input of '{}'", c, name, mesh.name))?;
+		let constraint = Constraint::parse(spec).map_err(|e| format!("Constraint '{}': {}", c, e))?;
+		if sig.width < 64 && constraint.max() >> sig.width != 0 {
+			return Err(format!("Constraint '{}': {:#x} doesn't fit in {} ({} bits)", c, constraint.max(), sig.name, sig.width));
+		}
+		sig.constraint = Some(constraint);
+	}
+	Ok(signals)
+}
+
+/// Check properties and error breakpoints
+fn check(sim: &::simulator::Engine, mesh: &flat::Mesh) -> Option<(FailureKind, String)>
+{
+	for (i,prop) in mesh.properties.iter().enumerate()
+	{
+		if sim.are_set(&prop.conditions, true)
+		{
+			let have = sim.get_values(&prop.values);
+			let exp  = sim.get_values(&prop.expected);
+			if have != exp {
+				return Some( (FailureKind::Property(i), format!("Property #{} (line {}) violated - have:{:?} != exp:{:?}", i, prop.line, have, exp)) );
+			}
+		}
+	}
+	for (i,bp) in mesh.breakpoints.iter().enumerate()
+	{
+		if bp.error && sim.are_set(&bp.conds, true) {
+			return Some( (FailureKind::Breakpoint(i), format!("Error breakpoint '{}' hit", bp.name)) );
+		}
+	}
+	None
+}
+
+/// Run a sequence from reset, returning the first failure
+fn run_sequence(sim: &mut ::simulator::Engine, mesh: &flat::Mesh, signals: &[Signal], seq: &Sequence) -> Option<Failure>
+{
+	sim.reset();
+	for (tick,vals) in seq.iter().enumerate()
+	{
+		for (sig,&v) in signals.iter().zip(vals.iter())
+		{
+			for bit in 0 .. sig.width
+			{
+				if let NodeRef::NodeId(id) = mesh.inputs[sig.first + bit] {
+					sim.force_node(id, (v >> bit) & 1 != 0);
+				}
+			}
+		}
+		sim.tick();
+		if let Some((kind, message)) = check(sim, mesh) {
+			return Some(Failure { kind, tick, message });
+		}
+	}
+	None
+}
+
+/// Shrink a failing sequence, keeping the same failure
+fn shrink(sim: &mut ::simulator::Engine, mesh: &flat::Mesh, signals: &[Signal], mut seq: Sequence, kind: FailureKind) -> Sequence
+{
+	// Returns the candidate (truncated after the failure) if it still fails in the same way
+	let mut attempt = |mut cand: Sequence| match run_sequence(sim, mesh, signals, &cand)
+		{
+		Some(ref f) if f.kind == kind => { cand.truncate(f.tick + 1); Some(cand) },
+		_ => None,
+		};
+	loop
+	{
+		let prev = seq.clone();
+
+		// Remove runs of ticks, largest first
+		let mut chunk = seq.len() / 2;
+		while chunk > 0
+		{
+			let mut start = 0;
+			while start + chunk <= seq.len()
+			{
+				let cand = seq[..start].iter().chain(seq[start + chunk ..].iter()).cloned().collect();
+				match attempt(cand)
+				{
+				Some(s) => seq = s,
+				None => start += chunk,
+				}
+			}
+			chunk /= 2;
+		}
+
+		// Simplify values
+		for tick in 0 .. seq.len()
+		{
+			for (i,sig) in signals.iter().enumerate()
+			{
+				if tick >= seq.len() {
+					break;
+				}
+				if seq[tick][i] != sig.simplest() {
+					let mut cand = seq.clone();
+					cand[tick][i] = sig.simplest();
+					if let Some(s) = attempt(cand) {
+						seq = s;
+						continue ;
+					}
+				}
+				if sig.is_free() {
+					for bit in (0 .. sig.width).rev() {
+						if tick < seq.len() && (seq[tick][i] >> bit) & 1 != 0 {
+							let mut cand = seq.clone();
+							cand[tick][i] &= !(1 << bit);
+							if let Some(s) = attempt(cand) {
+								seq = s;
+							}
+						}
+					}
+				}
+			}
+		}
+
+		if seq == prev {
+			return seq;
+		}
+	}
+}
+
+/// Handle the `fuzz UNIT [TICKS] [RUNS]` command
+pub fn cmd_fuzz(mesh: &flat::Mesh, opts: &Options) -> bool
+{
+	let signals = match get_signals(mesh, &opts.constraints) {
+		Ok(s) => s,
+		Err(e) => {
+			println!("Cannot fuzz '{}': {}", mesh.name, e);
+			return false;
+			},
+		};
+	let n_errors = mesh.breakpoints.iter().filter(|bp| bp.error).count();
+	if mesh.properties.is_empty() && n_errors == 0 {
+		println!("'{}' has no #property assertions or error #breakpoints to check", mesh.name);
+		return false;
+	}
+	println!("Fuzzing '{}' ({} properties, {} error breakpoints) with {} runs of {} ticks, seed {}",
+		mesh.name, mesh.properties.len(), n_errors, opts.n_runs, opts.n_ticks, opts.seed);
+
+	let mut rng = Rng::new(opts.seed);
+	let mut sim = ::simulator::Engine::new(mesh);
+	for run in 0 .. opts.n_runs
+	{
+		let seq: Sequence = (0 .. opts.n_ticks)
+			.map(|_| signals.iter().map(|s| s.generate(&mut rng)).collect())
+			.collect();
+		let failure = match run_sequence(&mut sim, mesh, &signals, &seq) {
+			Some(f) => f,
+			None => continue,
+			};
+		println!("Run {} failed at tick {}: {}", run, failure.tick, failure.message);
+
+		let seq = shrink(&mut sim, mesh, &signals, seq, failure.kind);
+		let failure = run_sequence(&mut sim, mesh, &signals, &seq).expect("BUG - Shrunk sequence doesn't fail");
+		println!("Shrunk to {} ticks, failing at tick {}: {}", seq.len(), failure.tick, failure.message);
+		println!("Inputs:");
+		let bits: Vec<Vec<bool>> = seq.iter()
+			.map(|vals| signals.iter().zip(vals.iter()).flat_map(|(s,&v)| (0 .. s.width).map(move |b| (v >> b) & 1 != 0)).collect())
+			.collect();
+		::formal::print_trace(mesh, &bits);
+		// Constraints are quoted, as they often contain `$` and `|`
+		let constraints: String = opts.constraints.iter().map(|c| format!(" --fuzz-constraint '{}'", c)).collect();
+		println!("Reproduce with: fuzz {} {} {} --fuzz-seed {}{}", mesh.name, opts.n_ticks, run + 1, opts.seed, constraints);
+		return false;
+	}
+	println!("No failures found");
+	true
+}
+
+#[test]
+fn test_constraints()
+{
+	assert_eq!( Constraint::parse("5"), Ok(Constraint::Const(5)) );
+	assert_eq!( Constraint::parse("0x10..0x1F"), Ok(Constraint::Range(16, 31)) );
+	assert_eq!( Constraint::parse("1|2|4"), Ok(Constraint::Choice(vec![1, 2, 4])) );
+	assert_eq!( Constraint::parse("10%"), Ok(Constraint::Percent(10)) );
+	assert!( Constraint::parse("5..4").is_err() );
+	assert!( Constraint::parse("1|x").is_err() );
+
+	let mut rng = Rng::new(1);
+	let sig = Signal { name: "@op".to_string(), first: 0, width: 4, constraint: Some(Constraint::Range(3, 5)) };
+	assert!( (0 .. 100).map(|_| sig.generate(&mut rng)).all(|v| (3 ..= 5).contains(&v)) );
+	assert_eq!( sig.simplest(), 3 );
+	let sig = Signal { name: "$en".to_string(), first: 0, width: 1, constraint: Some(Constraint::Percent(0)) };
+	assert!( (0 .. 100).all(|_| sig.generate(&mut rng) == 0) );
+}
+
+// vim: ft=rust
@@ -0,0 +1,215 @@
+//
+//
+//
+//! Golden-output regression checks of `#display` logs (`--golden` / `--bless`)
+
+/// Lines of context around each change in a diff
+const CONTEXT: usize = 3;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Op
+{
+	Same,
+	Delete,
+	Insert,
+}
+
+/// Most edits (inserted plus deleted lines) searched for by `diff_lines`, as the search needs O(D^2) memory
+const MAX_EDITS: usize = 2000;
+
+/// Line-level edit script from `a` to `b`, with the position in each at every edit
+///
+/// Uses Myers' O(ND) algorithm, returns the index of the first differing line if there are more than
+/// `MAX_EDITS` changes.
+fn diff_lines(a: &[&str], b: &[&str]) -> Result<Vec<(Op, usize, usize)>,usize>
+{
+	// Only the middle section (after trimming common lines) needs to be searched
+	let pre = a.iter().zip(b.iter()).take_while(|&(x,y)| x == y).count();
+	let suf = a[pre..].iter().rev().zip(b[pre..].iter().rev()).take_while(|&(x,y)| x == y).count();
+	let (ma, mb) = (&a[pre .. a.len() - suf], &b[pre .. b.len() - suf]);
+	let (n, m) = (ma.len() as isize, mb.len() as isize);
+
+	// v[k] = Furthest `x` reached on diagonal `k` (= x - y), `trace[d]` is `v` over -d..=d after `d` edits
+	let ofs = n + m + 1;
+	let mut v = vec![0isize; (2*ofs + 1) as usize];
+	let mut trace: Vec<Vec<isize>> = Vec::new();
+	let v_at = |v: &[isize], k: isize| v[(k + ofs) as usize];
+	let prev_diag = |v: &[isize], k: isize, d: isize| if k == -d || (k != d && v_at(v, k-1) < v_at(v, k+1)) { k+1 } else { k-1 };
+	'search: for d in 0 ..= n + m
+	{
+		if d as usize > MAX_EDITS {
+			return Err(pre);
+		}
+		for k in (-d ..= d).step_by(2)
+		{
+			let mut x = if d == 0 { 0 } else if prev_diag(&v, k, d) == k+1 { v_at(&v, k+1) } else { v_at(&v, k-1) + 1 };
+			while x < n && x - k < m && ma[x as usize] == mb[(x - k) as usize] {
+				x += 1;
+			}
+			v[(k + ofs) as usize] = x;
+			if x >= n && x - k >= m {
+				trace.push( v[(ofs - d) as usize ..= (ofs + d) as usize].to_vec() );
+				break 'search;
+			}
+		}
+		trace.push( v[(ofs - d) as usize ..= (ofs + d) as usize].to_vec() );
+	}
+
+	// Walk back through the trace, from the end of both inputs
+	let mut edits = Vec::new();
+	let (mut x, mut y) = (n, m);
+	for d in (0 .. trace.len() as isize).rev()
+	{
+		let k = x - y;
+		let (prev_x, prev_y) = if d == 0 {
+				(0, 0)
+			}
+			else {
+				// `trace[d-1]` starts at diagonal -(d-1)
+				let pv = &trace[(d - 1) as usize];
+				let at = |k: isize| pv[(k + d - 1) as usize];
+				let pk = if k == -d || (k != d && at(k-1) < at(k+1)) { k+1 } else { k-1 };
+				(at(pk), at(pk) - pk)
+			};
+		while x > prev_x && y > prev_y
+		{
+			x -= 1;
+			y -= 1;
+			edits.push( (Op::Same, x, y) );
+		}
+		if d > 0
+		{
+			if x == prev_x {
+				edits.push( (Op::Insert, prev_x, prev_y) );
+			}
+			else {
+				edits.push( (Op::Delete, prev_x, prev_y) );
+			}
+		}
+		x = prev_x;
+		y = prev_y;
+	}
+
+	let mut rv: Vec<_> = (0 .. pre).map(|i| (Op::Same, i, i)).collect();
+	rv.extend( edits.into_iter().rev().map(|(op, i, j)| (op, pre + i as usize, pre + j as usize)) );
+	rv.extend( (0 .. suf).map(|k| (Op::Same, a.len() - suf + k, b.len() - suf + k)) );
+	Ok(rv)
+}
+
+/// Unified diff (as `diff -u`) between two texts, empty if they have the same lines
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String
+{
+	let a: Vec<&str> = old.lines().collect();
+	let b: Vec<&str> = new.lines().collect();
+	let ops = match diff_lines(&a, &b)
+		{
+		Ok(ops) => ops,
+		Err(line) => return format!("--- {}\n+++ {}\nToo many changes to show, the first is at line {}\n", old_name, new_name, line + 1),
+		};
+
+	let mut rv = String::new();
+	let mut pos = 0;
+	while let Some(first) = ops[pos..].iter().position(|op| op.0 != Op::Same).map(|p| pos + p)
+	{
+		// Extend the hunk until the next change is too far away to share context
+		let mut last = first;
+		for (j,op) in ops.iter().enumerate().skip(first)
+		{
+			if op.0 != Op::Same {
+				last = j;
+			}
+			else if j - last > 2*CONTEXT {
+				break;
+			}
+		}
+		let start = first.saturating_sub(CONTEXT);
+		let end = ::std::cmp::min(last + 1 + CONTEXT, ops.len());
+		let hunk = &ops[start .. end];
+
+		let n_old = hunk.iter().filter(|op| op.0 != Op::Insert).count();
+		let n_new = hunk.iter().filter(|op| op.0 != Op::Delete).count();
+		// Empty ranges give the line before the change (as diff does)
+		let line = |idx: usize, n: usize| if n == 0 { idx } else { idx + 1 };
+		if rv.is_empty() {
+			rv.push_str( &format!("--- {}\n+++ {}\n", old_name, new_name) );
+		}
+		rv.push_str( &format!("@@ -{},{} +{},{} @@\n", line(hunk[0].1, n_old), n_old, line(hunk[0].2, n_new), n_new) );
+		for &(op, i, j) in hunk
+		{
+			let (c, text) = match op
+				{
+				Op::Same => (' ', a[i]),
+				Op::Delete => ('-', a[i]),
+				Op::Insert => ('+', b[j]),
+				};
+			rv.push(c);
+			rv.push_str(text);
+			rv.push('\n');
+		}
+		pos = end;
+	}
+	rv
+}
+
+/// Compare a display log against the golden file at `path`, printing a diff if they differ
+///
+/// With `bless`, the golden file is (re)written instead.
+pub fn check(path: &str, actual: &str, bless: bool) -> bool
+{
+	if bless
+	{
+		return match ::std::fs::write(path, actual)
+			{
+			Ok(()) => { println!("Updated golden file '{}'", path); true },
+			Err(e) => { println!("Unable to write golden file '{}': {}", path, e); false },
+			};
+	}
+
+	let expected = match ::std::fs::read_to_string(path)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			println!("Unable to read golden file '{}': {} (use --bless to create it)", path, e);
+			return false;
+			},
+		};
+	let diff = unified_diff(&expected, actual, path, "(display output)");
+	if diff.is_empty() {
+		println!("Display output matches '{}'", path);
+		true
+	}
+	else {
+		println!("Display output differs from '{}' (use --bless to accept):", path);
+		print!("{}", diff);
+		false
+	}
+}
+
+#[test]
+fn test_unified_diff()
+{
+	let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+	assert_eq!( unified_diff(old, old, "x", "y"), "" );
+	let new = "a\nb\nC\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\nn\n";
+	assert_eq!( unified_diff(old, new, "x", "y"), concat!(
+		"--- x\n+++ y\n",
+		"@@ -1,6 +1,6 @@\n a\n b\n-c\n+C\n d\n e\n f\n",
+		"@@ -11,3 +11,4 @@\n k\n l\n m\n+n\n",
+		) );
+	assert_eq!( unified_diff("", "a\n", "x", "y"), "--- x\n+++ y\n@@ -0,0 +1,1 @@\n+a\n" );
+	
+	// Shortest edit script (the example from Myers' paper needs five edits)
+	let (a, b) = (["a", "b", "c", "a", "b", "b", "a"], ["c", "b", "a", "b", "a", "c"]);
+	let ops = diff_lines(&a, &b).unwrap();
+	assert_eq!( ops.iter().filter(|op| op.0 != Op::Same).count(), 5 );
+	let kept: Vec<_> = ops.iter().filter(|op| op.0 == Op::Same).map(|op| (a[op.1], b[op.2])).collect();
+	assert!( kept.iter().all(|&(x,y)| x == y) );
+	assert_eq!( ops.iter().filter(|op| op.0 != Op::Delete).map(|op| if op.0 == Op::Same { a[op.1] } else { b[op.2] }).collect::<Vec<_>>(), b );
+	
+	// Too many changes are only reported by the first differing line
+	let old: String = (0 .. 3000).map(|i| format!("a{}\n", i)).collect();
+	let new: String = (0 .. 3000).map(|i| if i < 2 { format!("a{}\n", i) } else { format!("b{}\n", i) }).collect();
+	assert_eq!( unified_diff(&old, &new, "x", "y"), "--- x\n+++ y\nToo many changes to show, the first is at line 3\n" );
+}
+
+// vim: ft=rust
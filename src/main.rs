@@ -16,7 +16,24 @@ mod cct_mesh;
 mod parse;
 mod elements;
 mod simulator;
+mod coverage;
+mod faultsim;
+mod rng;
+mod formal;
+mod vcd;
+mod display;
+mod tui;
+mod waveform;
+mod fuzz;
+mod golden;
+mod console;
+#[cfg(test)]
+mod test_util;
 
+/// Exit code when free-running with a stop condition and the tick limit is reached first
+const EXIT_TIMEOUT: i32 = 2;
+
+#[derive(Debug,PartialEq)]
 enum TestStatus
 {
 	Pass(u32),
@@ -36,7 +53,38 @@ fn main()
 	opts.optflag("h", "help", "Print help text");
 	opts.optflag("", "test", "Run tests");
 	opts.optopt("", "test-glob", "Run tests matching glob", "GLOB");
+	opts.optflag("", "list-tests", "List the selected tests (with their tags) instead of running them");
+	opts.optmulti("", "tag", "Only run tests with this tag", "TAG");
+	opts.optmulti("", "exclude-tag", "Don't run tests with this tag", "TAG");
+	opts.optflag("", "rerun-failed", "Only run tests that failed on the previous run");
+	opts.optopt("", "test-state", "Record failed tests in this file for --rerun-failed (default FILE.failed, only kept with --rerun-failed)", "PATH");
 	opts.optflag("", "test-display", "Print display items during tests");
+	opts.optmulti("", "display-block", "Only print display items from this #block (and blocks nested in it)", "BLOCK");
+	opts.optmulti("", "hide-block", "Don't print display items from this #block", "BLOCK");
+	opts.optopt("", "golden", "Capture display output (with tick numbers) and compare it against this file", "FILE");
+	opts.optflag("", "bless", "Write the captured display output to the --golden file instead of comparing");
+	opts.optopt("", "fail-history", "Ticks of history to show in assertion failure waveforms (default 16, 0 to disable)", "TICKS");
+	opts.optopt("", "wave-style", "Failure waveform style: blocks (default) or lines", "STYLE");
+	opts.optflag("", "coverage", "Collect toggle/select coverage during tests");
+	opts.optopt("", "coverage-json", "Write the coverage report as JSON", "FILE");
+	opts.optopt("", "coverage-threshold", "Fail if total coverage is below this percentage", "PCT");
+	opts.optopt("", "faults", "Run stuck-at fault simulation on a unit using the selected tests", "UNIT");
+	opts.optopt("", "fault-sample", "Only simulate a random sample of faults", "COUNT");
+	opts.optopt("", "fault-seed", "Seed for fault sampling", "SEED");
+	opts.optopt("", "fuzz-seed", "Seed for the fuzz command (default: time based)", "SEED");
+	opts.optmulti("", "fuzz-constraint", "Constrain a fuzzed input: NAME=V, NAME=LO..HI, NAME=A|B|C or NAME=P%", "NAME=SPEC");
+	opts.optopt("", "vcd", "Write counterexample traces to a VCD file", "FILE");
+	opts.optopt("", "mem-dump", "Write memory contents at the end of simulation and on breakpoints", "FILE");
+	opts.optflag("", "free-unsupported", "Model elements that can't be encoded to CNF as free inputs");
+	opts.optopt("", "cycles", "Ticks to run for when free-running (default 30, 0 for no limit)", "TICKS");
+	opts.optmulti("", "until", "Stop free-running when this #breakpoint is hit", "NAME");
+	opts.optflag("", "stop-on-breakpoint", "Stop free-running when any #breakpoint is hit");
+	opts.optopt("", "until-expr", "Stop free-running when all of these values are high (as for #testcomplete)", "VALUES");
+	opts.optflag("", "print-state", "Print the value of every named line and group when free-running stops");
+	opts.optflag("", "tui", "Show a live terminal view of the simulation");
+	opts.optmulti("", "watch", "Line or group to show the value of in the terminal view", "NAME");
+	opts.optmulti("", "wave", "Line to show the waveform of in the terminal view", "NAME");
+	opts.optopt("", "tui-every", "Ticks per terminal view update (default 1)", "TICKS");
 
 	//println!("> opts = ");
 	let args_s: Vec<_> = ::std::env::args().collect();
@@ -58,85 +106,388 @@ fn main()
 	}
 	
 	// 2. Load circuit file
-	let mut mesh = match parse::load( &args.free[0] ) {
+	let registry = elements::ElementRegistry::default();
+	let mut mesh = match parse::load( &args.free[0], &registry ) {
 		Some(x) => x,
 		None => panic!("Parsing of {} failed", args.free[1])
 		};
 	
+	let until_expr = args.opt_str("until-expr").map(|e| parse::parse_root_values(&mut mesh, "--until-expr", &e, &registry));
+	
+	let display_filter = ::display::BlockFilter {
+		show: args.opt_strs("display-block"),
+		hide: args.opt_strs("hide-block"),
+		};
+	let golden = args.opt_str("golden");
+	if args.opt_present("bless") && golden.is_none() {
+		panic!("--bless requires --golden");
+	}
+	let mut golden_log = ::display::Log::default();
+	
 	// - Flatten root (also flattens all other units)
 	let flat = mesh.flatten_root();
+	let until_expr = until_expr.map(|v| cct_mesh::flat::linklist_to_noderefs(mesh.get_root_unit(), &v));
 
 	// 3. Run the mesh!
-	if args.opt_present("test")
+	if args.free.len() > 1
+	{
+		let ok = match &*args.free[1]
+			{
+			"equiv" => {
+				if args.free.len() < 4 {
+					panic!("Usage: equiv UNIT_A UNIT_B [TICKS]");
+				}
+				let n_ticks = args.free.get(4).map(|v| v.parse::<usize>().unwrap_or_else(|e| panic!("Invalid tick count '{}': {}", v, e))).unwrap_or(16);
+				let unit_a = mesh.flatten_unit(&args.free[2]).unwrap_or_else(|| panic!("Unknown unit '{}'", args.free[2]));
+				let unit_b = mesh.flatten_unit(&args.free[3]).unwrap_or_else(|| panic!("Unknown unit '{}'", args.free[3]));
+				::formal::cmd_equiv(&unit_a, &unit_b, n_ticks)
+				},
+			"prove" => {
+				// prove [UNIT] [TICKS]
+				let mut cmd_args = args.free[2..].iter().peekable();
+				let unit = match cmd_args.peek() {
+					Some(v) if v.parse::<usize>().is_err() => {
+						let name = cmd_args.next().unwrap();
+						Some( mesh.flatten_unit(name).unwrap_or_else(|| panic!("Unknown unit '{}'", name)) )
+						},
+					_ => None,
+					};
+				let n_ticks = cmd_args.next().map(|v| v.parse::<usize>().unwrap_or_else(|e| panic!("Invalid tick count '{}': {}", v, e))).unwrap_or(16);
+				::formal::prove::cmd_prove( match unit { Some(ref u) => u, None => &flat }, n_ticks, args.opt_str("vcd") )
+				},
+			"cnf" => {
+				if args.free.len() < 5 {
+					panic!("Usage: cnf UNIT TICKS FILE");
+				}
+				let unit = mesh.flatten_unit(&args.free[2]).unwrap_or_else(|| panic!("Unknown unit '{}'", args.free[2]));
+				let n_ticks = args.free[3].parse::<usize>().unwrap_or_else(|e| panic!("Invalid tick count '{}': {}", args.free[3], e));
+				::formal::dimacs::cmd_cnf(&unit, n_ticks, &args.free[4], args.opt_present("free-unsupported"))
+				},
+			"fuzz" => {
+				if args.free.len() < 3 {
+					panic!("Usage: fuzz UNIT [TICKS] [RUNS]");
+				}
+				let unit = mesh.flatten_unit(&args.free[2]).unwrap_or_else(|| panic!("Unknown unit '{}'", args.free[2]));
+				let opts = ::fuzz::Options {
+					n_ticks: args.free.get(3).map(|v| v.parse::<usize>().unwrap_or_else(|e| panic!("Invalid tick count '{}': {}", v, e))).unwrap_or(100),
+					n_runs: args.free.get(4).map(|v| v.parse::<usize>().unwrap_or_else(|e| panic!("Invalid run count '{}': {}", v, e))).unwrap_or(1000),
+					seed: args.opt_str("fuzz-seed").map(|v| v.parse::<u64>().unwrap_or_else(|e| panic!("Invalid fuzz seed '{}': {}", v, e)))
+						.unwrap_or_else(|| ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)),
+					constraints: args.opt_strs("fuzz-constraint"),
+					};
+				::fuzz::cmd_fuzz(&unit, &opts)
+				},
+			cmd => panic!("Unknown command '{}'", cmd),
+			};
+		if !ok {
+			::std::process::exit(1);
+		}
+	}
+	else if args.opt_present("test") || args.opt_present("list-tests")
 	{
 		// Run circuit unit tests
 		
 		let show_display = args.opt_present("test-display");
+		let trace = ::waveform::TraceOptions {
+			depth: args.opt_str("fail-history").map(|v| v.parse::<usize>().unwrap_or_else(|e| panic!("Invalid history length '{}': {}", v, e))).unwrap_or(16),
+			style: args.opt_str("wave-style").map(|v| ::waveform::Style::from_name(&v).unwrap_or_else(|| panic!("Unknown waveform style '{}'", v))).unwrap_or(::waveform::Style::Blocks),
+			};
 		let test_glob = args.opt_str("test-glob").unwrap_or( From::from("*") );
 		let pat = ::glob::Pattern::new(&*test_glob).unwrap();
+		let coverage_threshold = args.opt_str("coverage-threshold").map(|v| match v.parse::<f64>() {
+			Ok(v) => v,
+			Err(e) => panic!("Invalid coverage threshold '{}': {}", v, e),
+			});
+		let mut coverage = if args.opt_present("coverage") || args.opt_present("coverage-json") || coverage_threshold.is_some() {
+				Some( ::coverage::Report::new() )
+			}
+			else {
+				None
+			};
 
-		// Only flatten tests if required
-		// TODO: Pass a glob to this function so it doesn't flatten unless it will be run
-		mesh.flatten_tests();
+		// Select tests by name, tag and previous result
+		let tags = args.opt_strs("tag");
+		let exclude_tags = args.opt_strs("exclude-tag");
+		// Failed tests are only recorded when asked for
+		let state_path = match args.opt_str("test-state")
+			{
+			Some(path) => Some(path),
+			None if args.opt_present("rerun-failed") => Some(format!("{}.failed", args.free[0])),
+			None => None,
+			};
+		let rerun = match state_path
+			{
+			Some(ref path) if args.opt_present("rerun-failed") => {
+				// Nothing recorded yet (or everything passed last time), so run everything and record the failures
+				let failed = read_failed_tests(path);
+				if failed.is_empty() {
+					println!("No failed tests recorded in '{}', running all tests", path);
+					None
+				}
+				else {
+					Some(failed)
+				}
+				},
+			_ => None,
+			};
+		let selected = select_tests(&mesh, &pat, &tags, &exclude_tags, rerun.as_ref());
 		
-		// Unit test!
-		for (name,test) in mesh.iter_tests()
+		if args.opt_present("list-tests")
 		{
-			if pat.matches(&name)
+			for name in selected.iter()
 			{
-				if show_display {
-					println!("TEST: '{}'", name);
-				}
-				let res = run_test(test, show_display);
-				if ! show_display {
-					print!("{:40} ", name);
+				let test = mesh.get_test(name).unwrap();
+				print!("{:40}", name);
+				if !test.tags().is_empty() {
+					print!(" tags={}", test.tags().join(","));
 				}
-				match res
+				match test.xfail()
 				{
-				TestStatus::Pass(cyc) => println!("- PASS ({}/{} cycles)", cyc, test.exec_limit()),
-				TestStatus::Fail(cyc,msg) => println!("- FAIL ({} cycles): {}", cyc, msg),
-				TestStatus::Timeout(cyc) => println!("- TIMEOUT ({} cycles)", cyc),
+				Some("") => print!(" (xfail)"),
+				Some(reason) => print!(" (xfail: {})", reason),
+				None => {},
 				}
+				println!();
 			}
+			return ;
+		}
+		
+		// Only flatten the tests that will be run
+		mesh.flatten_tests(&selected);
+		let mut tests: Vec<_> = mesh.iter_tests().collect();
+		tests.sort_by_key(|&(name,_)| name);
+		
+		// Unit test!
+		let mut results = Vec::new();
+		let mut stdout = ::display::Stdout { marker: |tick| format!("=== {:4} ===", tick) };
+		// Display output goes to the golden log instead of being printed
+		let print_display = show_display && golden.is_none();
+		for &(name,test) in tests.iter()
+		{
+			if print_display {
+				println!("TEST: '{}'", name);
+			}
+			let sink: Option<&mut dyn display::Sink> = if golden.is_some() {
+					golden_log.section(name);
+					Some(&mut golden_log)
+				}
+				else if show_display {
+					Some(&mut stdout)
+				}
+				else {
+					None
+				};
+			let res = run_test(test, sink.map(|s| (&display_filter, s)), Some(trace), coverage.as_mut());
+			if ! print_display {
+				print!("{:40} ", name);
+			}
+			let (summary, failed) = describe_result(res, test.xfail(), test.exec_limit());
+			println!("- {}", summary);
+			results.push( (name.clone(), failed) );
+		}
+		if let Some(ref path) = state_path {
+			update_failed_tests(path, &results);
+		}
+		let golden_ok = match golden
+			{
+			Some(ref path) => ::golden::check(path, golden_log.text(), args.opt_present("bless")),
+			None => true,
+			};
+		
+		if let Some(unit_name) = args.opt_str("faults")
+		{
+			let unit = match mesh.get_flat_unit(&unit_name) {
+				Some(u) => u,
+				None => panic!("Unit '{}' is not used by any test", unit_name),
+				};
+			let sample = args.opt_str("fault-sample").map(|v| (
+				v.parse::<usize>().unwrap_or_else(|e| panic!("Invalid fault sample count '{}': {}", v, e)),
+				args.opt_str("fault-seed").map(|v| v.parse::<u64>().unwrap_or_else(|e| panic!("Invalid fault seed '{}': {}", v, e))).unwrap_or(0),
+				));
+			let faults = ::faultsim::enumerate_faults(unit, sample);
+			::faultsim::run(unit, &tests, &faults).print();
+		}
+		
+		if let Some(cov) = coverage
+		{
+			if args.opt_present("coverage") {
+				cov.write_text(&mut ::std::io::stdout()).unwrap();
+			}
+			if let Some(path) = args.opt_str("coverage-json") {
+				let mut fp = match ::std::fs::File::create(&path) {
+					Ok(fp) => fp,
+					Err(e) => panic!("Unable to open coverage output '{}': {}", path, e),
+					};
+				cov.write_json(&mut fp).unwrap();
+			}
+			if let Some(threshold) = coverage_threshold {
+				if let Err(msg) = cov.check_threshold(threshold) {
+					println!("{}", msg);
+					::std::process::exit(1);
+				}
+			}
+		}
+		if !golden_ok {
+			::std::process::exit(1);
+		}
+	}
+	else if args.opt_present("tui")
+	{
+		let opts = ::tui::Options {
+			watch: args.opt_strs("watch"),
+			wave: args.opt_strs("wave"),
+			every: args.opt_str("tui-every").map(|v| v.parse::<u32>().unwrap_or_else(|e| panic!("Invalid tick count '{}': {}", v, e))).unwrap_or(1),
+			};
+		if let Err(e) = ::tui::run(&flat, &opts) {
+			println!("{}", e);
+			::std::process::exit(1);
 		}
 	}
 	else
 	{
 		// Simulate until stopped
+		let n_cycles = args.opt_str("cycles").map(|v| v.parse::<u64>().unwrap_or_else(|e| panic!("Invalid cycle count '{}': {}", v, e))).unwrap_or(30);
+		let until = args.opt_strs("until");
+		if let Some(name) = until.iter().find(|&n| !flat.breakpoints.iter().any(|bp| bp.name == *n)) {
+			panic!("Unknown breakpoint '{}'", name);
+		}
+		let stop_on_breakpoint = args.opt_present("stop-on-breakpoint");
+		let has_stop = stop_on_breakpoint || !until.is_empty() || until_expr.is_some();
+		
 		let mut sim = ::simulator::Engine::new( &flat );
-		let step_count: u32 = 30;
-		for ticknum in 0 .. step_count
+		sim.set_display_filter(display_filter);
+		let mem_dump = args.opt_str("mem-dump");
+		let mut stdout = ::display::Stdout { marker: |tick| format!("--- ^ TICK {}", tick) };
+		let sink: &mut dyn display::Sink = if golden.is_some() { &mut golden_log } else { &mut stdout };
+		let mut ticknum: u64 = 0;
+		let stop_reason = loop
+			{
+				if n_cycles != 0 && ticknum == n_cycles {
+					break None;
+				}
+				sim.tick();
+				
+				let hit = sim.check_breakpoints();
+				if !hit.is_empty()
+				{
+					println!("Breakpoint hit.");
+					if let Some(ref path) = mem_dump {
+						dump_memories(&sim, path);
+					}
+				}
+				sim.show_display(ticknum, sink);
+				ticknum += 1;
+				
+				if let Some(bp) = hit.iter().find(|bp| stop_on_breakpoint || until.contains(&bp.name)) {
+					break Some( format!("breakpoint '{}'", bp.name) );
+				}
+				if let Some(ref conds) = until_expr {
+					if sim.are_set(conds, true) {
+						break Some( "--until-expr".to_string() );
+					}
+				}
+			};
+		if let Some(ref path) = mem_dump {
+			dump_memories(&sim, path);
+		}
+		if has_stop
 		{
-			sim.tick();
-			
-			if sim.check_breakpoints()
+			match stop_reason
 			{
-				println!("Breakpoint hit.");
+			Some(ref r) => println!("Stopped by {} after {} ticks", r, ticknum),
+			None => println!("TIMEOUT ({} ticks)", ticknum),
 			}
-			if sim.show_display()
-			{
-				println!("--- ^ TICK {}", ticknum);
+		}
+		if args.opt_present("print-state") {
+			print_state(&sim, &flat);
+		}
+		if let Some(ref path) = golden {
+			if !::golden::check(path, golden_log.text(), args.opt_present("bless")) {
+				::std::process::exit(1);
 			}
 		}
+		if has_stop && stop_reason.is_none() {
+			::std::process::exit(EXIT_TIMEOUT);
+		}
 	}
 }
 
-fn run_test(test: &cct_mesh::flat::Test, show_display: bool) -> TestStatus
+/// Run a test, printing display items (from the selected blocks) to `display` if it is set
+fn run_test(test: &cct_mesh::flat::Test, display: Option<(&::display::BlockFilter, &mut dyn display::Sink)>, trace: Option<::waveform::TraceOptions>, coverage: Option<&mut ::coverage::Report>) -> TestStatus
 {
 	let mut sim = ::simulator::Engine::new( test.get_mesh() );
+	if coverage.is_some() {
+		sim.enable_coverage();
+	}
+	let display = display.map(|(filter, sink)| {
+		sim.set_display_filter(filter.clone());
+		sink
+		});
+	let rv = run_test_inner(&mut sim, test, display, trace);
+	if let Some(report) = coverage {
+		report.merge( test.get_mesh(), sim.get_coverage().unwrap() );
+	}
+	rv
+}
+fn run_test_inner(sim: &mut ::simulator::Engine, test: &cct_mesh::flat::Test, mut display: Option<&mut dyn display::Sink>, trace: Option<::waveform::TraceOptions>) -> TestStatus
+{
+	use cct_mesh::flat::{AssertTiming, NodeRef};
+	
+	// Signals of all assertions, recorded each tick for failure waveforms
+	let trace = trace.filter(|t| t.depth > 0);
+	let all_asserts: Vec<_> = test.iter_asserts().chain( test.timed_asserts().iter().map(|t| &t.check) ).collect();
+	let traced_nodes: Vec<_> = all_asserts.iter().flat_map(|a| a.conditions.iter().chain(a.values.iter()).chain(a.expected.iter()).cloned()).collect();
+	let trace_ofs: Vec<_> = all_asserts.iter().scan(0, |ofs, a| { let o = *ofs; *ofs += a.conditions.len() + a.values.len() + a.expected.len(); Some(o) }).collect();
+	let mut history = ::waveform::History::new( trace.map(|t| t.depth).unwrap_or(0) );
+	let failure = |msg: String, assert_idx: usize, history: &::waveform::History| match trace
+		{
+		Some(t) => format!("{}\n{}", msg, assert_waveform(test.get_mesh(), all_asserts[assert_idx], trace_ofs[assert_idx], history, t.style)),
+		None => msg,
+		};
+	
+	let vectors = test.vectors();
+	let mut stimulus = test.stimulus().iter().peekable();
+	// Tests with vectors or timed assertions (and no explicit completion condition) complete once they have all been checked
+	let implicit_completion = test.get_completion().is_empty() && (vectors.is_some() || !test.timed_asserts().is_empty());
+	let mut vectors_done = match vectors { Some(v) => v.rows.is_empty(), None => true };
+	let mut timed_done: Vec<bool> = test.timed_asserts().iter().map(|_| false).collect();
+	
+	sim.hold_reset(test.reset_hold());
 	for ticknum in 0 .. test.exec_limit()
 	{
-		sim.tick();
-		
-		if show_display
+		// Apply #at stimulus and the next row of test vectors
+		let mut force = |nodes: &[NodeRef], values: &[bool]| {
+			for (node,&val) in nodes.iter().zip(values.iter())
+			{
+				if let NodeRef::NodeId(id) = *node {
+					sim.force_node(id, val);
+				}
+			}
+			};
+		while let Some(s) = stimulus.next_if(|s| s.tick == ticknum) {
+			force(&s.nodes, &s.values);
+		}
+		if let Some(v) = vectors
 		{
-			if sim.show_display()
+			if let Some(row) = v.rows.get( (ticknum / v.period) as usize ).filter(|_| ticknum % v.period == 0)
 			{
-				println!("=== {:4} ===", ticknum);
+				force(&v.inputs, &row.inputs);
 			}
 		}
 		
-		if sim.are_set(test.get_completion(), true)
+		sim.tick();
+		if trace.is_some() {
+			history.record(ticknum, sim.get_values(&traced_nodes));
+		}
+		
+		if let Some(ref mut sink) = display
+		{
+			sim.show_display(u64::from(ticknum), *sink);
+		}
+		
+		// Completion only passes once every expectation and test vector row has been checked (including on this tick)
+		let completed = !implicit_completion && sim.are_set(test.get_completion(), true);
+		if completed && vectors_done && timed_done.iter().all(|&d| d)
 		{
 			return TestStatus::Pass(ticknum+1);
 		}
@@ -151,23 +502,375 @@ fn run_test(test: &cct_mesh::flat::Test, show_display: bool) -> TestStatus
 				
 				if have != exp
 				{
-					return TestStatus::Fail(ticknum+1, format!("Assertion #{} failed (line {}) - have:{:?} != exp:{:?}",
-						ass_idx, assert.line, have, exp));
+					let msg = format!("Assertion #{} failed (line {}) - have:{:?} != exp:{:?}", ass_idx, assert.line, have, exp);
+					return TestStatus::Fail(ticknum+1, failure(msg, ass_idx, &history));
 				}
 			}
 		}
+		
+		// Check #expect_at/#expect_within
+		for (idx,ta) in test.timed_asserts().iter().enumerate()
+		{
+			if timed_done[idx] {
+				continue ;
+			}
+			let have = sim.get_values(&ta.check.values);
+			let exp  = sim.get_values(&ta.check.expected);
+			let msg = match ta.timing
+				{
+				AssertTiming::At(t) if t == ticknum => {
+					timed_done[idx] = true;
+					if have == exp { None } else { Some(format!("Expectation at tick {} failed (line {})", t, ta.check.line)) }
+					},
+				AssertTiming::At(_) => None,
+				AssertTiming::Within(_) if have == exp => { timed_done[idx] = true; None },
+				AssertTiming::Within(n) if ticknum + 1 >= n => Some(format!("Expectation not met within {} ticks (line {})", n, ta.check.line)),
+				AssertTiming::Within(_) => None,
+				};
+			if let Some(msg) = msg {
+				let msg = format!("{} - have:{:?} != exp:{:?}", msg, have, exp);
+				return TestStatus::Fail(ticknum+1, failure(msg, test.iter_asserts().len() + idx, &history));
+			}
+		}
+		
+		// Check test vector outputs on the last tick of each row
+		if let Some(v) = vectors
+		{
+			let row_idx = (ticknum / v.period) as usize;
+			if let Some(row) = v.rows.get(row_idx).filter(|_| ticknum % v.period == v.period - 1)
+			{
+				let have = sim.get_values(&row.check.values);
+				let exp  = sim.get_values(&row.check.expected);
+				if have != exp
+				{
+					return TestStatus::Fail(ticknum+1, vector_failure(v, row_idx, &have, &exp));
+				}
+				vectors_done = row_idx + 1 == v.rows.len();
+			}
+		}
+		
+		if completed
+		{
+			if let Some(idx) = timed_done.iter().position(|&d| !d) {
+				let msg = format!("Expectation never checked (line {}), the test completed first", test.timed_asserts()[idx].check.line);
+				return TestStatus::Fail(ticknum+1, msg);
+			}
+			if let (Some(v), false) = (vectors, vectors_done) {
+				let row = ((ticknum + 1) / v.period) as usize;
+				let msg = format!("Test vector row {} (line {}) never checked, the test completed first", row, v.rows[row].check.line);
+				return TestStatus::Fail(ticknum+1, msg);
+			}
+			return TestStatus::Pass(ticknum+1);
+		}
+		if implicit_completion && vectors_done && timed_done.iter().all(|&d| d)
+		{
+			return TestStatus::Pass(ticknum+1);
+		}
 	}
 	TestStatus::Timeout(test.exec_limit())
 }
 
+/// Describe a failed test vector row, listing the inputs and each output signal (mismatches marked with '*')
+fn vector_failure(vectors: &cct_mesh::flat::TestVectors, row: usize, have: &[bool], exp: &[bool]) -> String
+{
+	let row_inputs = &vectors.rows[row].inputs;
+	let mut ofs = 0;
+	let inputs: Vec<_> = vectors.input_signals.iter()
+		.map(|&(ref name, width)| { ofs += width; format!("{} = {}", name, format_value(&row_inputs[ofs - width .. ofs])) })
+		.collect();
+	let mut rv = format!("Test vector row {} (line {}) failed\n    inputs: {}", row, vectors.rows[row].check.line, inputs.join(", "));
+	let name_width = vectors.output_signals.iter().map(|o| o.0.len()).max().unwrap_or(0);
+	let mut ofs = 0;
+	for &(ref name, width) in vectors.output_signals.iter()
+	{
+		let (h, e) = (&have[ofs ..][..width], &exp[ofs ..][..width]);
+		ofs += width;
+		rv.push_str( &format!("\n    {} {:w$} have {} exp {}", if h != e { '*' } else { ' ' }, name, format_value(h), format_value(e), w = name_width) );
+	}
+	rv
+}
+
+/// Format a line (`0`/`1`) or group (hex, or binary if wider than 64 bits) value
+fn format_value(vals: &[bool]) -> String
+{
+	let bits = || vals.iter().rev().map(|&v| if v { '1' } else { '0' }).collect::<String>();
+	if vals.len() == 1 {
+		bits()
+	}
+	else if vals.len() <= 64 {
+		format!("0x{:0w$X}", ::simulator::decode_u64_le(vals), w = vals.len().div_ceil(4))
+	}
+	else {
+		format!("0b{}", bits())
+	}
+}
+
+/// Render the recent history of an assertion's signals (`ofs` is its first entry in the recorded values)
+fn assert_waveform(mesh: &cct_mesh::flat::Mesh, assert: &cct_mesh::flat::TestAssert, ofs: usize, history: &::waveform::History, style: ::waveform::Style) -> String
+{
+	use cct_mesh::flat::NodeRef;
+	let mut traces: Vec<_> = assert.conditions.iter().enumerate()
+		.map(|(i,c)| ::waveform::Trace {
+			name: match *c { NodeRef::NodeId(id) => mesh.node_name(id), NodeRef::NodeOne => "1".to_string(), NodeRef::NodeZero => "0".to_string() },
+			first: ofs + i,
+			bits: 1,
+			})
+		.collect();
+	let ofs = ofs + assert.conditions.len();
+	traces.push( ::waveform::Trace { name: "have".to_string(), first: ofs, bits: assert.values.len() } );
+	traces.push( ::waveform::Trace { name: "exp".to_string(), first: ofs + assert.values.len(), bits: assert.expected.len() } );
+	// Buses wider than 64 bits can't be shown as a single hex value, show each bit instead
+	let traces = traces.into_iter()
+		.flat_map(|t| if t.bits <= 64 { vec![t] } else {
+			(0 .. t.bits).map(|i| ::waveform::Trace { name: format!("{}[{}]", t.name, i), first: t.first + i, bits: 1 }).collect()
+			})
+		.collect::<Vec<_>>();
+	history.render(&traces, style).lines().map(|l| format!("    {}", l)).collect::<Vec<_>>().join("\n")
+}
+
+/// Read the names of tests that failed on earlier runs (the `--rerun-failed` state file)
+/// Names of the tests to run (sorted), selected by name, tag and previous result
+fn select_tests(mesh: &cct_mesh::Root, pat: &::glob::Pattern, tags: &[String], exclude_tags: &[String], rerun: Option<&Vec<String>>) -> Vec<String>
+{
+	let mut selected: Vec<String> = mesh.iter_test_defs()
+		.filter(|&(name,_)| pat.matches(name))
+		.filter(|&(_,t)| tags.is_empty() || t.tags().iter().any(|tag| tags.contains(tag)))
+		.filter(|&(_,t)| !t.tags().iter().any(|tag| exclude_tags.contains(tag)))
+		.filter(|&(name,_)| match rerun { Some(r) => r.contains(name), None => true })
+		.map(|(name,_)| name.clone())
+		.collect();
+	selected.sort();
+	selected
+}
+
+/// Result summary for a test, and whether it counts as failed
+///
+/// Expected failures (#xfail) that pass count as failed.
+fn describe_result(res: TestStatus, xfail: bool, exec_limit: u32) -> (String, bool)
+{
+	match (res, xfail)
+	{
+	(TestStatus::Pass(cyc), false) => (format!("PASS ({}/{} cycles)", cyc, exec_limit), false),
+	(TestStatus::Pass(cyc), true) => (format!("XPASS ({}/{} cycles)", cyc, exec_limit), true),
+	(TestStatus::Fail(cyc,msg), false) => (format!("FAIL ({} cycles): {}", cyc, msg), true),
+	(TestStatus::Fail(cyc,msg), true) => (format!("XFAIL ({} cycles): {}", cyc, msg), false),
+	(TestStatus::Timeout(cyc), false) => (format!("TIMEOUT ({} cycles)", cyc), true),
+	(TestStatus::Timeout(cyc), true) => (format!("XFAIL (timeout, {} cycles)", cyc), false),
+	}
+}
+
+fn read_failed_tests(path: &str) -> Vec<String>
+{
+	match ::std::fs::read_to_string(path)
+	{
+	Ok(s) => s.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect(),
+	Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Vec::new(),
+	Err(e) => panic!("Unable to read test state '{}': {}", path, e),
+	}
+}
+/// Update the failed test list with the results of this run (tests that weren't run keep their old state)
+///
+/// The file is removed once no tests are failing.
+fn update_failed_tests(path: &str, results: &[(String,bool)])
+{
+	let mut failed: ::std::collections::BTreeSet<_> = read_failed_tests(path).into_iter()
+		.filter(|name| !results.iter().any(|r| r.0 == *name))
+		.collect();
+	failed.extend( results.iter().filter(|r| r.1).map(|r| r.0.clone()) );
+	let rv = if failed.is_empty() {
+			match ::std::fs::remove_file(path) {
+			Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok( () ),
+			rv => rv,
+			}
+		}
+		else {
+			::std::fs::write(path, failed.into_iter().map(|n| n + "\n").collect::<String>())
+		};
+	if let Err(e) = rv {
+		println!("Unable to update test state '{}': {}", path, e);
+	}
+}
+
+fn dump_memories(sim: &::simulator::Engine, path: &str)
+{
+	let rv = ::std::fs::File::create(path).and_then(|mut fp| sim.dump_memories(&mut fp));
+	match rv
+	{
+	Ok(n) => println!("Dumped {} memories to {}", n, path),
+	Err(e) => panic!("Unable to write memory dump '{}': {}", path, e),
+	}
+}
+
+/// Print the value of every named line and group in the root unit (sorted by name)
+fn print_state(sim: &::simulator::Engine, mesh: &cct_mesh::flat::Mesh)
+{
+	use cct_mesh::flat::NodeRef;
+	// Collect the root's names in one pass over the nodes, showing groups as a whole
+	let mut signals = ::std::collections::BTreeMap::<String,Vec<(usize,NodeRef)>>::new();
+	for (id,node) in mesh.nodes.iter().enumerate()
+	{
+		for name in node.names.iter().filter(|n| !n.contains('/'))
+		{
+			let (key, bit) = match cct_mesh::flat::split_group_member(name)
+				{
+				Some((g, i)) => (format!("@{}", g), i),
+				None => (format!("${}", name), 0),
+				};
+			signals.entry(key).or_default().push( (bit, NodeRef::NodeId(id as u32)) );
+		}
+	}
+	for (name,mut bits) in signals
+	{
+		bits.sort_by_key(|b| b.0);
+		let vals = sim.get_values(&bits.into_iter().map(|b| b.1).collect::<Vec<_>>());
+		let value = match vals.len()
+			{
+			1 => format_value(&vals),
+			n => format!("{} ({} bits)", format_value(&vals), n),
+			};
+		println!("{:16} = {}", name, value);
+	}
+}
+
 fn print_usage(program_name: &str, opts: &::getopts::Options)
 {
-	println!("Usage: {}", opts.short_usage(program_name));
+	println!("Usage: {} [COMMAND ARGS...]", opts.short_usage(program_name));
+	println!("");
+	println!("Commands:");
+	println!("    equiv UNIT_A UNIT_B [TICKS]  Check that two units have identical outputs for TICKS ticks");
+	println!("    prove [UNIT] [TICKS]         Check #property assertions for TICKS ticks after reset");
+	println!("    cnf UNIT TICKS FILE          Export UNIT unrolled for TICKS ticks as DIMACS (with FILE.json var map)");
+	println!("    fuzz UNIT [TICKS] [RUNS]     Drive UNIT with random inputs, checking #property and error #breakpoints");
 	println!("");
 	println!("{}", opts.usage("Logic gate simulator") );
+	println!("When free-running with a stop condition (--until, --until-expr, --stop-on-breakpoint), the exit code is");
+	println!("0 if the condition was reached and {} if --cycles ran out first.", EXIT_TIMEOUT);
+	println!("With --golden, display output is captured instead of printed, and the exit code is 1 if it doesn't match.");
 }
 
 
+#[test]
+fn test_vectors()
+{
+	use test_util::run_test as run;
+	let adder = |period: u32, rows: &str| format!(concat!(
+		"#testcase 20 \"t\"\n",
+		"#array a 4\n",
+		"#array b 4\n",
+		"#array s 4\n",
+		"#array q 4\n",
+		"@s, $c, $v = ADD{{4}} @a, @b, 0\n",
+		"@q = DELAY{{1}} @s\n",
+		"#testvectors {} @a, @b : @q, $c\n",
+		"{}",
+		"#endtestvectors\n",
+		"#endtestcase\n",
+		), period, rows);
+	
+	// Passes once the last row has been checked (each row takes `period` ticks)
+	let rows = "1, 2 : 3, 0\n0xF, 1 : 0, 1\n7, 7 : 14, 0\n";
+	assert_eq!( run(&adder(2, rows)), TestStatus::Pass(6) );
+	assert_eq!( run(&adder(3, rows)), TestStatus::Pass(9) );
+	// Outputs are checked on the last tick of the row, too early for the delayed sum with a period of one
+	assert_eq!( run(&adder(1, "1, 2 : 3, 0\n")), TestStatus::Fail(1, concat!(
+		"Test vector row 0 (line 9) failed\n",
+		"    inputs: @a = 0x1, @b = 0x2\n",
+		"    * @q have 0x0 exp 0x3\n",
+		"      $c have 0 exp 0",
+		).to_string()) );
+	
+	// A mismatch reports the row, its inputs, and marks the outputs that differ
+	assert_eq!( run(&adder(2, "1, 2 : 3, 0\n3, 3 : 7, 1\n")), TestStatus::Fail(4, concat!(
+		"Test vector row 1 (line 10) failed\n",
+		"    inputs: @a = 0x3, @b = 0x3\n",
+		"    * @q have 0x6 exp 0x7\n",
+		"    * $c have 0 exp 1",
+		).to_string()) );
+}
+
+#[test]
+fn test_completion()
+{
+	let run = |body: &str| -> TestStatus {
+		::test_util::run_test(&format!(concat!(
+			"#testcase 20 \"t\"\n",
+			"#array q 4\n",
+			"#array zero 4\n",
+			"@zero = 0, 0, 0, 0\n",
+			"@q, $tc = COUNTER{{4}} 1, 0, 0, 0, @zero\n",
+			"$done = AND @q[1], @q[0]\n",
+			"#testcomplete $done\n",
+			"{}",
+			"#endtestcase\n",
+			), body))
+		};
+	
+	// $done is set on tick 3 (q becomes 3 on tick 2, through the AND)
+	assert_eq!( run(""), TestStatus::Pass(4) );
+	assert_eq!( run("#expect_at 1 @q 2[0:3]\n"), TestStatus::Pass(4) );
+	assert_eq!( run("#expect_at 3 @q 4[0:3]\n"), TestStatus::Pass(4) );
+	assert_eq!( run("#expect_at 5 @q 6[0:3]\n"), TestStatus::Fail(4, "Expectation never checked (line 8), the test completed first".to_string()) );
+	assert_eq!( run("#testvectors 2 $tc : $tc\n0 : 0\n0 : 0\n#endtestvectors\n"), TestStatus::Pass(4) );
+	assert_eq!( run("#testvectors 3 $tc : $tc\n0 : 0\n0 : 0\n#endtestvectors\n"),
+		TestStatus::Fail(4, "Test vector row 1 (line 10) never checked, the test completed first".to_string()) );
+}
+
+#[test]
+fn test_selection()
+{
+	let mut root = test_util::load(concat!(
+		"#testcase 5 \"add\" tags=alu,fast\n",
+		"$x = DELAY{1} 1\n",
+		"#testcomplete $x\n",
+		"#endtestcase\n",
+		"#testcase 5 \"mul\" tags=alu,slow\n",
+		"$x = DELAY{1} 1\n",
+		"#testcomplete $x\n",
+		"#endtestcase\n",
+		"#testcase 5 \"misc\"\n",
+		"#xfail \"never completes\"\n",
+		"$x = DELAY{1} 0\n",
+		"#testcomplete $x\n",
+		"#endtestcase\n",
+		));
+	let all = ::glob::Pattern::new("*").unwrap();
+	let strs = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+	assert_eq!( select_tests(&root, &all, &[], &[], None), ["add", "misc", "mul"] );
+	assert_eq!( select_tests(&root, &all, &strs(&["alu"]), &[], None), ["add", "mul"] );
+	assert_eq!( select_tests(&root, &all, &strs(&["alu"]), &strs(&["slow"]), None), ["add"] );
+	assert_eq!( select_tests(&root, &::glob::Pattern::new("m*").unwrap(), &[], &strs(&["fast"]), None), ["misc", "mul"] );
+	// Recorded failures that no longer exist are ignored
+	assert_eq!( select_tests(&root, &all, &[], &[], Some(&strs(&["misc", "gone"]))), ["misc"] );
+
+	// An expected failure that fails is fine, one that passes counts as failed
+	root.flatten_tests(&strs(&["misc"]));
+	let (_, test) = root.iter_tests().next().unwrap();
+	assert!( test.xfail() );
+	let res = run_test(test, None, None, None);
+	assert_eq!( res, TestStatus::Timeout(5) );
+	assert_eq!( describe_result(res, test.xfail(), test.exec_limit()), ("XFAIL (timeout, 5 cycles)".to_string(), false) );
+	assert_eq!( describe_result(TestStatus::Pass(1), true, 5), ("XPASS (1/5 cycles)".to_string(), true) );
+	assert_eq!( describe_result(TestStatus::Fail(2, "bad".to_string()), false, 5), ("FAIL (2 cycles): bad".to_string(), true) );
+}
+
+#[test]
+fn test_failed_state()
+{
+	let path = ::std::env::temp_dir().join(format!("lc_test_state_{}", ::std::process::id()));
+	let path = path.to_str().unwrap();
+	assert!( read_failed_tests(path).is_empty() );
+	
+	update_failed_tests(path, &[("b".to_string(), true), ("a".to_string(), false), ("c".to_string(), true)]);
+	assert_eq!( read_failed_tests(path), ["b", "c"] );
+	// Tests that weren't run keep their state
+	update_failed_tests(path, &[("c".to_string(), false), ("a".to_string(), true)]);
+	assert_eq!( read_failed_tests(path), ["a", "b"] );
+	// The file goes once nothing is failing
+	update_failed_tests(path, &[("a".to_string(), false), ("b".to_string(), false)]);
+	assert!( !::std::path::Path::new(path).exists() );
+	assert!( read_failed_tests(path).is_empty() );
+}
+
 // vim: ft=rust
 
 
@@ -343,6 +343,10 @@ impl<'rl> Lexer<'rl>
 			}
 		}
 	}
+	/// Name of the file currently being read (follows `%line` markers)
+	pub fn filename(&self) -> &str {
+		&self.filename
+	}
 	pub fn put_back(&mut self, tok: Token) {
 		self.saved_tok = Some(tok)
 	}
@@ -0,0 +1,338 @@
+//
+//
+//
+//! Loading of memory/ROM initialisation files
+use std::path::Path;
+
+#[derive(Copy,Clone,Debug)]
+pub enum Format
+{
+	/// `$readmemh`-style text (hex words, `@addr` markers, `//` comments)
+	Hex,
+	/// `$readmemb`-style text (binary words)
+	BinText,
+	/// Raw binary
+	Raw,
+	/// Intel HEX
+	IntelHex,
+	/// Motorola S-record
+	SRecord,
+}
+
+/// How bytes are grouped into words for byte-oriented formats
+#[derive(Copy,Clone,Debug)]
+pub struct WordLayout
+{
+	pub bytes: usize,
+	pub big_endian: bool,
+}
+impl Default for WordLayout
+{
+	fn default() -> WordLayout {
+		WordLayout { bytes: 1, big_endian: false }
+	}
+}
+
+impl Format
+{
+	pub fn from_name(name: &str) -> Option<Format>
+	{
+		match name
+		{
+		"hex" | "readmemh" => Some(Format::Hex),
+		"memb" | "readmemb" => Some(Format::BinText),
+		"raw" => Some(Format::Raw),
+		"ihex" => Some(Format::IntelHex),
+		"srec" => Some(Format::SRecord),
+		_ => None,
+		}
+	}
+
+	/// Guess the format of a ROM image from its file extension (defaulting to `$readmemh` text)
+	///
+	/// Note that `.bin` is raw binary, the `$readmemb` text format is named `memb`.
+	pub fn from_path(path: &str) -> Format
+	{
+		match Path::new(path).extension().and_then(|e| e.to_str())
+		{
+		Some("bin") | Some("rom") => Format::Raw,
+		Some("ihex") | Some("ihx") => Format::IntelHex,
+		Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => Format::SRecord,
+		_ => Format::Hex,
+		}
+	}
+}
+
+/// Words loaded from a data file (in address order)
+#[derive(Debug,PartialEq)]
+pub struct Data
+{
+	pub words: Vec<u64>,
+	/// File offset each word was read from (`None` for gaps between addressed records)
+	pub offsets: Vec<Option<usize>>,
+}
+impl Data
+{
+	fn set(&mut self, addr: usize, val: u64, ofs: usize)
+	{
+		if self.words.len() <= addr {
+			self.words.resize(addr+1, 0);
+			self.offsets.resize(addr+1, None);
+		}
+		self.words[addr] = val;
+		self.offsets[addr] = Some(ofs);
+	}
+}
+
+/// Byte image built from an addressed format, with the file offset of each byte
+#[derive(Debug,Default)]
+struct Image
+{
+	bytes: Vec<u8>,
+	offsets: Vec<Option<usize>>,
+}
+
+/// Load a data file
+pub fn load(path: &Path, format: Format, layout: WordLayout) -> Result<Data,String>
+{
+	let data = match ::std::fs::read(path) {
+		Ok(d) => d,
+		Err(e) => return Err(format!("Unable to read '{}': {}", path.display(), e)),
+		};
+	let text = || String::from_utf8_lossy(&data);
+	match format
+	{
+	Format::Hex => parse_text(&text(), 16),
+	Format::BinText => parse_text(&text(), 2),
+	Format::Raw => bytes_to_words(&data, &(0 .. data.len()).map(Some).collect::<Vec<_>>(), layout, true),
+	Format::IntelHex => parse_ihex(&text()).and_then(|i| bytes_to_words(&i.bytes, &i.offsets, layout, false)),
+	Format::SRecord => parse_srec(&text()).and_then(|i| bytes_to_words(&i.bytes, &i.offsets, layout, false)),
+	}.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Upper limit on the size of an image built from an addressed format
+const MAX_IMAGE_BYTES: usize = 1 << 27;
+
+/// Parse whitespace-separated words, with `@addr` (hex) address markers and `//` comments
+fn parse_text(text: &str, radix: u32) -> Result<Data,String>
+{
+	let mut rv = Data { words: Vec::new(), offsets: Vec::new() };
+	let mut addr = 0;
+	for (line_ofs,lineno,line) in lines(text)
+	{
+		let line = match line.find("//") {
+			Some(p) => &line[..p],
+			None => line,
+			};
+		for word in line.split_whitespace()
+		{
+			let ofs = line_ofs + (word.as_ptr() as usize - line.as_ptr() as usize);
+			let clean = word.replace('_', "");
+			if let Some(a) = clean.strip_prefix('@')
+			{
+				addr = match usize::from_str_radix(a, 16) {
+					Ok(v) if v < MAX_IMAGE_BYTES => v,
+					Ok(_) => return Err(format!("offset {} (line {}): Address '{}' too large", ofs, lineno, a)),
+					Err(e) => return Err(format!("offset {} (line {}): Bad address '{}': {}", ofs, lineno, a, e)),
+					};
+				continue ;
+			}
+			let val = match u64::from_str_radix(&clean, radix) {
+				Ok(v) => v,
+				Err(e) => return Err(format!("offset {} (line {}): Bad word '{}': {}", ofs, lineno, word, e)),
+				};
+			rv.set(addr, val, ofs);
+			addr += 1;
+		}
+	}
+	Ok(rv)
+}
+
+/// Iterate lines along with their byte offset and (1-based) line number
+fn lines(text: &str) -> impl Iterator<Item=(usize,usize,&str)>
+{
+	text.split('\n').enumerate()
+		.scan(0, |ofs, (i,line)| { let o = *ofs; *ofs += line.len() + 1; Some( (o, i+1, line.trim_end_matches('\r')) ) })
+}
+
+/// Reject non-ASCII text in a record line (so it can be sliced by byte offset)
+fn check_ascii(line: &str, ofs: usize, lineno: usize) -> Result<(),String>
+{
+	match line.find(|c: char| !c.is_ascii())
+	{
+	Some(p) => Err(format!("offset {} (line {}): Non-ASCII character in record", ofs + p, lineno)),
+	None => Ok( () ),
+	}
+}
+
+/// Decode the hex digits of a record (after the start marker), returning the bytes
+fn record_bytes(rec: &str, ofs: usize, lineno: usize) -> Result<Vec<u8>,String>
+{
+	if rec.len() & 1 != 0 {
+		return Err(format!("offset {} (line {}): Odd number of hex digits in record", ofs, lineno));
+	}
+	(0 .. rec.len() / 2)
+		.map(|i| u8::from_str_radix(&rec[i*2 ..][..2], 16)
+			.map_err(|_| format!("offset {} (line {}): Bad hex byte '{}'", ofs + 1 + i*2, lineno, &rec[i*2 ..][..2])))
+		.collect()
+}
+
+/// Store bytes at an address in a growing image
+///
+/// `data_ofs` is the file offset of the first byte's hex digits (each byte takes two).
+fn image_write(image: &mut Image, addr: usize, bytes: &[u8], data_ofs: usize, ofs: usize, lineno: usize) -> Result<(),String>
+{
+	if addr + bytes.len() > MAX_IMAGE_BYTES {
+		return Err(format!("offset {} (line {}): Address {:#x} too large", ofs, lineno, addr));
+	}
+	if image.bytes.len() < addr + bytes.len() {
+		image.bytes.resize(addr + bytes.len(), 0);
+		image.offsets.resize(addr + bytes.len(), None);
+	}
+	image.bytes[addr ..][..bytes.len()].copy_from_slice(bytes);
+	for (i,o) in image.offsets[addr ..][..bytes.len()].iter_mut().enumerate() {
+		*o = Some(data_ofs + i*2);
+	}
+	Ok( () )
+}
+
+/// Parse Intel HEX records into a byte image
+fn parse_ihex(text: &str) -> Result<Image,String>
+{
+	let mut image = Image::default();
+	let mut base = 0;
+	for (ofs,lineno,line) in lines(text)
+	{
+		check_ascii(line, ofs, lineno)?;
+		let line = line.trim();
+		if line.is_empty() {
+			continue ;
+		}
+		if !line.starts_with(':') {
+			return Err(format!("offset {} (line {}): Record doesn't start with ':'", ofs, lineno));
+		}
+		let rec = record_bytes(&line[1..], ofs, lineno)?;
+		if rec.len() < 5 || rec.len() != 5 + rec[0] as usize {
+			return Err(format!("offset {} (line {}): Bad record length", ofs, lineno));
+		}
+		if rec.iter().fold(0u8, |s,&b| s.wrapping_add(b)) != 0 {
+			return Err(format!("offset {} (line {}): Checksum mismatch", ofs, lineno));
+		}
+		let addr = (rec[1] as usize) << 8 | rec[2] as usize;
+		let data = &rec[4 .. rec.len()-1];
+		match rec[3]
+		{
+		0x00 => image_write(&mut image, base + addr, data, ofs + 1 + 4*2, ofs, lineno)?,
+		0x01 => break,
+		0x02 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+		0x04 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+		0x03 | 0x05 => {},	// Start address, not relevant
+		t => return Err(format!("offset {} (line {}): Unsupported record type {:02X}", ofs, lineno, t)),
+		}
+	}
+	Ok(image)
+}
+
+/// Parse Motorola S-records into a byte image
+fn parse_srec(text: &str) -> Result<Image,String>
+{
+	let mut image = Image::default();
+	for (ofs,lineno,line) in lines(text)
+	{
+		check_ascii(line, ofs, lineno)?;
+		let line = line.trim();
+		if line.is_empty() {
+			continue ;
+		}
+		if line.len() < 2 || !line.starts_with('S') {
+			return Err(format!("offset {} (line {}): Record doesn't start with 'S'", ofs, lineno));
+		}
+		let rec = record_bytes(&line[2..], ofs + 1, lineno)?;
+		if rec.is_empty() || rec.len() != 1 + rec[0] as usize {
+			return Err(format!("offset {} (line {}): Bad record length", ofs, lineno));
+		}
+		if rec.iter().fold(0u8, |s,&b| s.wrapping_add(b)) != 0xFF {
+			return Err(format!("offset {} (line {}): Checksum mismatch", ofs, lineno));
+		}
+		let addr_len = match &line[1..2]
+			{
+			"0" | "5" | "6" => continue,	// Header and record counts
+			"7" | "8" | "9" => break,	// Termination (start address)
+			"1" => 2,
+			"2" => 3,
+			"3" => 4,
+			t => return Err(format!("offset {} (line {}): Unsupported record type S{}", ofs, lineno, t)),
+			};
+		if rec.len() < 2 + addr_len {
+			return Err(format!("offset {} (line {}): Bad record length", ofs, lineno));
+		}
+		let addr = rec[1 .. 1+addr_len].iter().fold(0, |a,&b| a << 8 | b as usize);
+		image_write(&mut image, addr, &rec[1+addr_len .. rec.len()-1], ofs + 2 + (1+addr_len)*2, ofs, lineno)?;
+	}
+	Ok(image)
+}
+
+/// Group bytes into words
+///
+/// If `exact` is set, a trailing partial word is an error, otherwise it is zero-padded.
+fn bytes_to_words(data: &[u8], offsets: &[Option<usize>], layout: WordLayout, exact: bool) -> Result<Data,String>
+{
+	let partial = data.len() % layout.bytes;
+	if exact && partial != 0 {
+		let ofs = data.len() - partial;
+		return Err(format!("offset {}: Truncated {}-byte word at end of file", ofs, layout.bytes));
+	}
+	let words = data.chunks(layout.bytes).map(|w| {
+		let mut word = [0u8; 8];
+		word[..w.len()].copy_from_slice(w);
+		let word = &word[..layout.bytes];
+		if layout.big_endian {
+			word.iter().fold(0, |v,&b| v << 8 | b as u64)
+		}
+		else {
+			word.iter().rev().fold(0, |v,&b| v << 8 | b as u64)
+		}
+		}).collect();
+	let offsets = offsets.chunks(layout.bytes).map(|o| o.iter().flatten().next().cloned()).collect();
+	Ok( Data { words, offsets } )
+}
+
+#[test]
+fn test_parse_text()
+{
+	let d = parse_text("1 2 // three\n@4 ff\n", 16).unwrap();
+	assert_eq!( d.words, vec![1,2,0,0,0xFF] );
+	assert_eq!( d.offsets, vec![Some(0),Some(2),None,None,Some(16)] );
+	assert_eq!( parse_text("0101_1010\n", 2).map(|d| d.words), Ok(vec![0x5A]) );
+	assert!( parse_text("1\n  xyz\n", 16).unwrap_err().starts_with("offset 4 (line 2):") );
+}
+
+#[test]
+fn test_parse_records()
+{
+	let ihex = ":0300300002337A1E\n:020000040001F9\n:02000000BEEF51\n:00000001FF\n";
+	let image = parse_ihex(ihex).unwrap();
+	assert_eq!( &image.bytes[0x30 .. 0x33], &[0x02, 0x33, 0x7A] );
+	assert_eq!( &image.offsets[0x30 .. 0x33], &[Some(9), Some(11), Some(13)] );
+	assert_eq!( &image.bytes[0x10000 ..], &[0xBE, 0xEF] );
+	assert!( parse_ihex(":0300300002337A1F\n").unwrap_err().contains("Checksum") );
+
+	// Non-ASCII (including invalid UTF-8 replaced by `load`) is an error, not a panic
+	assert!( parse_ihex(":03003000\u{e9}2337A1E\n").unwrap_err().starts_with("offset 9 (line 1):") );
+	let bad = String::from_utf8_lossy(b"S00600004844521B\nS1\xff7");
+	assert!( parse_srec(&bad).unwrap_err().starts_with("offset 19 (line 2):") );
+
+	let srec = "S00600004844521B\nS1070002DEADBEEFBE\nS9030000FC\n";
+	let image = parse_srec(srec).unwrap();
+	assert_eq!( image.bytes, vec![0, 0, 0xDE, 0xAD, 0xBE, 0xEF] );
+	assert_eq!( image.offsets[2], Some(25) );
+
+	let be = WordLayout { bytes: 2, big_endian: true };
+	let le = WordLayout { bytes: 2, big_endian: false };
+	let ofs = [Some(0), Some(1), Some(2), Some(3)];
+	assert_eq!( bytes_to_words(&[1,2,3,4], &ofs, be, true).map(|d| d.words), Ok(vec![0x0102, 0x0304]) );
+	assert_eq!( bytes_to_words(&[1,2,3,4], &ofs, le, true), Ok(Data { words: vec![0x0201, 0x0403], offsets: vec![Some(0), Some(2)] }) );
+	assert!( bytes_to_words(&[1,2,3], &ofs[..3], le, true).unwrap_err().starts_with("offset 2:") );
+}
+
+// vim: ft=rust
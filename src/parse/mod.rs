@@ -7,10 +7,14 @@ use parse::lex::Token::*;
 use std::io::Read;
 
 mod lex;
+mod memfile;
 
 struct Parser<'stream>
 {
 	lexer: lex::Lexer<'stream>,
+	registry: &'stream ::elements::ElementRegistry,
+	/// Only allow lines that already exist (for values given outside of a file)
+	existing_lines: bool,
 }
 
 macro_rules! is_enum{
@@ -43,9 +47,11 @@ macro_rules! syntax_assert_get{ ($parser:expr, $filter:pat => $val:expr, $msg:ex
 
 impl<'rl> Parser<'rl>
 {
-	fn new<'a>(instream: lex::InStream<'a>, root_filename: &str) -> Parser<'a> {
+	fn new<'a>(instream: lex::InStream<'a>, root_filename: &str, registry: &'a ::elements::ElementRegistry) -> Parser<'a> {
 		Parser {
 			lexer: Lexer::new(instream, root_filename),
+			registry,
+			existing_lines: false,
 		}
 	}
 	
@@ -127,6 +133,9 @@ impl<'rl> Parser<'rl>
 					1
 				};
 			debug!("get_value: Line '{}' * {}", name, count);
+			if self.existing_lines && unit.find_link(&name).is_none() {
+				syntax_error!(self.lexer, "Line ${} is not defined", name);
+			}
 			for _ in 0 .. count {
 				let v = unit.get_link(&name);
 				trace!("- {:?}", v);
@@ -223,10 +232,10 @@ impl<'rl> Parser<'rl>
 		TokParenOpen => {
 			let (elename, params, inputs) = self.get_element(meshroot, unit);
 			syntax_assert_get!(self, TokParenClose => (), "Expected TokParenClose after sub-element");
-			let ll = match unit.append_element( meshroot, &elename, params, inputs, None )
+			let ll = match unit.append_element( meshroot, self.registry, &elename, params, inputs, None )
 				{
 				Ok(v) => v,
-				Err(e) => panic!("Error appending element {} : {:?}", elename, e),
+				Err(e) => panic!("Error appending element {} : {}", elename, e),
 				};
 			values.extend( ll.into_iter() );
 			},
@@ -320,6 +329,48 @@ impl<'rl> Parser<'rl>
 		return values;
 	}
 	
+	/// Read a comma-separated list of lines and groups, keeping the name of each
+	fn get_signal_list(&mut self, meshroot: &::cct_mesh::Root, unit: &mut ::cct_mesh::Unit) -> Vec<(String,::cct_mesh::LinkList)>
+	{
+		let mut rv = Vec::new();
+		loop
+		{
+			let name = match self.look_ahead()
+				{
+				TokLine(n) => format!("${}", n),
+				TokGroup(n) => format!("@{}", n),
+				tok => syntax_error!(self.lexer, "Expected line or group, got {}", tok),
+				};
+			let mut values = Default::default();
+			self.get_value(&mut values, meshroot, unit);
+			rv.push( (name, values) );
+			let tok = self.get_token();
+			if !is_enum!(tok, TokComma) {
+				self.put_back(tok);
+				break
+			}
+		}
+		rv
+	}
+	
+	/// Read one comma-separated value per signal, returning the bits (LSB first)
+	fn get_vector_values(&mut self, signals: &[(String,::cct_mesh::LinkList)]) -> Vec<bool>
+	{
+		let mut rv = Vec::new();
+		for (i,(name, links)) in signals.iter().enumerate()
+		{
+			if i > 0 {
+				syntax_assert_get!(self, TokComma => (), "Expected ',' between test vector values");
+			}
+			let val = self.get_numeric();
+			if links.len() < 64 && val >> links.len() != 0 {
+				syntax_error!(self.lexer, "Value {:#x} doesn't fit in {} ({} bits)", val, name, links.len());
+			}
+			rv.extend( (0 .. links.len()).map(|b| b < 64 && (val >> b) & 1 != 0) );
+		}
+		rv
+	}
+	
 	/// Handle a descriptor line (<outputs> = ELEMENT <inputs>)
 	fn do_line(&mut self, meshroot: &::cct_mesh::Root, unit: &mut ::cct_mesh::Unit)
 	{
@@ -338,10 +389,10 @@ impl<'rl> Parser<'rl>
 		{
 			let (name,params,inputs) = self.get_element(meshroot, unit);
 			syntax_assert_get!(self, TokNewline => (), "Expected newline after element descriptor");
-			match unit.append_element(meshroot, &name, params, inputs, Some(outputs))
+			match unit.append_element(meshroot, self.registry, &name, params, inputs, Some(outputs))
 			{
 			Ok(_) => {},
-			Err(e) => syntax_error!(self.lexer, "Error appending element {} : {:?}", name, e),
+			Err(e) => syntax_error!(self.lexer, "Error appending element {} : {}", name, e),
 			}
 		}
 		// If it's not, then it's a binding operation
@@ -512,20 +563,107 @@ fn handle_meta(parser: &mut Parser, meshroot: &mut ::cct_mesh::Root, state: &mut
 		{
 		Ok(d) => {
 			//println!("{:?}", d);
-			state.get_curunit().set_rom_data(index, d);
+			state.get_curunit().set_rom_data(index, d, None);
 			},
 		Err(e) => syntax_error!(parser.lexer, "Unable to parse hex string: {}", e),
 		}
 		},
+	"rom_file" | "mem_file" => {
+		// #rom_file <index> "path" [format [word_bits] [le|be]]
+		let index = parser.get_numeric() as usize;
+		let path = syntax_assert_get!(parser, TokString(v) => v, "Expected path string after file index");
+		// Only ROM images have their format guessed, memory files default to `$readmemh` text
+		let mut format = if name == "rom_file" { memfile::Format::from_path(&path) } else { memfile::Format::Hex };
+		let mut layout = memfile::WordLayout::default();
+		if let TokIdent(v) = parser.look_ahead()
+		{
+			parser.get_token();
+			format = match memfile::Format::from_name(&v) {
+				Some(f) => f,
+				None => syntax_error!(parser.lexer, "Unknown data file format '{}'", v),
+				};
+			if let TokNumber(bits) = parser.look_ahead()
+			{
+				parser.get_token();
+				if bits == 0 || bits > 64 || bits % 8 != 0 {
+					syntax_error!(parser.lexer, "Word size must be a multiple of 8 in 8--64 (got {})", bits);
+				}
+				layout.bytes = bits as usize / 8;
+			}
+			if let TokIdent(v) = parser.look_ahead()
+			{
+				parser.get_token();
+				layout.big_endian = match &*v {
+					"le" => false,
+					"be" => true,
+					_ => syntax_error!(parser.lexer, "Expected 'le' or 'be' for endianness, got '{}'", v),
+					};
+			}
+		}
+		syntax_assert_get!(parser, TokNewline => (), "Expected newline after data file");
+		// Relative paths are relative to the including file
+		let path = ::std::path::Path::new(parser.lexer.filename()).parent()
+			.map(|d| d.join(&path))
+			.unwrap_or_else(|| From::from(path));
+		match memfile::load(&path, format, layout)
+		{
+		Ok(d) => state.get_curunit().set_rom_data(index, d.words, Some((path, d.offsets))),
+		Err(e) => syntax_error!(parser.lexer, "Unable to load data file {}", e),
+		}
+		},
+	"uart_file" => {
+		// #uart_file <index> rx|tx "path"
+		let index = parser.get_numeric() as usize;
+		let transmit = match &*syntax_assert_get!(parser, TokIdent(v) => v, "Expected 'rx' or 'tx' after #uart_file index")
+			{
+			"rx" => false,
+			"tx" => true,
+			v => syntax_error!(parser.lexer, "Expected 'rx' or 'tx' after #uart_file index, got '{}'", v),
+			};
+		let path = syntax_assert_get!(parser, TokString(v) => v, "Expected path string after #uart_file direction");
+		syntax_assert_get!(parser, TokNewline => (), "Expected newline after #uart_file");
+		let path = ::std::path::Path::new(parser.lexer.filename()).parent()
+			.map(|d| d.join(&path))
+			.unwrap_or_else(|| From::from(path));
+		state.get_curunit().set_io_file(index, transmit, path);
+		},
 	"endunit" => {
 		syntax_assert_get!(parser, TokNewline => (), "Expected newline after #endunit");
+		if let Some(b) = state.get_curunit().open_block() {
+			syntax_error!(parser.lexer, "#block \"{}\" not closed before #endunit", b);
+		}
 
 		state.set_curunit( meshroot.get_root_unit() );
 		},
 	"testcase" => {
 		let limit = syntax_assert_get!(parser, TokNumber(v) => (v), "Expected number after #testcase");
 		let name = syntax_assert_get!(parser, TokString(v) => (v), "Expected test name after execution limit");
-		syntax_assert_get!(parser, TokNewline => (), "Expected newline after test case definition");
+		// Options
+		let mut reset_hold = 0;
+		let mut tags = Vec::new();
+		loop
+		{
+			match parser.get_token()
+			{
+			TokNewline => break,
+			TokIdent(ref opt) if opt == "reset" => {
+				reset_hold = syntax_assert_get!(parser, TokNumber(v) => v, "Expected tick count after 'reset'");
+				},
+			TokIdent(ref opt) if opt == "tags" => {
+				// tags=a,b,c
+				syntax_assert_get!(parser, TokAssign => (), "Expected '=' after 'tags'");
+				loop
+				{
+					tags.push( syntax_assert_get!(parser, TokIdent(v) => v, "Expected tag name") );
+					if parser.look_ahead() != TokComma {
+						break
+					}
+					parser.get_token();
+				}
+				},
+			tok => syntax_error!(parser.lexer, "Expected test option or newline after test case definition, got {}", tok),
+			}
+		}
 		
 		//if state.get_curunit() != meshroot.get_root_unit() {
 		//	syntax_error!(parser.lexer, "#testcase outside of root");
@@ -533,10 +671,26 @@ fn handle_meta(parser: &mut Parser, meshroot: &mut ::cct_mesh::Root, state: &mut
 		
 		match meshroot.add_test(name, limit as u32)
 		{
-		Ok(x) => state.set_curtest( x ),
+		Ok(x) => { x.set_reset_hold(reset_hold as u32); x.set_tags(tags); state.set_curtest( x ) },
 		Err(e) => panic!("Redefinition of test \"{}\"", e)
 		}
 		},
+	"xfail" => {
+		// #xfail ["reason"]
+		let reason = match parser.get_token()
+			{
+			TokString(r) => {
+				syntax_assert_get!(parser, TokNewline => (), "Expected newline after #xfail reason");
+				r
+				},
+			TokNewline => String::new(),
+			tok => syntax_error!(parser.lexer, "Expected reason or newline after #xfail, got {}", tok),
+			};
+		match state.get_curtest() {
+			Some(x) => x,
+			None => syntax_error!(parser.lexer, "#xfail outside of a test")
+			}.set_xfail(reason);
+		},
 	"testcomplete" => {
 		let conditions = parser.get_value_list( meshroot, state.get_curunit() );
 		syntax_assert_get!(parser, TokNewline => (), "Expected newline after test completion condition");
@@ -558,39 +712,194 @@ fn handle_meta(parser: &mut Parser, meshroot: &mut ::cct_mesh::Root, state: &mut
 			None => syntax_error!(parser.lexer, "#testassert outside of a test")
 			}.add_assert(line, conditions, values, expected);
 		},
+	"testvectors" => {
+		// #testvectors <period> <inputs> : <outputs>
+		// <input values> : <output values>
+		// ...
+		// #endtestvectors
+		let exec_limit = match state.get_curtest() {
+			Some(x) => x.exec_limit(),
+			None => syntax_error!(parser.lexer, "#testvectors outside of a test")
+			};
+		let period = syntax_assert_get!(parser, TokNumber(v) => v, "Expected tick count after #testvectors");
+		if period == 0 {
+			syntax_error!(parser.lexer, "Test vector period must be at least one tick");
+		}
+		let inputs = parser.get_signal_list( meshroot, state.get_curunit() );
+		syntax_assert_get!(parser, TokColon => (), "Expected ':' between test vector inputs and outputs");
+		let outputs = parser.get_signal_list( meshroot, state.get_curunit() );
+		syntax_assert_get!(parser, TokNewline => (), "Expected newline after #testvectors outputs");
+		
+		let mut vectors = ::cct_mesh::TestVectors::new(period as u32, inputs.clone(), outputs.clone());
+		loop
+		{
+			match parser.get_token()
+			{
+			TokNewline => {},
+			TokMetaOp(ref n) if n == "endtestvectors" => {
+				syntax_assert_get!(parser, TokNewline => (), "Expected newline after #endtestvectors");
+				break
+				},
+			TokMetaOp(n) => syntax_error!(parser.lexer, "Expected test vector row or #endtestvectors, got #{}", n),
+			TokEof => syntax_error!(parser.lexer, "#testvectors not closed at end of file"),
+			tok => {
+				parser.put_back(tok);
+				let line = parser.lexer.curline();
+				let in_vals = parser.get_vector_values(&inputs);
+				syntax_assert_get!(parser, TokColon => (), "Expected ':' between test vector input and output values");
+				let exp_vals = parser.get_vector_values(&outputs);
+				syntax_assert_get!(parser, TokNewline => (), "Expected newline after test vector row");
+				vectors.add_row(line, in_vals, exp_vals);
+				},
+			}
+		}
+		if vectors.n_ticks() > exec_limit as u64 {
+			syntax_warn!(parser.lexer, "Test vectors need {} ticks, but the test is limited to {}", vectors.n_ticks(), exec_limit);
+		}
+		if state.get_curtest().unwrap().set_vectors(vectors) {
+			syntax_error!(parser.lexer, "Multiple #testvectors blocks in one test");
+		}
+		},
+	"at" => {
+		// #at <tick> <lines> = <values>
+		// The lines are held at these values (overriding anything driving them) until another #at sets them
+		let exec_limit = match state.get_curtest() {
+			Some(x) => x.exec_limit(),
+			None => syntax_error!(parser.lexer, "#at outside of a test")
+			};
+		let tick = syntax_assert_get!(parser, TokNumber(v) => v, "Expected tick number after #at");
+		let signals = parser.get_signal_list( meshroot, state.get_curunit() );
+		syntax_assert_get!(parser, TokAssign => (), "Expected '=' after #at lines");
+		let values = parser.get_vector_values(&signals);
+		syntax_assert_get!(parser, TokNewline => (), "Expected newline after #at values");
+		if tick >= exec_limit as u64 {
+			syntax_warn!(parser.lexer, "#at tick {} is after the end of the test ({} ticks)", tick, exec_limit);
+		}
+		let links = signals.into_iter().flat_map(|s| s.1.into_iter()).collect();
+		state.get_curtest().unwrap().add_stimulus(tick as u32, links, values);
+		},
+	"expect_at" | "expect_within" => {
+		// #expect_at <tick> <values> <expected>
+		// #expect_within <ticks> <values> <expected>
+		let exec_limit = match state.get_curtest() {
+			Some(x) => x.exec_limit(),
+			None => syntax_error!(parser.lexer, "#{} outside of a test", name)
+			};
+		let line = parser.lexer.curline();
+		let tick = syntax_assert_get!(parser, TokNumber(v) => v, "Expected tick count");
+		let values = parser.get_value_list( meshroot, state.get_curunit() );
+		let expected = parser.get_value_list( meshroot, state.get_curunit() );
+		syntax_assert_get!(parser, TokNewline => (), "Expected newline after expected values");
+		if values.len() != expected.len() {
+			syntax_error!(parser.lexer, "#{} compares {} values against {} expected", name, values.len(), expected.len());
+		}
+		let timing = if name == "expect_at" {
+				if tick >= exec_limit as u64 {
+					syntax_warn!(parser.lexer, "#expect_at tick {} is after the end of the test ({} ticks)", tick, exec_limit);
+				}
+				::cct_mesh::flat::AssertTiming::At(tick as u32)
+			}
+			else {
+				if tick == 0 {
+					syntax_error!(parser.lexer, "#expect_within needs at least one tick");
+				}
+				::cct_mesh::flat::AssertTiming::Within(tick as u32)
+			};
+		state.get_curtest().unwrap().add_timed_assert(timing, line, values, expected);
+		},
+	"property" => {
+		let line = parser.lexer.curline();
+		let conditions = parser.get_value_list( meshroot, state.get_curunit() );
+		let values = parser.get_value_list( meshroot, state.get_curunit() );
+		let expected = parser.get_value_list( meshroot, state.get_curunit() );
+		syntax_assert_get!(parser, TokNewline => (), "Expected newline after property definition");
+		
+		if values.len() != expected.len() {
+			syntax_error!(parser.lexer, "#property value and expected counts don't match ({} != {})", values.len(), expected.len());
+		}
+		state.get_curunit().append_property(line, conditions, values, expected);
+		},
 	"endtestcase" => {
 		syntax_assert_get!(parser, TokNewline => (), "Expected newline after #endtestcase");
+		if let Some(b) = state.get_curunit().open_block() {
+			syntax_error!(parser.lexer, "#block \"{}\" not closed before #endtestcase", b);
+		}
+		// Completion would wait for rows that never come, so the test could only time out
+		if state.get_curtest().is_some_and(|t| t.has_empty_vectors() && !t.has_completion()) {
+			syntax_error!(parser.lexer, "Empty #testvectors block in a test without #testcomplete");
+		}
 		state.set_curunit( meshroot.get_root_unit() );
 		},
+	"reset" => {
+		// #reset $line
+		let line = parser.get_value_list( meshroot, state.get_curunit() );
+		syntax_assert_get!(parser, TokNewline => (), "Expected newline after #reset");
+		
+		if state.get_curtest().is_none() && !::std::ptr::eq(state.get_curunit(), meshroot.get_root_unit()) {
+			syntax_error!(parser.lexer, "#reset is only valid in the root unit or a test");
+		}
+		if line.len() != 1 {
+			syntax_error!(parser.lexer, "#reset takes a single line, got {}", line.len());
+		}
+		if state.get_curunit().set_reset(line.into_iter().next().unwrap()) {
+			syntax_error!(parser.lexer, "Duplicate #reset");
+		}
+		},
 	"display" => {
 		let conditions = parser.get_value_list( meshroot, state.get_curunit() );
 		let text = syntax_assert_get!(parser, TokString(x) => x, "Expected string after condtions in #display");
 		let values = parser.get_value_list( meshroot, state.get_curunit() );
 		syntax_assert_get!(parser, TokNewline => (), "Expected newline after values in #display");
 		
-		state.get_curunit().append_display(conditions, text, values);
+		let format = match ::display::Format::parse(&text)
+			{
+			Ok(f) => f,
+			Err(e) => syntax_error!(parser.lexer, "Bad #display format \"{}\": {}", text, e),
+			};
+		if format.n_bits() > values.len() {
+			syntax_error!(parser.lexer, "#display format \"{}\" needs {} values, only {} given", text, format.n_bits(), values.len());
+		}
+		if format.has_values() && format.n_bits() < values.len() {
+			syntax_error!(parser.lexer, "#display format \"{}\" uses {} values, {} given", text, format.n_bits(), values.len());
+		}
+		state.get_curunit().append_display(conditions, text, format, values);
 		},
 	"block" => {
 		let name = syntax_assert_get!(parser, TokString(x) => x, "Expected block name after #block");
 		syntax_assert_get!(parser, TokNewline => (), "Expected newline after name in #block");
 		
-		warn!("TODO: Display blocks (#block \"{}\")", name);
+		if name.is_empty() || name.contains('.') {
+			syntax_error!(parser.lexer, "Invalid block name \"{}\" (must be non-empty, and nested blocks are used instead of '.')", name);
+		}
+		state.get_curunit().push_block(name);
 		},
 	"breakpoint" => {
+		// #breakpoint <conditions> "name" [error]
 		let conditions = parser.get_value_list( meshroot, state.get_curunit() );
 		let name = syntax_assert_get!(parser, TokString(x) => x, "Expected string after conditions in #breakpoint");
-		syntax_assert_get!(parser, TokNewline => (), "Expected newline after name in #breakpoint");
+		let error = match parser.get_token()
+			{
+			TokIdent(ref v) if v == "error" => {
+				syntax_assert_get!(parser, TokNewline => (), "Expected newline after 'error' in #breakpoint");
+				true
+				},
+			TokNewline => false,
+			tok => syntax_error!(parser.lexer, "Expected 'error' or newline after name in #breakpoint, got {}", tok),
+			};
 		
-		state.get_curunit().append_breakpoint(name, conditions);
+		state.get_curunit().append_breakpoint(name, conditions, error);
 		},
 	"endblock" => {
 		syntax_assert_get!(parser, TokNewline => (), "Expected newline after #endblock");
+		if !state.get_curunit().pop_block() {
+			syntax_error!(parser.lexer, "#endblock without #block");
+		}
 		},
 	_ => panic!("Unknown meta-op '#{}'", name)
 	}
 }
 
-pub fn load(filename: &str) -> Option<::cct_mesh::Root>
+pub fn load(filename: &str, registry: &::elements::ElementRegistry) -> Option<::cct_mesh::Root>
 {
 	use std::process::{Command,Stdio};
 	debug!("load(filename='{}')", filename);
@@ -603,24 +912,43 @@ pub fn load(filename: &str) -> Option<::cct_mesh::Root>
 		Some(ref mut pipe) => pipe,
 		None => panic!("BUGCHECK - Stdout was None"),
 		};
-	// 2. Create a parser object
+	// 2. Parse the preprocessed output
 	//let mut input_iter = output_pipe.chars().map(|r| r.unwrap());
 	let mut input_iter = output_pipe.bytes().map(|r| r.unwrap() as char);
-	let mut parser = Parser::new(&mut input_iter, filename);
+	Some( parse_stream(&mut input_iter, filename, registry) )
+}
+
+/// Parse circuit source that has already been preprocessed (without going through yasm)
+#[cfg(test)]
+pub fn load_str(text: &str, filename: &str, registry: &::elements::ElementRegistry) -> ::cct_mesh::Root
+{
+	parse_stream(&mut text.chars(), filename, registry)
+}
+
+fn parse_stream(input: lex::InStream, filename: &str, registry: &::elements::ElementRegistry) -> ::cct_mesh::Root
+{
+	// 1. Create a parser object
+	let mut parser = Parser::new(input, filename, registry);
 	
-	// 3. Create mesh root
+	// 2. Create mesh root
 	let mut meshroot = ::cct_mesh::Root::new();
 	{
 		let mut state = RootState::new(meshroot.get_root_unit());
 		
-		// 4. Parse!
+		// 3. Parse!
 		loop
 		{
 			let tok = parser.get_token();
 			match tok
 			{
 			TokNewline => {},	// ignore newlines
-			TokEof => { println!("EOF"); break },
+			TokEof => {
+				println!("EOF");
+				if let Some(b) = state.get_curunit().open_block() {
+					syntax_error!(parser.lexer, "#block \"{}\" not closed at end of file", b);
+				}
+				break
+				},
 			TokMetaOp(name) => handle_meta(&mut parser, &mut meshroot, &mut state,  name),
 			_ => {
 				parser.put_back(tok);
@@ -630,7 +958,26 @@ pub fn load(filename: &str) -> Option<::cct_mesh::Root>
 		}
 	}
 	
-	return Some(meshroot);
+	meshroot
+}
+
+/// Parse a value list (as used by `#testcomplete`) given outside of a file, in the context of the root unit
+///
+/// Lines must already exist in the root unit. Any elements used in the list are added to the root unit, so
+/// this must be called before flattening.
+pub fn parse_root_values(meshroot: &mut ::cct_mesh::Root, source: &str, text: &str, registry: &::elements::ElementRegistry) -> ::cct_mesh::LinkList
+{
+	let mut input_iter = text.chars();
+	let mut parser = Parser::new(&mut input_iter, source, registry);
+	parser.existing_lines = true;
+	let state = RootState::new(meshroot.get_root_unit());
+	let values = parser.get_value_list( meshroot, state.get_curunit() );
+	match parser.get_token()
+	{
+	TokEof | TokNewline => {},
+	tok => syntax_error!(parser.lexer, "Unexpected {} after value list", tok),
+	}
+	values
 }
 
 // vim: ft=rust
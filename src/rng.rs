@@ -0,0 +1,29 @@
+//
+//
+//
+//! Small deterministic PRNG (xorshift64*), used where reproducible seeds are needed
+
+pub struct Rng(u64);
+
+impl Rng
+{
+	pub fn new(seed: u64) -> Rng {
+		// Zero is a fixed point of xorshift, so perturb the seed
+		Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+	}
+	
+	pub fn next_u64(&mut self) -> u64 {
+		let mut x = self.0;
+		x ^= x >> 12;
+		x ^= x << 25;
+		x ^= x >> 27;
+		self.0 = x;
+		x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+	}
+	/// Random value in 0 .. max (max must be non-zero)
+	pub fn below(&mut self, max: u64) -> u64 {
+		self.next_u64() % max
+	}
+}
+
+// vim: ft=rust
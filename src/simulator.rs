@@ -2,7 +2,9 @@
 //
 //
 
+use std::io::Write;
 use cct_mesh::flat::NodeRef;
+use display::Sink;
 
 struct Ele
 {
@@ -17,6 +19,13 @@ pub struct Engine<'a>
 	elements: Vec<Ele>,
 	curstate: Vec<bool>,
 	newstate: Vec<bool>,
+	
+	coverage: Option<::coverage::Coverage>,
+	/// Nodes held at a fixed value (e.g. for fault injection)
+	forced: Vec<(u32,bool)>,
+	/// Remaining ticks to hold the reset line high for
+	reset_hold: u32,
+	display_filter: ::display::BlockFilter,
 }
 
 macro_rules! getval{ ($state:expr, $nr:expr) => ( {
@@ -42,18 +51,94 @@ impl<'a> Engine<'a>
 				).collect(),
 			curstate: ::from_elem(mesh.n_nodes, false),
 			newstate: ::std::iter::repeat(false).take(mesh.n_nodes).collect(),
+			coverage: None,
+			forced: Vec::new(),
+			reset_hold: 0,
+			display_filter: Default::default(),
 		}
 	}
 	
-	pub fn tick(&mut self)
+	/// Return all nodes and elements to their initial state
+	///
+	/// Forced nodes and coverage collection are kept.
+	pub fn reset(&mut self)
 	{
+		for v in self.curstate.iter_mut().chain( self.newstate.iter_mut() ) {
+			*v = false;
+		}
 		for ele in self.elements.iter_mut()
 		{
+			ele.inst.inst.reset();
+			for v in ele.output_vals.iter_mut() {
+				*v = false;
+			}
+		}
+		for &(id,val) in self.forced.iter() {
+			self.curstate[id as usize] = val;
+		}
+		self.reset_hold = 0;
+	}
+	
+	/// Assert reset for the next `ticks` ticks (driving the #reset line high, if there is one)
+	pub fn hold_reset(&mut self, ticks: u32)
+	{
+		self.reset_hold = ticks;
+	}
+	
+	/// Hold a node at a fixed value, regardless of what drives it (replacing any value it was already held at)
+	pub fn force_node(&mut self, id: u32, value: bool)
+	{
+		match self.forced.iter_mut().find(|f| f.0 == id)
+		{
+		Some(f) => f.1 = value,
+		None => self.forced.push( (id, value) ),
+		}
+		self.curstate[id as usize] = value;
+	}
+	
+	/// Only show display items from the selected blocks
+	pub fn set_display_filter(&mut self, filter: ::display::BlockFilter)
+	{
+		self.display_filter = filter;
+	}
+	
+	/// Start collecting toggle/select coverage
+	pub fn enable_coverage(&mut self)
+	{
+		self.coverage = Some( ::coverage::Coverage::new(self.mesh) );
+	}
+	pub fn get_coverage(&self) -> Option<&::coverage::Coverage>
+	{
+		self.coverage.as_ref()
+	}
+	
+	pub fn tick(&mut self)
+	{
+		let in_reset = if self.reset_hold > 0 {
+				self.reset_hold -= 1;
+				if let Some(NodeRef::NodeId(id)) = self.mesh.reset {
+					self.curstate[id as usize] = true;
+				}
+				true
+			}
+			else {
+				match self.mesh.reset { Some(n) => getval!(self.curstate, n), None => false }
+			};
+		for (ele_idx,ele) in self.elements.iter_mut().enumerate()
+		{
+			// Elements held in reset don't update (so their outputs stay low)
+			if in_reset && ele.inst.inst.uses_reset_line() {
+				ele.inst.inst.reset();
+				continue ;
+			}
 			// Obtain inputs
 			for (v,i) in ele.input_vals.iter_mut().zip( ele.inst.inputs.iter() ) {
 				*v = getval!(self.curstate, *i);
 			}
 			ele.output_vals.iter_mut().map( |v| *v = false ).count();
+			if let Some(ref mut cov) = self.coverage {
+				cov.record_select(ele_idx, &*ele.inst.inst, &ele.input_vals);
+			}
 
 			// Update
 			ele.inst.inst.update(&mut ele.output_vals, &ele.input_vals);
@@ -74,6 +159,13 @@ impl<'a> Engine<'a>
 		}
 		::std::mem::swap( &mut self.curstate, &mut self.newstate );
 		self.newstate.iter_mut().map( |v| *v = false ).count();
+		for &(id,val) in self.forced.iter() {
+			self.curstate[id as usize] = val;
+		}
+		
+		if let Some(ref mut cov) = self.coverage {
+			cov.record_nodes(&self.curstate);
+		}
 	}
 	
 	/// @param logical_and - If true, perform a logical AND on the values, else do an OR
@@ -101,80 +193,49 @@ impl<'a> Engine<'a>
 		rv
 	}
 	
-	pub fn check_breakpoints(&self) -> bool
+	/// Write the contents of all memory elements (in `$readmemh` format, one block per element)
+	pub fn dump_memories(&self, out: &mut dyn Write) -> ::std::io::Result<usize>
 	{
-		for bp in self.mesh.breakpoints.iter()
+		let mut count = 0;
+		for ele in self.elements.iter()
 		{
-			if self.are_set(&bp.conds, true) {
-				return true;
+			if let Some( (wordsize, data) ) = ele.inst.inst.memory()
+			{
+				let len = data.iter().rposition(|&v| v != 0).map(|i| i+1).unwrap_or(0);
+				writeln!(out, "// {} {}", ele.inst.inst.name(), ::formal::describe_element(self.mesh, &ele.inst))?;
+				writeln!(out, "@0")?;
+				for v in data[..len].iter() {
+					writeln!(out, "{:01$X}", v, wordsize.div_ceil(4))?;
+				}
+				count += 1;
 			}
 		}
-		return false;
+		Ok(count)
 	}
 	
-	pub fn show_display(&self) -> bool
+	/// Get the breakpoints whose conditions are currently met
+	pub fn check_breakpoints(&self) -> Vec<&'a ::cct_mesh::flat::Breakpoint>
 	{
-		let mut rv = false;
-		for disp in self.mesh.dispitems.iter()
-		{
-			if self.are_set(&disp.condition, true)
-			{
-				debug!("Display '{}' with '{:?}'", disp.text, disp.values);
-				print_display( &disp.text, &self.get_values(&disp.values) );
-				rv = true;
-			}
-		}
-		rv
+		self.mesh.breakpoints.iter().filter(|bp| self.are_set(&bp.conds, true)).collect()
 	}
-}
-
-fn print_display(fmtstr: &str, vals: &[bool])
-{
-	let mut idx = 0;
 	
-	let mut it = fmtstr.chars();
-	'parse: loop
+	/// Render the display items whose conditions are met (on tick `tick`) to `out`
+	pub fn show_display(&self, tick: u64, out: &mut dyn Sink)
 	{
-		let mut c = match it.next() { None=>break 'parse,Some(x)=>x };
-		if c == '%'
+		let mut shown = false;
+		for disp in self.mesh.dispitems.iter().filter(|d| self.display_filter.is_shown(&d.block))
 		{
-			let mut count = 0;
-			loop
-			{
-				c = match it.next() { None=>break 'parse,Some(x)=>x };
-				match c.to_digit(10) {
-					Some(x) => {
-						count = count*10 + x
-						},
-					None => break,
-					};
-			}
-			if count == 0 {
-				count = 1;
-			}
-			let val = read_uint(vals, idx, count as u8);
-			idx += count as usize;
-			match c
+			if self.are_set(&disp.condition, true)
 			{
-			'i' => print!("{}", val),
-			'x' => print!("{:x}", val),
-			_ => print!("UNK"),
+				debug!("Display '{}' with '{:?}'", disp.text, disp.values);
+				out.display( tick, &disp.format.render(&self.get_values(&disp.values)) );
+				shown = true;
 			}
 		}
-		else
-		{
-			print!("{}", c);
+		if shown {
+			out.end_tick(tick);
 		}
 	}
-	
-	if idx != vals.len()
-	{
-		print!(">> ");
-		for i in idx .. vals.len() {
-			print!("{}", match vals[i] {false=>0,true=>1});
-		}
-	}
-	println!("");
 }
 
 /// Read an unsigned integer from a sequence of bools
@@ -210,4 +271,50 @@ pub fn decode_u64_be(inlines: &[bool]) -> u64
 	val
 }
 
+#[test]
+fn test_reset()
+{
+	use test_util::run_display as run;
+	let src = concat!(
+		"#array q 4\n",
+		"#array zero 4\n",
+		"@zero = 0, 0, 0, 0\n",
+		"@q, $tc = COUNTER{4} 1, 0, 0, 0, @zero\n",
+		"$n = NOT $rst\n",
+		"#reset $rst\n",
+		"#display 1 \"q=%4i n=%i\" @q, $n\n",
+		"#testcase 10 \"hold\" reset 2\n",
+		"#array q 4\n",
+		"#array zero 4\n",
+		"@zero = 0, 0, 0, 0\n",
+		"@q, $tc = COUNTER{4} 1, 0, 0, 0, @zero\n",
+		"#reset $rst\n",
+		"#display 1 \"q=%4i\" @q\n",
+		"#endtestcase\n",
+		);
+	let mut root = ::test_util::load(src);
+	let mesh = root.flatten_root();
+	assert!( mesh.reset.is_some() );
+	
+	// Free-running, then `reset` returns to the power-on state
+	let mut sim = Engine::new(&mesh);
+	let first = run(&mut sim, 4);
+	assert_eq!( first, "0: q=1 n=1\n1: q=2 n=1\n2: q=3 n=1\n3: q=4 n=1\n" );
+	sim.reset();
+	assert_eq!( run(&mut sim, 4), first );
+	
+	// Holding #reset keeps the counter (which opts in) at zero, the NOT gate still sees the line
+	sim.reset();
+	sim.hold_reset(3);
+	assert_eq!( run(&mut sim, 6), "0: q=0 n=0\n1: q=0 n=0\n2: q=0 n=0\n3: q=1 n=1\n4: q=2 n=1\n5: q=3 n=1\n" );
+	
+	// `#testcase ... reset N` holds the test's #reset line for the first N ticks
+	root.flatten_tests(&["hold".to_string()]);
+	let (_, test) = root.iter_tests().next().unwrap();
+	assert_eq!( test.reset_hold(), 2 );
+	let mut sim = Engine::new(test.get_mesh());
+	sim.hold_reset(test.reset_hold());
+	assert_eq!( run(&mut sim, 4), "0: q=0\n1: q=0\n2: q=1\n3: q=2\n" );
+}
+
 // vim: ft=rust
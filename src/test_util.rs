@@ -0,0 +1,57 @@
+//
+//
+//
+//! Helpers shared by the unit tests
+use elements::ElementRegistry;
+use simulator::Engine;
+
+/// The low `n` bits of `v`, LSB first
+pub fn bits(v: u64, n: usize) -> Vec<bool>
+{
+	(0 .. n).map(|i| (v >> i) & 1 != 0).collect()
+}
+
+/// Create an element and update it once per set of inputs, returning the outputs after each
+pub fn run_element(name: &str, params: &[u64], ticks: &[Vec<bool>]) -> Vec<Vec<bool>>
+{
+	let mut ele = ElementRegistry::default().create(name, params, ticks[0].len()).unwrap();
+	ticks.iter().map(|ins| {
+		let mut outs = vec![false; ele.get_outputs(ins.len())];
+		ele.update(&mut outs, ins);
+		outs
+		}).collect()
+}
+/// Outputs of a new element after a single update
+pub fn eval_element(name: &str, params: &[u64], ins: Vec<bool>) -> Vec<bool>
+{
+	run_element(name, params, &[ins]).pop().unwrap()
+}
+
+/// Parse circuit source (already preprocessed) with the default elements
+pub fn load(src: &str) -> ::cct_mesh::Root
+{
+	::parse::load_str(src, "test", &ElementRegistry::default())
+}
+
+/// Run the `#testcase` named "t" from `src`
+pub fn run_test(src: &str) -> ::TestStatus
+{
+	let mut root = load(src);
+	root.flatten_tests(&["t".to_string()]);
+	let (_, test) = root.iter_tests().next().unwrap();
+	::run_test(test, None, None, None)
+}
+
+/// Tick the engine `ticks` times, returning the `#display` output (numbered from zero)
+pub fn run_display(sim: &mut Engine, ticks: u64) -> String
+{
+	let mut log = ::display::Log::default();
+	for t in 0 .. ticks
+	{
+		sim.tick();
+		sim.show_display(t, &mut log);
+	}
+	log.text().to_owned()
+}
+
+// vim: ft=rust
@@ -0,0 +1,220 @@
+//
+//
+//
+//! Full-screen terminal view of a running simulation (`--tui`)
+//!
+//! Uses only ANSI escapes (and `stty` to put the terminal into raw mode), so works over SSH.
+//! Keys: space pause/resume, `s` single step (while paused), `+`/`-` change speed, `q` quit.
+use std::io::Write;
+use std::collections::VecDeque;
+use cct_mesh::flat::{self, NodeRef};
+
+pub struct Options
+{
+	/// Groups/lines to show as values
+	pub watch: Vec<String>,
+	/// Lines to show as waveforms
+	pub wave: Vec<String>,
+	/// Ticks per redraw
+	pub every: u32,
+}
+
+struct Watch
+{
+	name: String,
+	nodes: Vec<NodeRef>,
+}
+
+struct BreakpointState
+{
+	hits: u32,
+	last_hit: Option<u64>,
+}
+
+/// Terminal raw mode, restored on drop
+struct RawMode
+{
+	saved: String,
+}
+impl RawMode
+{
+	fn enter() -> Result<RawMode,String>
+	{
+		let saved = stty(&["-g"])?;
+		stty(&["raw", "-echo"])?;
+		print!("\x1b[?1049h\x1b[?25l");
+		Ok(RawMode { saved: saved.trim().to_string() })
+	}
+}
+impl Drop for RawMode
+{
+	fn drop(&mut self)
+	{
+		print!("\x1b[?25h\x1b[?1049l");
+		let _ = ::std::io::stdout().flush();
+		let _ = stty(&[&self.saved]);
+	}
+}
+
+fn stty(args: &[&str]) -> Result<String,String>
+{
+	let out = ::std::process::Command::new("stty")
+		.args(args)
+		.stdin(::std::process::Stdio::inherit())
+		.output()
+		.map_err(|e| format!("Unable to run stty: {}", e))?;
+	if !out.status.success() {
+		return Err(format!("stty {} failed (is stdin a terminal?)", args.join(" ")));
+	}
+	Ok( String::from_utf8_lossy(&out.stdout).into_owned() )
+}
+
+/// Terminal size (rows, columns)
+fn term_size() -> (usize, usize)
+{
+	let size = stty(&["size"]).unwrap_or_default();
+	let mut it = size.split_whitespace().map(|v| v.parse::<usize>().ok());
+	match (it.next(), it.next())
+	{
+	(Some(Some(r)), Some(Some(c))) if r > 0 && c > 0 => (r, c),
+	_ => (24, 80),
+	}
+}
+
+/// Redraw rates (frames per second), 0 is as fast as possible
+const SPEEDS: &[u32] = &[1, 2, 5, 10, 20, 50, 100, 0];
+
+/// Run the mesh with a live terminal view until the user quits
+pub fn run(mesh: &flat::Mesh, opts: &Options) -> Result<(),String>
+{
+	let names = mesh.name_map();
+	let lookup = |name: &String| flat::Mesh::lookup_in(&names, name).ok_or_else(|| format!("Unknown line or group '{}'", name));
+	let watches = opts.watch.iter()
+		.map(|n| Ok(Watch { name: n.clone(), nodes: lookup(n)? }))
+		.collect::<Result<Vec<_>,String>>()?;
+	let waves = opts.wave.iter()
+		.map(|n| Ok(Watch { name: n.clone(), nodes: lookup(n)? }))
+		.collect::<Result<Vec<_>,String>>()?;
+	if let Some(w) = waves.iter().find(|w| w.nodes.len() != 1) {
+		return Err(format!("Waveform signal '{}' is a group, only single lines can be shown", w.name));
+	}
+
+	let (rows, cols) = term_size();
+	let name_width = waves.iter().map(|w| w.name.len()).max().unwrap_or(0);
+	let wave_len = cols.saturating_sub(name_width + 4).max(8);
+
+	::console::reserve_for_keys();
+	let _raw = RawMode::enter()?;
+
+	let mut sim = ::simulator::Engine::new(mesh);
+	let mut tick: u64 = 0;
+	let mut paused = false;
+	let mut speed = 3;
+	let mut history: Vec<VecDeque<bool>> = waves.iter().map(|_| VecDeque::with_capacity(wave_len)).collect();
+	let mut bps: Vec<BreakpointState> = mesh.breakpoints.iter().map(|_| BreakpointState { hits: 0, last_hit: None }).collect();
+	loop
+	{
+		let mut step = false;
+		while let Some(k) = ::console::read_key()
+		{
+			match k
+			{
+			b'q' | 3 => return Ok( () ),	// 3 = Ctrl-C (raw mode)
+			b' ' => paused = !paused,
+			b's' | b'n' if paused => step = true,
+			b'+' | b'=' => speed = ::std::cmp::min(speed + 1, SPEEDS.len() - 1),
+			b'-' => speed = speed.saturating_sub(1),
+			_ => {},
+			}
+		}
+
+		if !paused || step
+		{
+			let n = if step { 1 } else { opts.every };
+			for _ in 0 .. n
+			{
+				sim.tick();
+				tick += 1;
+				for (w,h) in waves.iter().zip(history.iter_mut()) {
+					if h.len() == wave_len {
+						h.pop_front();
+					}
+					h.push_back( sim.are_set(&w.nodes, true) );
+				}
+				let mut hit = false;
+				for (bp,state) in mesh.breakpoints.iter().zip(bps.iter_mut()) {
+					if sim.are_set(&bp.conds, true) {
+						state.hits += 1;
+						state.last_hit = Some(tick);
+						hit = true;
+					}
+				}
+				if hit {
+					paused = true;
+					break;
+				}
+			}
+		}
+
+		// Draw
+		let mut lines = Vec::new();
+		lines.push( format!("\x1b[7m {} - tick {} - {} \x1b[0m",
+			if mesh.name.is_empty() { "(root)" } else { &mesh.name },
+			tick,
+			if paused { "PAUSED".to_string() }
+			else if SPEEDS[speed] == 0 { "RUNNING (max speed)".to_string() }
+			else { format!("RUNNING ({}/s)", SPEEDS[speed] * opts.every) },
+			) );
+		lines.push( "space: pause/resume  s: step  +/-: speed  q: quit".to_string() );
+		lines.push( String::new() );
+		if !watches.is_empty() {
+			lines.push( "Values".to_string() );
+			for w in watches.iter() {
+				let vals = sim.get_values(&w.nodes);
+				let bits: String = vals.iter().rev().map(|&v| if v { '1' } else { '0' }).collect();
+				if vals.len() <= 64 {
+					lines.push( format!("  {:10} 0x{:0w$X}  0b{}", w.name, ::simulator::decode_u64_le(&vals), bits, w = vals.len().div_ceil(4)) );
+				}
+				else {
+					lines.push( format!("  {:10} 0b{}", w.name, bits) );
+				}
+			}
+			lines.push( String::new() );
+		}
+		if !mesh.breakpoints.is_empty() {
+			lines.push( "Breakpoints".to_string() );
+			for (bp,state) in mesh.breakpoints.iter().zip(bps.iter()) {
+				lines.push( format!("  {} {:20} {} hit(s){}",
+					if sim.are_set(&bp.conds, true) { '*' } else { ' ' },
+					bp.name,
+					state.hits,
+					state.last_hit.map(|t| format!(", last at tick {}", t)).unwrap_or_default(),
+					) );
+			}
+			lines.push( String::new() );
+		}
+		if !waves.is_empty() {
+			lines.push( format!("Waveforms (last {} ticks)", wave_len) );
+			for (w,h) in waves.iter().zip(history.iter()) {
+				let trace: String = h.iter().map(|&v| if v { '-' } else { '_' }).collect();
+				lines.push( format!("  {:w$} {}", w.name, trace, w = name_width) );
+			}
+		}
+
+		let mut out = ::std::io::stdout();
+		let mut frame = String::from("\x1b[H");
+		for l in lines.iter().take(rows) {
+			frame.push_str(l);
+			frame.push_str("\x1b[K\r\n");
+		}
+		frame.push_str("\x1b[J");
+		out.write_all(frame.as_bytes()).and_then(|_| out.flush()).map_err(|e| format!("Terminal write failed: {}", e))?;
+
+		let delay = if paused { 50 } else { 1000u32.checked_div(SPEEDS[speed]).unwrap_or(0) };
+		if delay > 0 {
+			::std::thread::sleep( ::std::time::Duration::from_millis(delay as u64) );
+		}
+	}
+}
+
+// vim: ft=rust
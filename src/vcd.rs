@@ -0,0 +1,74 @@
+//
+//
+//
+//! Value Change Dump (VCD) output
+use std::io::Write;
+use cct_mesh::flat;
+
+pub struct Writer<W: Write>
+{
+	out: W,
+	last: Vec<Option<bool>>,
+}
+
+/// Get the named nodes of a mesh (first name of each node) for dumping
+pub fn named_nodes(mesh: &flat::Mesh) -> Vec<(u32, String)>
+{
+	mesh.node_names().into_iter().enumerate()
+		.filter_map(|(id,names)| names.into_iter().next().map(|name| (id as u32, name)))
+		.collect()
+}
+
+/// Identifier code for a signal (printable ASCII, base 94)
+fn ident(mut idx: usize) -> String
+{
+	let mut rv = String::new();
+	loop {
+		rv.push( (33 + (idx % 94) as u8) as char );
+		idx /= 94;
+		if idx == 0 {
+			break;
+		}
+	}
+	rv
+}
+
+impl<W: Write> Writer<W>
+{
+	/// Write the VCD header, declaring one single-bit wire per name
+	pub fn new(mut out: W, module: &str, names: &[String]) -> ::std::io::Result<Writer<W>>
+	{
+		writeln!(out, "$timescale 1ns $end")?;
+		writeln!(out, "$scope module {} $end", if module.is_empty() { "top" } else { module })?;
+		for (i,name) in names.iter().enumerate()
+		{
+			// VCD identifiers can't contain whitespace, and the path separators are ours
+			let name: String = name.chars().filter(|c| !c.is_whitespace())
+				.map(|c| match c { '/' => '.', '#' => '_', c => c })
+				.collect();
+			writeln!(out, "$var wire 1 {} {} $end", ident(i), name)?;
+		}
+		writeln!(out, "$upscope $end")?;
+		writeln!(out, "$enddefinitions $end")?;
+		Ok(Writer {
+			out,
+			last: names.iter().map(|_| None).collect(),
+		})
+	}
+
+	/// Record the values at a given time (only changes are written)
+	pub fn sample(&mut self, time: u64, values: &[bool]) -> ::std::io::Result<()>
+	{
+		writeln!(self.out, "#{}", time)?;
+		for (i,(&v,last)) in values.iter().zip( self.last.iter_mut() ).enumerate()
+		{
+			if *last != Some(v) {
+				writeln!(self.out, "{}{}", v as u8, ident(i))?;
+				*last = Some(v);
+			}
+		}
+		Ok( () )
+	}
+}
+
+// vim: ft=rust
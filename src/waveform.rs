@@ -0,0 +1,134 @@
+//
+//
+//
+//! Text waveforms of recent signal history (used for test failure reports)
+use std::collections::VecDeque;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Style
+{
+	/// `▁▔`
+	Blocks,
+	/// `_‾`
+	Lines,
+}
+impl Style
+{
+	pub fn from_name(name: &str) -> Option<Style>
+	{
+		match name
+		{
+		"blocks" => Some(Style::Blocks),
+		"lines" => Some(Style::Lines),
+		_ => None,
+		}
+	}
+	fn glyphs(&self) -> (char, char)
+	{
+		match *self
+		{
+		Style::Blocks => ('▁', '▔'),
+		Style::Lines => ('_', '‾'),
+		}
+	}
+}
+
+/// How much history to keep for failure reports, and how to draw it
+#[derive(Copy,Clone,Debug)]
+pub struct TraceOptions
+{
+	pub depth: usize,
+	pub style: Style,
+}
+
+/// A named row of a waveform, taking `bits` values (LSB first) from each sample starting at `first`
+///
+/// Single-bit rows are drawn as a waveform, wider rows as hex values.
+pub struct Trace
+{
+	pub name: String,
+	pub first: usize,
+	pub bits: usize,
+}
+
+/// Ring buffer of the last few ticks of a set of values
+pub struct History
+{
+	depth: usize,
+	samples: VecDeque<(u32, Vec<bool>)>,
+}
+
+impl History
+{
+	pub fn new(depth: usize) -> History
+	{
+		History {
+			depth,
+			samples: VecDeque::with_capacity(depth),
+		}
+	}
+
+	pub fn record(&mut self, tick: u32, values: Vec<bool>)
+	{
+		if self.depth == 0 {
+			return ;
+		}
+		if self.samples.len() == self.depth {
+			self.samples.pop_front();
+		}
+		self.samples.push_back( (tick, values) );
+	}
+
+	/// Render the given traces, one line per trace (plus a header of tick numbers)
+	pub fn render(&self, traces: &[Trace], style: Style) -> String
+	{
+		use std::fmt::Write;
+		let (low, high) = style.glyphs();
+		let last_tick = self.samples.back().map(|s| s.0).unwrap_or(0);
+		let col_width = 1 + ::std::cmp::max(
+			last_tick.to_string().len(),
+			traces.iter().map(|t| t.bits.div_ceil(4)).max().unwrap_or(0)
+			);
+		let name_width = traces.iter().map(|t| t.name.chars().count()).max().unwrap_or(0).max(4);
+
+		let mut rv = String::new();
+		let _ = write!(rv, "{:w$} |", "tick", w = name_width);
+		for &(tick, _) in self.samples.iter() {
+			let _ = write!(rv, "{:>w$}", tick, w = col_width);
+		}
+		for t in traces.iter()
+		{
+			let _ = write!(rv, "\n{:w$} |", t.name, w = name_width);
+			for (_, vals) in self.samples.iter()
+			{
+				let vals = &vals[t.first ..][..t.bits];
+				if t.bits == 1 {
+					rv.extend( ::std::iter::repeat_n(if vals[0] { high } else { low }, col_width) );
+				}
+				else {
+					let _ = write!(rv, "{:>w$X}", ::simulator::decode_u64_le(vals), w = col_width);
+				}
+			}
+		}
+		rv
+	}
+}
+
+#[test]
+fn test_render()
+{
+	let mut h = History::new(3);
+	for tick in 0 .. 5 {
+		// Bit 0 toggles, bits 1-8 count
+		let v = tick as u64 * 3;
+		h.record(tick, (0 .. 9).map(|i| if i == 0 { tick % 2 == 1 } else { (v >> (i-1)) & 1 != 0 }).collect());
+	}
+	let traces = [
+		Trace { name: "$en".to_string(), first: 0, bits: 1 },
+		Trace { name: "have".to_string(), first: 1, bits: 8 },
+		];
+	assert_eq!( h.render(&traces, Style::Lines), "tick |  2  3  4\n$en  |___‾‾‾___\nhave |  6  9  C" );
+	assert_eq!( History::new(0).render(&traces[..1], Style::Blocks), "tick |\n$en  |" );
+}
+
+// vim: ft=rust